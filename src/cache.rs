@@ -0,0 +1,233 @@
+use crate::config::Config;
+use crate::util::metrics::CACHE_HITS;
+use crate::util::variables::REDIS_URL;
+
+use lru::LruCache;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+use tokio::time::timeout;
+use tracing::error;
+
+/// File ID, version of its contents, target width and height, and a description of the transform.
+/// The version keeps instances which didn't see a file replaced from serving its old contents.
+pub type Key = (String, String, u32, u32, String);
+
+struct ResizeCache {
+    entries: LruCache<Key, (Vec<u8>, &'static str)>,
+    bytes: usize,
+}
+
+lazy_static! {
+    static ref CACHE: Option<Mutex<ResizeCache>> =
+        NonZeroUsize::new(Config::global().resize_cache.max_entries).map(|capacity| {
+            Mutex::new(ResizeCache {
+                entries: LruCache::new(capacity),
+                bytes: 0,
+            })
+        });
+}
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Look up a previously resized image.
+pub fn get(key: &Key) -> Option<(Vec<u8>, &'static str)> {
+    let mut cache = CACHE.as_ref()?.lock().unwrap();
+    match cache.entries.get(key) {
+        Some(entry) => {
+            HITS.fetch_add(1, Ordering::Relaxed);
+            CACHE_HITS.inc();
+            Some(entry.clone())
+        }
+        None => {
+            MISSES.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+/// Store a resized image, evicting the least recently used entries to stay within limits.
+pub fn insert(key: Key, bytes: Vec<u8>, content_type: &'static str) {
+    let max_bytes = Config::global().resize_cache.max_bytes;
+    if bytes.len() > max_bytes {
+        return;
+    }
+
+    let mut cache = match CACHE.as_ref() {
+        Some(cache) => cache.lock().unwrap(),
+        None => return,
+    };
+
+    cache.bytes += bytes.len();
+    if let Some((_, (old, _))) = cache.entries.push(key, (bytes, content_type)) {
+        cache.bytes -= old.len();
+    }
+
+    while cache.bytes > max_bytes {
+        match cache.entries.pop_lru() {
+            Some((_, (old, _))) => cache.bytes -= old.len(),
+            None => break,
+        }
+    }
+}
+
+/// Drop every cached variant of a file.
+pub fn invalidate(id: &str) {
+    let mut cache = match CACHE.as_ref() {
+        Some(cache) => cache.lock().unwrap(),
+        None => return,
+    };
+
+    let keys: Vec<Key> = cache
+        .entries
+        .iter()
+        .filter(|(key, _)| key.0 == id)
+        .map(|(key, _)| key.clone())
+        .collect();
+
+    for key in keys {
+        if let Some((old, _)) = cache.entries.pop(&key) {
+            cache.bytes -= old.len();
+        }
+    }
+}
+
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+pub fn stats() -> CacheStats {
+    let (entries, bytes) = match CACHE.as_ref() {
+        Some(cache) => {
+            let cache = cache.lock().unwrap();
+            (cache.entries.len(), cache.bytes)
+        }
+        None => (0, 0),
+    };
+
+    CacheStats {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+        entries,
+        bytes,
+    }
+}
+
+/// How long to wait on Redis before giving up and resizing live.
+const REDIS_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// How long to wait after failing to connect before trying again.
+const REDIS_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+static REDIS: OnceCell<ConnectionManager> = OnceCell::const_new();
+static REDIS_RETRY_AT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Connect on first use, the connection manager reconnects by itself afterwards.
+/// Until a connection is made, attempts are spaced out so requests don't all wait on it.
+async fn redis() -> Option<ConnectionManager> {
+    REDIS
+        .get_or_try_init(|| async {
+            // Requests which queued up behind a failed attempt give up straight away too.
+            if matches!(*REDIS_RETRY_AT.lock().unwrap(), Some(at) if Instant::now() < at) {
+                return Err(());
+            }
+
+            let url = REDIS_URL.as_deref().ok_or(())?;
+            let client =
+                redis::Client::open(url).map_err(|err| error!("Invalid REDIS_URL. {}", err))?;
+
+            match timeout(REDIS_TIMEOUT, ConnectionManager::new(client)).await {
+                Ok(Ok(manager)) => Ok(manager),
+                _ => {
+                    error!(
+                        "Failed to connect to Redis, resizing without it for {:?}.",
+                        REDIS_RETRY_DELAY
+                    );
+                    *REDIS_RETRY_AT.lock().unwrap() = Some(Instant::now() + REDIS_RETRY_DELAY);
+                    Err(())
+                }
+            }
+        })
+        .await
+        .ok()
+        .cloned()
+}
+
+/// Redis key for a resized image, which changes along with the version of the file.
+pub fn shared_key(key: &Key) -> String {
+    let digest = md5::compute(format!("{}:{}", key.4, key.1));
+    format!("resize:{}:{}:{}:{:x}", key.0, key.2, key.3, digest)
+}
+
+/// Look up a resized image shared between instances.
+pub async fn get_shared(key: &str) -> Option<(Vec<u8>, String)> {
+    REDIS_URL.as_ref()?;
+    let mut conn = redis().await?;
+    let (content_type, bytes): (Option<String>, Option<Vec<u8>>) =
+        timeout(REDIS_TIMEOUT, conn.hget(key, &["content_type", "bytes"]))
+            .await
+            .ok()?
+            .ok()?;
+
+    let hit = (bytes?, content_type?);
+    CACHE_HITS.inc();
+    Some(hit)
+}
+
+/// Share a resized image with other instances, failures are ignored.
+pub async fn insert_shared(key: &str, bytes: &[u8], content_type: &str) {
+    if REDIS_URL.is_none() {
+        return;
+    }
+
+    let mut conn = match redis().await {
+        Some(conn) => conn,
+        None => return,
+    };
+
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .hset_multiple(
+            key,
+            &[("content_type", content_type.as_bytes()), ("bytes", bytes)],
+        )
+        .ignore()
+        .expire(key, Config::global().resize_cache.redis_ttl)
+        .ignore();
+
+    timeout(REDIS_TIMEOUT, pipe.query_async::<_, ()>(&mut conn))
+        .await
+        .ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(version: &str) -> Key {
+        (
+            "cached".to_string(),
+            version.to_string(),
+            16,
+            16,
+            String::new(),
+        )
+    }
+
+    #[test]
+    fn misses_once_a_file_is_replaced() {
+        Config::init_for_tests();
+        insert(key("old"), b"old".to_vec(), "image/webp");
+        assert_eq!(get(&key("old")).unwrap().0, b"old");
+        assert!(get(&key("new")).is_none());
+        assert_ne!(shared_key(&key("old")), shared_key(&key("new")));
+    }
+}
@@ -0,0 +1,89 @@
+use std::env;
+
+/// What the process was asked to do.
+pub enum Command {
+    Serve,
+    BackfillThumbnails {
+        tag: String,
+    },
+    MigrateLocalStorage,
+    CleanOrphans {
+        dry_run: bool,
+        min_age: u64,
+    },
+    RotateKey {
+        tag: String,
+        old_key: String,
+        new_key: String,
+    },
+}
+
+const USAGE: &str = "Usage: autumn [backfill-thumbnails --tag <tag> | migrate-local-storage | clean-orphans [--dry-run] [--min-age <minutes>] | rotate-key --tag <tag> --old-key <hex> --new-key <hex>]";
+
+/// Minutes a file must have existed for before it's considered orphaned.
+const DEFAULT_ORPHAN_MIN_AGE: u64 = 10;
+
+/// Read a command from the process arguments, defaulting to running the server.
+pub fn parse() -> Result<Command, String> {
+    let mut args = env::args().skip(1);
+    let command = match args.next() {
+        Some(command) => command,
+        None => return Ok(Command::Serve),
+    };
+
+    match command.as_str() {
+        "backfill-thumbnails" => {
+            let mut tag = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--tag" => tag = args.next(),
+                    _ => return Err(USAGE.to_string()),
+                }
+            }
+
+            Ok(Command::BackfillThumbnails {
+                tag: tag.ok_or_else(|| USAGE.to_string())?,
+            })
+        }
+        "migrate-local-storage" if args.next().is_none() => Ok(Command::MigrateLocalStorage),
+        "clean-orphans" => {
+            let mut dry_run = false;
+            let mut min_age = DEFAULT_ORPHAN_MIN_AGE;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--dry-run" => dry_run = true,
+                    "--min-age" => {
+                        min_age = args
+                            .next()
+                            .and_then(|minutes| minutes.parse().ok())
+                            .ok_or_else(|| USAGE.to_string())?
+                    }
+                    _ => return Err(USAGE.to_string()),
+                }
+            }
+
+            Ok(Command::CleanOrphans { dry_run, min_age })
+        }
+        "rotate-key" => {
+            let (mut tag, mut old_key, mut new_key) = (None, None, None);
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--tag" => tag = args.next(),
+                    "--old-key" => old_key = args.next(),
+                    "--new-key" => new_key = args.next(),
+                    _ => return Err(USAGE.to_string()),
+                }
+            }
+
+            match (tag, old_key, new_key) {
+                (Some(tag), Some(old_key), Some(new_key)) => Ok(Command::RotateKey {
+                    tag,
+                    old_key,
+                    new_key,
+                }),
+                _ => Err(USAGE.to_string()),
+            }
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
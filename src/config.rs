@@ -1,12 +1,15 @@
+use actix_web::http::{HeaderName, HeaderValue};
 use actix_web::HttpRequest;
+use ipnet::IpNet;
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
 use std::io::Read;
 
 use crate::util::result::Error;
-use crate::util::variables::CONFIG;
+use crate::util::variables::{CONFIG, LOCAL_STORAGE_PATH};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ContentType {
@@ -15,10 +18,148 @@ pub enum ContentType {
     Audio,
 }
 
+/// Server-side encryption S3 is asked to apply to stored objects.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum S3Sse {
+    #[serde(rename = "AES256")]
+    Aes256,
+    #[serde(rename = "aws:kms")]
+    AwsKms,
+}
+
+impl S3Sse {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            S3Sse::Aes256 => "AES256",
+            S3Sse::AwsKms => "aws:kms",
+        }
+    }
+}
+
+/// How files are presented to browsers, in `Content-Disposition`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DispositionPolicy {
+    /// Display every file in the browser, including HTML,
+    /// so only use this for tags where uploads are trusted.
+    Inline,
+    /// Always download files.
+    Attachment,
+    /// Display images, video and audio, and download anything else.
+    Auto,
+}
+
+/// How requests are written to the access log.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One JSON object per line on stdout, for log aggregators.
+    Json,
+    /// A line in the usual log output, much like a web server's combined format.
+    Text,
+}
+
+/// Whether a tag hosts the files it's given, or only records them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyMode {
+    Store,
+    /// Files fetched from a URL are served by redirecting to it, their contents aren't kept.
+    Redirect,
+}
+
+/// How far an image's aspect ratio may be from the required one, as a fraction of it.
+const DEFAULT_ASPECT_RATIO_TOLERANCE: f32 = 0.05;
+
+/// Headers `extra_headers` can't set, as they'd weaken security or break responses.
+const RESERVED_HEADERS: &[&str] = &[
+    "content-security-policy",
+    "set-cookie",
+    "strict-transport-security",
+    "x-content-type-options",
+    "x-frame-options",
+    "access-control-allow-origin",
+    "access-control-allow-credentials",
+    "content-type",
+    "content-length",
+    "content-encoding",
+    "transfer-encoding",
+    "connection",
+];
+
 fn default_as_true() -> bool {
     true
 }
 
+fn default_presign_expiry() -> u32 {
+    300
+}
+
+fn default_max_blur_sigma() -> f32 {
+    100.0
+}
+
+fn default_compression_threshold() -> u64 {
+    1024
+}
+
+fn default_allow_origins() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CorsConfig {
+    #[serde(default = "default_allow_origins")]
+    pub allow_origins: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allow_origins: default_allow_origins(),
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn allows(&self, origin: &str) -> bool {
+        self.allow_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == origin)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignedUrlConfig {
+    // Tags are listed publicly by the index route.
+    #[serde(skip_serializing)]
+    pub secret: String,
+}
+
+/// A bucket to store a tag's files in, when it doesn't use the default one.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct S3BucketConfig {
+    pub name: String,
+    pub region: String,
+    pub endpoint: String,
+    /// Address the bucket as part of the path instead of the host name,
+    /// defaults to `AUTUMN_S3_PATH_STYLE`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_style: Option<bool>,
+    #[serde(skip_serializing)]
+    pub access_key: Option<String>,
+    #[serde(skip_serializing)]
+    pub secret_key: Option<String>,
+}
+
+/// A second place to keep a copy of a tag's files, which is never read from.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Local { path: String },
+    S3(S3BucketConfig),
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Tag {
     pub max_size: usize,
@@ -30,13 +171,293 @@ pub struct Tag {
     pub serve_if_field_present: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub restrict_content_type: Option<ContentType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cors: Option<CorsConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signed_url: Option<SignedUrlConfig>,
+    #[serde(skip_serializing)]
+    pub auth_token: Option<String>,
+    /// Reuse existing files with identical contents instead of storing them again.
+    /// This lets uploaders infer that someone else has uploaded the same file.
+    #[serde(default)]
+    pub deduplication: bool,
+    #[serde(default = "default_presign_expiry")]
+    pub presign_expiry: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_file_size_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub allowed_mime_types: Vec<String>,
+    /// Extensions an uploaded file's name must end with, checked as well as its MIME type.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_extensions: Option<Vec<String>>,
+    /// Images wider than this many pixels are rejected at upload.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_upload_width: Option<u32>,
+    /// Images taller than this many pixels are rejected at upload.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_upload_height: Option<u32>,
+    /// Width to height ratio images must have at upload, such as `[1, 1]` for avatars.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required_aspect_ratio: Option<(u32, u32)>,
+    /// How far an image's aspect ratio may be from `required_aspect_ratio`,
+    /// as a fraction of it. Defaults to 0.05.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aspect_ratio_tolerance: Option<f32>,
+    /// Convert JPEGs and PNGs with an embedded colour profile other than sRGB to sRGB at upload.
+    /// Re-encoding drops the profile, so without this such images show with the wrong colours.
+    #[serde(default)]
+    pub normalize_color_space: bool,
+    /// Uploads are refused with 507 once the tag's live files add up to this many bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage_quota_bytes: Option<u64>,
+    /// Whether files are displayed or downloaded, decided by their type when unset.
+    /// A `filename` in the request always downloads the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disposition_policy: Option<DispositionPolicy>,
+    /// Headers added to every file served from this tag, such as `Timing-Allow-Origin`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub extra_headers: BTreeMap<String, String>,
+    /// Allow `fit=fill`, which stretches images to the exact size requested.
+    #[serde(default = "default_as_true")]
+    pub allow_fit_fill: bool,
+    /// Sizes to resize images to as soon as they are uploaded.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub pregenerate_sizes: Vec<(u32, u32)>,
+    /// URL sent a POST whenever a file is uploaded or deleted.
+    #[serde(skip_serializing)]
+    pub webhook_url: Option<String>,
+    /// Secret used to sign webhooks, the hex HMAC-SHA256 of the body is sent as `X-Autumn-Signature`.
+    #[serde(skip_serializing)]
+    pub webhook_secret: Option<String>,
+    /// Directory to store this tag's files in, instead of `AUTUMN_LOCAL_STORAGE_PATH`.
+    /// Existing files are not moved when this is changed.
+    #[serde(skip_serializing)]
+    pub local_path_override: Option<String>,
+    /// Endpoint for this tag's bucket instead of `AUTUMN_S3_ENDPOINT`,
+    /// for services such as MinIO or Cloudflare R2. Ignored when `s3_buckets` is set.
+    #[serde(skip_serializing)]
+    pub s3_endpoint: Option<String>,
+    /// Buckets to use instead of the one named after the tag, in order of preference.
+    /// Uploads go to the first reachable bucket and reads try each in turn.
+    #[serde(skip_serializing, default)]
+    pub s3_buckets: Vec<S3BucketConfig>,
+    /// Encryption requested for every object written to S3, instead of the bucket's default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s3_sse: Option<S3Sse>,
+    /// KMS key to encrypt objects with, the account's default key is used when unset.
+    /// Only used with `s3_sse = "aws:kms"`.
+    #[serde(skip_serializing)]
+    pub s3_sse_kms_key_id: Option<String>,
+    /// Storage every file is copied to after it's written, for disaster recovery.
+    /// Deletes aren't mirrored, and local copies are encrypted with `local_encryption_key`.
+    #[serde(skip_serializing)]
+    pub secondary_storage: Option<StorageConfig>,
+    /// Base64 encoded 32 byte key to encrypt files written to local storage with.
+    #[serde(skip_serializing)]
+    pub local_encryption_key: Option<String>,
+    /// Keys files may still be encrypted with, so they can be read while rotating keys.
+    #[serde(skip_serializing, default)]
+    pub previous_local_encryption_keys: Vec<String>,
+    /// Files are stored when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_mode: Option<ProxyMode>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 #[serde(tag = "as")]
 pub enum ServeConfig {
-    WEBP { quality: Option<f32> },
-    PNG,
+    WEBP {
+        quality: Option<f32>,
+    },
+    PNG {
+        compression: Option<u8>,
+    },
+    AVIF {
+        quality: Option<f32>,
+        speed: Option<u8>,
+    },
+    JPEG {
+        quality: u8,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SecurityHeadersConfig {
+    #[serde(default = "default_as_true")]
+    pub x_content_type_options: bool,
+    #[serde(default = "default_as_true")]
+    pub x_frame_options: bool,
+    #[serde(default = "default_as_true")]
+    pub referrer_policy: bool,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        SecurityHeadersConfig {
+            x_content_type_options: true,
+            x_frame_options: true,
+            referrer_policy: true,
+        }
+    }
+}
+
+fn default_sweep_interval() -> u64 {
+    60
+}
+
+fn default_sweep_batch_size() -> i64 {
+    100
+}
+
+fn default_max_batch_sizes() -> usize {
+    8
+}
+
+fn default_max_resize_dimension() -> u32 {
+    4096
+}
+
+fn default_min_dpr() -> f32 {
+    0.25
+}
+
+fn default_max_dpr() -> f32 {
+    4.0
+}
+
+fn default_service_name() -> String {
+    "autumn".to_string()
+}
+
+fn default_stats_flush_interval() -> u64 {
+    10
+}
+
+fn default_multipart_threshold() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_shutdown_timeout_seconds() -> u64 {
+    30
+}
+
+fn default_log_format() -> LogFormat {
+    LogFormat::Text
+}
+
+fn default_content_type() -> String {
+    "application/octet-stream".to_string()
+}
+
+fn default_fallback_image_content_type() -> String {
+    "image/png".to_string()
+}
+
+fn default_quota_reconcile_interval() -> u64 {
+    60 * 60
+}
+
+fn default_max_s3_retries() -> u8 {
+    2
+}
+
+fn default_s3_initial_backoff_ms() -> u64 {
+    100
+}
+
+fn default_tus_expiry() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_resize_cache_entries() -> usize {
+    256
+}
+
+fn default_resize_cache_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_redis_ttl() -> usize {
+    24 * 60 * 60
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExpiryConfig {
+    /// Seconds between sweeps for expired files.
+    #[serde(default = "default_sweep_interval")]
+    pub sweep_interval: u64,
+    /// Maximum number of files to expire per sweep.
+    #[serde(default = "default_sweep_batch_size")]
+    pub batch_size: i64,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        ExpiryConfig {
+            sweep_interval: default_sweep_interval(),
+            batch_size: default_sweep_batch_size(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResizeCacheConfig {
+    /// Maximum number of resized images to keep, 0 disables the cache.
+    #[serde(default = "default_resize_cache_entries")]
+    pub max_entries: usize,
+    /// Maximum total size of cached images in bytes.
+    #[serde(default = "default_resize_cache_bytes")]
+    pub max_bytes: usize,
+    /// Seconds resized images are kept in Redis, if `REDIS_URL` is set.
+    #[serde(default = "default_redis_ttl")]
+    pub redis_ttl: usize,
+}
+
+impl Default for ResizeCacheConfig {
+    fn default() -> Self {
+        ResizeCacheConfig {
+            max_entries: default_resize_cache_entries(),
+            max_bytes: default_resize_cache_bytes(),
+            redis_ttl: default_redis_ttl(),
+        }
+    }
+}
+
+fn default_remote_fetch_timeout() -> u64 {
+    10
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoteFetchConfig {
+    /// Seconds to wait for a remote file before giving up.
+    #[serde(default = "default_remote_fetch_timeout")]
+    pub timeout: u64,
+    /// Largest remote file to fetch in bytes, the tag's limit still applies.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<usize>,
+}
+
+impl Default for RemoteFetchConfig {
+    fn default() -> Self {
+        RemoteFetchConfig {
+            timeout: default_remote_fetch_timeout(),
+            max_size: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TelemetryConfig {
+    /// OTLP/HTTP endpoint to export traces to, such as `http://localhost:4318/v1/traces`.
+    pub otlp_endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RateLimitConfig {
+    pub requests_per_second: f64,
+    pub burst: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -44,10 +465,127 @@ pub struct Config {
     pub tags: HashMap<String, Tag>,
     pub serve: ServeConfig,
     pub jpeg_quality: u8,
+    #[serde(default = "default_max_blur_sigma")]
+    pub max_blur_sigma: f32,
+    /// Largest width or height an image is resized to, defaults to 4096.
+    /// Larger requests are scaled down to fit, keeping their aspect ratio,
+    /// since decoding into a huge buffer can exhaust memory.
+    #[serde(default = "default_max_resize_dimension")]
+    pub max_resize_dimension: u32,
+    /// Range of `dpr` accepted in the query, defaults to 0.25 to 4.
+    #[serde(default = "default_min_dpr")]
+    pub min_dpr: f32,
+    #[serde(default = "default_max_dpr")]
+    pub max_dpr: f32,
+    /// Most sizes that can be requested at once from the batch resize endpoint.
+    #[serde(default = "default_max_batch_sizes")]
+    pub max_batch_sizes: usize,
+    #[serde(default = "default_compression_threshold")]
+    pub compression_threshold: u64,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Take the client's address from `X-Forwarded-For` for rate limiting and logging.
+    #[serde(default, alias = "trust_proxy")]
+    pub trust_proxy_headers: bool,
+    /// Proxies allowed to set `X-Forwarded-For`, such as `10.0.0.0/8`.
+    /// When empty every peer is trusted, letting clients pick their own address.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub trusted_proxy_cidrs: Vec<IpNet>,
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
+    pub cache_control: HashMap<String, String>,
+    #[serde(default)]
+    pub security_headers: SecurityHeadersConfig,
+    #[serde(default)]
+    pub expiry: ExpiryConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_max_file_size_bytes: Option<u64>,
+    /// Seconds between writing buffered download counts.
+    #[serde(default = "default_stats_flush_interval")]
+    pub stats_flush_interval: u64,
+    #[serde(default)]
+    pub resize_cache: ResizeCacheConfig,
+    #[serde(default)]
+    pub remote_fetch: RemoteFetchConfig,
+    /// Largest base64 upload body in bytes, defaults to the tag's upload limit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_base64_size: Option<usize>,
+    /// Threads dedicated to resizing images. When unset, image work shares the
+    /// runtime's blocking pool, which grows to hundreds of threads under load.
+    /// A fixed pool queues requests instead, which is usually kinder to tail latency.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_worker_threads: Option<usize>,
+    /// Prefix for `/health` and `/ready`, for deployments behind a path prefix.
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub health_path_prefix: String,
+    /// Bearer token required for `/metrics`, which is disabled without one.
+    #[serde(skip_serializing)]
+    pub metrics_auth_token: Option<String>,
+    /// Bearer token for admin routes, such as purging files.
+    #[serde(skip_serializing)]
+    pub master_key: Option<String>,
+    /// Seconds deleted files are kept, so they can be restored, before their
+    /// storage is reclaimed. Without this, storage is removed straight away.
+    #[serde(default)]
+    pub deleted_retention: u64,
+    /// Check files against the hash recorded at upload before serving them.
+    /// Ranged requests are streamed without being checked.
+    #[serde(default)]
+    pub verify_integrity: bool,
+    /// Seconds a resumable upload is kept for after its last chunk.
+    #[serde(default = "default_tus_expiry")]
+    pub tus_expiry: u64,
+    /// Files larger than this many bytes are sent to S3 in parts, defaults to 10 MB.
+    #[serde(default = "default_multipart_threshold")]
+    pub multipart_threshold: usize,
+    /// Seconds to wait for in-flight requests to finish after SIGTERM.
+    #[serde(default = "default_shutdown_timeout_seconds")]
+    pub shutdown_timeout_seconds: u64,
+    /// Format of the access log, `text` by default.
+    #[serde(default = "default_log_format")]
+    pub log_format: LogFormat,
+    /// Seconds an idle keep-alive connection is held open for, 0 disables keep-alive.
+    /// Defaults to actix's 5 seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive_timeout_seconds: Option<u64>,
+    /// Seconds a client has to send a request's headers before it's answered with a 408.
+    /// Defaults to actix's 5 seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_request_timeout_seconds: Option<u64>,
+    /// Concurrent connections each worker accepts before it stops accepting more.
+    /// Defaults to actix's 25,000.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    /// Times an S3 request is retried after being throttled or failing with a 5xx.
+    #[serde(default = "default_max_s3_retries")]
+    pub max_s3_retries: u8,
+    /// Milliseconds to wait before the first retry, doubling with each attempt.
+    #[serde(default = "default_s3_initial_backoff_ms")]
+    pub s3_initial_backoff_ms: u64,
+    /// Seconds between recounting the storage used by each tag.
+    #[serde(default = "default_quota_reconcile_interval")]
+    pub quota_reconcile_interval: u64,
+    /// Served for files stored without a valid content type.
+    #[serde(default = "default_content_type")]
+    pub default_content_type: String,
+    /// Served for images stored without a content type, defaults to `image/png`.
+    #[serde(default = "default_fallback_image_content_type")]
+    pub fallback_image_content_type: String,
+    /// Render the first page of PDFs when they're requested with a size.
+    /// Requires the `pdf` feature and Pdfium to be installed.
+    #[serde(default)]
+    pub pdf_preview: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub telemetry: Option<TelemetryConfig>,
 }
 
 static INSTANCE: OnceCell<Config> = OnceCell::new();
 
+fn invalid_config(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
 impl Config {
     pub fn global() -> &'static Config {
         INSTANCE.get().expect("Config is not initialized.")
@@ -59,9 +597,353 @@ impl Config {
         file.read_to_string(&mut contents)?;
 
         let config: Config = toml::from_str(&contents).unwrap();
+        config.validate()?;
+
         INSTANCE.set(config).expect("Failed to set global config.");
         Ok(())
     }
+
+    /// Set up the configuration every test shares, keeping files in a temporary directory.
+    #[cfg(test)]
+    pub fn init_for_tests() -> &'static Config {
+        INSTANCE.get_or_init(|| {
+            let root = std::env::temp_dir().join(format!("autumn-tests-{}", std::process::id()));
+            let config: Config = toml::from_str(&format!(
+                r#"
+                jpeg_quality = 80
+                max_resize_dimension = 256
+
+                [serve]
+                as = "WEBP"
+
+                [tags.test]
+                max_size = 20000000
+                local_path_override = {test:?}
+
+                [tags.private]
+                max_size = 20000000
+                auth_token = "test-token"
+                local_path_override = {private:?}
+
+                [tags.encrypted]
+                max_size = 20000000
+                local_encryption_key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+                local_path_override = {encrypted:?}
+
+                [tags.minio]
+                max_size = 20000000
+                s3_endpoint = {minio:?}
+                "#,
+                test = root.join("test").to_string_lossy(),
+                private = root.join("private").to_string_lossy(),
+                encrypted = root.join("encrypted").to_string_lossy(),
+                minio = std::env::var("AUTUMN_TEST_MINIO_ENDPOINT")
+                    .unwrap_or_else(|_| "http://127.0.0.1:9000".to_string())
+            ))
+            .expect("Test config should parse.");
+
+            config.validate().expect("Test config should be valid.");
+            config
+        })
+    }
+
+    /// Find the Cache-Control policy for a file, preferring a rule for its tag,
+    /// then its exact content type, then a `type/*` glob and finally `default`.
+    pub fn cache_control(&self, tag: &str, content_type: &str) -> &str {
+        let glob = content_type
+            .split('/')
+            .next()
+            .map(|kind| format!("{}/*", kind));
+
+        self.cache_control
+            .get(tag)
+            .or_else(|| self.cache_control.get(content_type))
+            .or_else(|| glob.and_then(|glob| self.cache_control.get(&glob)))
+            .or_else(|| self.cache_control.get("default"))
+            .map(String::as_str)
+            .unwrap_or(crate::CACHE_CONTROL)
+    }
+
+    fn validate(&self) -> std::io::Result<()> {
+        match self.serve {
+            ServeConfig::PNG {
+                compression: Some(level),
+            } if level > 9 => {
+                return Err(invalid_config(
+                    "serve.compression must be a value from 0 to 9.",
+                ))
+            }
+            ServeConfig::JPEG { quality } if !(1..=100).contains(&quality) => {
+                return Err(invalid_config(
+                    "serve.quality must be a value from 1 to 100.",
+                ))
+            }
+            _ => {}
+        }
+
+        if !self.health_path_prefix.is_empty()
+            && (!self.health_path_prefix.starts_with('/') || self.health_path_prefix.ends_with('/'))
+        {
+            return Err(invalid_config(
+                "health_path_prefix must start with a / and not end with one.",
+            ));
+        }
+
+        for (name, content_type) in [
+            ("default_content_type", &self.default_content_type),
+            (
+                "fallback_image_content_type",
+                &self.fallback_image_content_type,
+            ),
+        ] {
+            if content_type.parse::<mime::Mime>().is_err() {
+                return Err(invalid_config(&format!(
+                    "{} must be a valid content type.",
+                    name
+                )));
+            }
+        }
+
+        if self.quota_reconcile_interval == 0 {
+            return Err(invalid_config(
+                "quota_reconcile_interval must be at least 1.",
+            ));
+        }
+
+        if self.client_request_timeout_seconds == Some(0) {
+            return Err(invalid_config(
+                "client_request_timeout_seconds must be at least 1.",
+            ));
+        }
+
+        if self.max_connections == Some(0) {
+            return Err(invalid_config("max_connections must be at least 1."));
+        }
+
+        if self.max_resize_dimension == 0 {
+            return Err(invalid_config("max_resize_dimension must be at least 1."));
+        }
+
+        if !(self.min_dpr > 0.0 && self.min_dpr <= self.max_dpr && self.max_dpr.is_finite()) {
+            return Err(invalid_config(
+                "min_dpr must be above 0 and at most max_dpr.",
+            ));
+        }
+
+        if self.image_worker_threads == Some(0) {
+            return Err(invalid_config("image_worker_threads must be at least 1."));
+        }
+
+        if self.remote_fetch.timeout == 0 {
+            return Err(invalid_config("remote_fetch.timeout must be at least 1."));
+        }
+
+        if self.stats_flush_interval == 0 {
+            return Err(invalid_config("stats_flush_interval must be at least 1."));
+        }
+
+        if self.expiry.sweep_interval == 0 || self.expiry.batch_size < 1 {
+            return Err(invalid_config(
+                "expiry.sweep_interval and expiry.batch_size must be at least 1.",
+            ));
+        }
+
+        let mut local_paths = HashSet::new();
+        local_paths.insert(LOCAL_STORAGE_PATH.trim_end_matches('/'));
+        for (id, tag) in &self.tags {
+            if let Some(path) = &tag.local_path_override {
+                if !local_paths.insert(path.trim_end_matches('/')) {
+                    return Err(invalid_config(&format!(
+                        "tags.{}.local_path_override is already used by another tag.",
+                        id
+                    )));
+                }
+            }
+        }
+
+        for (id, tag) in &self.tags {
+            if tag.s3_sse_kms_key_id.is_some() && tag.s3_sse != Some(S3Sse::AwsKms) {
+                return Err(invalid_config(&format!(
+                    "tags.{}.s3_sse_kms_key_id requires s3_sse to be \"aws:kms\".",
+                    id
+                )));
+            }
+
+            if tag
+                .local_encryption_key
+                .iter()
+                .chain(tag.previous_local_encryption_keys.iter())
+                .any(|key| crate::encryption::parse_key(key).is_none())
+            {
+                return Err(invalid_config(&format!(
+                    "tags.{}.local_encryption_key must be a base64 encoded 32 byte key.",
+                    id
+                )));
+            }
+
+            if let Some((0, _)) | Some((_, 0)) = tag.required_aspect_ratio {
+                return Err(invalid_config(&format!(
+                    "tags.{}.required_aspect_ratio must be at least 1 on both sides.",
+                    id
+                )));
+            }
+
+            if let Some(tolerance) = tag.aspect_ratio_tolerance {
+                if tolerance < 0.0 || !tolerance.is_finite() {
+                    return Err(invalid_config(&format!(
+                        "tags.{}.aspect_ratio_tolerance must be at least 0.",
+                        id
+                    )));
+                }
+            }
+
+            for (name, value) in &tag.extra_headers {
+                if RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+                    return Err(invalid_config(&format!(
+                        "tags.{}.extra_headers can't set {}.",
+                        id, name
+                    )));
+                }
+
+                if HeaderName::from_bytes(name.as_bytes()).is_err()
+                    || HeaderValue::from_str(value).is_err()
+                {
+                    return Err(invalid_config(&format!(
+                        "tags.{}.extra_headers.{} is not a valid header.",
+                        id, name
+                    )));
+                }
+            }
+        }
+
+        if let Some(rate_limit) = &self.rate_limit {
+            if rate_limit.requests_per_second <= 0.0 || rate_limit.burst < 1.0 {
+                return Err(invalid_config(
+                    "rate_limit.requests_per_second must be positive and rate_limit.burst at least 1.",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check whether an origin may access the given request path,
+/// using the tag's CORS policy if it overrides the global one.
+pub fn origin_allowed(origin: &str, path: &str) -> bool {
+    let config = Config::global();
+    let tag = path.trim_start_matches('/').split('/').next().unwrap_or("");
+
+    config
+        .tags
+        .get(tag)
+        .and_then(|tag| tag.cors.as_ref())
+        .unwrap_or(&config.cors)
+        .allows(origin)
+}
+
+/// Match a MIME type against a pattern such as `image/*` or `video/mp4`.
+pub fn mime_matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(kind) => mime
+            .split('/')
+            .next()
+            .map(|mime_kind| mime_kind.eq_ignore_ascii_case(kind))
+            .unwrap_or(false),
+        None => pattern == "*" || pattern.eq_ignore_ascii_case(mime),
+    }
+}
+
+impl Tag {
+    /// Whether files fetched from a URL are only recorded and redirected to.
+    pub fn redirects(&self) -> bool {
+        self.proxy_mode == Some(ProxyMode::Redirect)
+    }
+
+    /// Whether this tag accepts files of the given MIME type.
+    pub fn allows_mime_type(&self, mime: &str) -> bool {
+        self.allowed_mime_types.is_empty()
+            || self
+                .allowed_mime_types
+                .iter()
+                .any(|pattern| mime_matches(pattern, mime))
+    }
+
+    /// Whether this tag accepts files with the given name, ignoring case.
+    /// Names without an extension are rejected once any are listed.
+    pub fn allows_extension(&self, filename: &str) -> bool {
+        let allowed = match &self.allowed_extensions {
+            Some(allowed) => allowed,
+            None => return true,
+        };
+
+        match std::path::Path::new(filename)
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some(extension) => allowed.iter().any(|allowed| {
+                allowed
+                    .trim_start_matches('.')
+                    .eq_ignore_ascii_case(extension)
+            }),
+            None => false,
+        }
+    }
+
+    /// The largest file this tag accepts, the stricter of
+    /// `max_size` and `max_file_size_bytes` or the global default.
+    pub fn max_file_size(&self) -> usize {
+        match self
+            .max_file_size_bytes
+            .or(Config::global().default_max_file_size_bytes)
+        {
+            Some(limit) => cmp::min(self.max_size as u64, limit) as usize,
+            None => self.max_size,
+        }
+    }
+
+    /// Reject images larger than `max_upload_width` or `max_upload_height`.
+    pub fn check_dimensions(&self, width: u32, height: u32) -> Result<(), Error> {
+        let too_wide = matches!(self.max_upload_width, Some(max) if width > max);
+        let too_tall = matches!(self.max_upload_height, Some(max) if height > max);
+
+        if too_wide || too_tall {
+            return Err(Error::ImageTooLarge {
+                width,
+                height,
+                max_width: self.max_upload_width,
+                max_height: self.max_upload_height,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Reject images whose shape is too far from `required_aspect_ratio`.
+    pub fn check_aspect_ratio(&self, width: u32, height: u32) -> Result<(), Error> {
+        let (ratio_width, ratio_height) = match self.required_aspect_ratio {
+            Some(ratio) => ratio,
+            None => return Ok(()),
+        };
+
+        let tolerance = self
+            .aspect_ratio_tolerance
+            .unwrap_or(DEFAULT_ASPECT_RATIO_TOLERANCE);
+
+        let required = ratio_width as f32 / ratio_height as f32;
+        let actual = width as f32 / height.max(1) as f32;
+        if (actual - required).abs() > required * tolerance {
+            return Err(Error::WrongAspectRatio {
+                width,
+                height,
+                required_aspect_ratio: (ratio_width, ratio_height),
+                tolerance,
+            });
+        }
+
+        Ok(())
+    }
 }
 
 pub fn get_tag(request: &HttpRequest) -> Result<(String, &Tag), Error> {
@@ -78,3 +960,34 @@ pub fn get_tag(request: &HttpRequest) -> Result<(String, &Tag), Error> {
         Err(Error::UnknownTag)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_tags_leave_out_secrets() {
+        let tag: Tag = toml::from_str(
+            r#"
+            max_size = 1000
+            webhook_url = "https://hooks.example.com/services/secret"
+            webhook_secret = "secret"
+            s3_endpoint = "http://minio.internal:9000"
+            s3_sse = "aws:kms"
+            s3_sse_kms_key_id = "arn:aws:kms:us-east-1:123456789012:key/secret"
+            "#,
+        )
+        .unwrap();
+
+        let public = serde_json::to_value(&tag).unwrap();
+        let hidden = [
+            "webhook_url",
+            "webhook_secret",
+            "s3_endpoint",
+            "s3_sse_kms_key_id",
+        ];
+        for field in hidden {
+            assert!(public.get(field).is_none(), "{} was published", field);
+        }
+    }
+}
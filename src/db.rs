@@ -1,14 +1,16 @@
-use crate::config::Tag;
+use crate::config::{Config, Tag};
 use crate::util::result::Error;
-use crate::util::variables::{
-    get_s3_bucket, LOCAL_STORAGE_PATH, MONGO_DATABASE, MONGO_URI, USE_S3,
-};
+use crate::util::variables::{MONGO_DATABASE, MONGO_URI};
 
-use actix_web::web;
-use mongodb::bson::doc;
-use mongodb::{Client, Collection};
+use futures::StreamExt;
+use mongodb::bson::{doc, Binary, Bson, DateTime, Document};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{CreateCollectionOptions, FindOptions, IndexOptions, ValidationLevel};
+use mongodb::{Client, Collection, IndexModel};
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::warn;
 
 static DBCONN: OnceCell<Client> = OnceCell::new();
 
@@ -20,6 +22,151 @@ pub async fn connect() {
     DBCONN.set(client).unwrap();
 }
 
+/// Connect once for every test which needs the database.
+#[cfg(test)]
+pub async fn connect_for_tests() {
+    if DBCONN.get().is_none() {
+        connect().await;
+    }
+}
+
+/// Backfill fields on documents created by older versions.
+pub async fn migrate() -> mongodb::error::Result<()> {
+    get_collection("attachments")
+        .update_many(
+            doc! { "created_at": { "$exists": false } },
+            doc! { "$set": { "created_at": DateTime::now() } },
+            None,
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Create the indexes queries rely on and validate new documents.
+///
+/// Both are idempotent, failures are logged rather than fatal since the
+/// database user might not be allowed to manage the collection.
+pub async fn ensure_indexes() {
+    let col = get_collection("attachments");
+    let indexes = vec![
+        // Used by the expiry sweep.
+        IndexModel::builder()
+            .keys(doc! { "deleted": 1, "expires_at": 1 })
+            .build(),
+        // Used by deduplication.
+        IndexModel::builder()
+            .keys(doc! { "hash": 1, "tag": 1 })
+            .build(),
+    ];
+
+    if let Err(err) = col.create_indexes(indexes, None).await {
+        warn!("Failed to create attachment indexes. {}", err);
+    }
+
+    // Abandoned resumable uploads are removed once they expire.
+    let ttl = IndexModel::builder()
+        .keys(doc! { "expires_at": 1 })
+        .options(
+            IndexOptions::builder()
+                .expire_after(std::time::Duration::from_secs(0))
+                .build(),
+        )
+        .build();
+
+    if let Err(err) = get_upload_collection()
+        .create_index(ttl.clone(), None)
+        .await
+    {
+        warn!("Failed to create upload indexes. {}", err);
+    }
+
+    // Along with their data, which is otherwise found by upload.
+    let chunk_indexes = vec![
+        ttl.clone(),
+        IndexModel::builder()
+            .keys(doc! { "upload": 1, "segment": 1 })
+            .build(),
+    ];
+
+    if let Err(err) = get_upload_chunk_collection()
+        .create_indexes(chunk_indexes, None)
+        .await
+    {
+        warn!("Failed to create upload chunk indexes. {}", err);
+    }
+
+    // As are used upload tokens, which would be refused by then anyway.
+    if let Err(err) = get_used_upload_token_collection()
+        .create_index(ttl, None)
+        .await
+    {
+        warn!("Failed to create upload token indexes. {}", err);
+    }
+
+    let validator = doc! {
+        "$jsonSchema": {
+            "bsonType": "object",
+            "required": ["_id", "tag", "filename", "metadata", "content_type", "size"],
+            "properties": {
+                "_id": { "bsonType": "string" },
+                "tag": { "bsonType": "string" },
+                "filename": { "bsonType": "string" },
+                "metadata": { "bsonType": "object" },
+                "content_type": { "bsonType": "string" },
+                "size": { "bsonType": ["int", "long"] }
+            }
+        }
+    };
+
+    let db = DBCONN.get().unwrap().database(&MONGO_DATABASE);
+    let exists = db
+        .list_collection_names(doc! { "name": "attachments" })
+        .await
+        .map(|names| !names.is_empty())
+        .unwrap_or(true);
+
+    // Existing documents which don't match are left alone.
+    let result = if exists {
+        db.run_command(
+            doc! {
+                "collMod": "attachments",
+                "validator": validator,
+                "validationLevel": "moderate"
+            },
+            None,
+        )
+        .await
+        .map(|_| ())
+    } else {
+        db.create_collection(
+            "attachments",
+            CreateCollectionOptions::builder()
+                .validator(validator)
+                .validation_level(ValidationLevel::Moderate)
+                .build(),
+        )
+        .await
+    };
+
+    if let Err(err) = result {
+        warn!("Failed to set the attachment schema validator. {}", err);
+    }
+}
+
+/// Check the database is reachable.
+pub async fn ping() -> Result<(), Error> {
+    DBCONN
+        .get()
+        .unwrap()
+        .database(&MONGO_DATABASE)
+        .run_command(doc! { "ping": 1 }, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    Ok(())
+}
+
 pub fn get_collection(collection: &str) -> Collection<File> {
     DBCONN
         .get()
@@ -28,17 +175,161 @@ pub fn get_collection(collection: &str) -> Collection<File> {
         .collection(collection)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Upload tokens which can't be used again.
+pub fn get_used_upload_token_collection() -> Collection<UsedUploadToken> {
+    DBCONN
+        .get()
+        .unwrap()
+        .database(&MONGO_DATABASE)
+        .collection("used_upload_tokens")
+}
+
+/// Storage used by each tag, see `crate::quota`.
+pub fn get_quota_collection() -> Collection<QuotaUsage> {
+    DBCONN
+        .get()
+        .unwrap()
+        .database(&MONGO_DATABASE)
+        .collection("tag_quota_used")
+}
+
+/// Resumable uploads which haven't finished yet.
+pub fn get_upload_collection() -> Collection<Upload> {
+    DBCONN
+        .get()
+        .unwrap()
+        .database(&MONGO_DATABASE)
+        .collection("uploads")
+}
+
+/// Chunks of resumable uploads, see `UploadChunk`.
+pub fn get_upload_chunk_collection() -> Collection<UploadChunk> {
+    DBCONN
+        .get()
+        .unwrap()
+        .database(&MONGO_DATABASE)
+        .collection("upload_chunks")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum Metadata {
     File,
     Text,
-    Image { width: isize, height: isize },
-    Video { width: isize, height: isize },
+    Image {
+        width: isize,
+        height: isize,
+        #[serde(default)]
+        animated: bool,
+        /// Frames in a GIF or PNG, missing for other formats and older files.
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        frame_count: Option<u32>,
+    },
+    Video {
+        width: isize,
+        height: isize,
+    },
     Audio,
+    /// Sized by the first page, in points.
+    Document {
+        width: isize,
+        height: isize,
+        page_count: u32,
+    },
 }
 
+impl Metadata {
+    /// Frames in a GIF or PNG, `None` for other formats and files from before they were counted.
+    pub fn frame_count(&self) -> Option<u32> {
+        match self {
+            Metadata::Image { frame_count, .. } => *frame_count,
+            _ => None,
+        }
+    }
+}
+
+/// A resized copy of an image generated ahead of time.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Variant {
+    pub id: String,
+    pub width: u32,
+    pub height: u32,
+    pub content_type: String,
+}
+
+/// A dominant colour of an image.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Swatch {
+    pub hex: String,
+    /// Fraction of the image closest to this colour.
+    pub fraction: f32,
+}
+
+/// Dominant colours of an image, kept so they aren't computed on every request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Palette {
+    /// Number of colours that were requested.
+    pub colors: u32,
+    pub swatches: Vec<Swatch>,
+}
+
+/// State of a resumable upload, its data is kept on disk until it completes.
 #[derive(Serialize, Deserialize, Debug)]
+pub struct Upload {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub tag: String,
+    pub filename: String,
+    /// Total size of the file in bytes.
+    pub length: i64,
+    /// Bytes received so far.
+    pub offset: i64,
+    /// Requests whose chunks make up the bytes received so far, in order.
+    #[serde(default)]
+    pub segments: Vec<String>,
+    pub expires_at: DateTime,
+}
+
+/// Data sent to a resumable upload, kept in the database so that
+/// any instance can carry on with an upload another one started.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadChunk {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub upload: String,
+    /// Request the chunk was sent in, which only counts once it's one of the upload's segments.
+    pub segment: String,
+    /// Position of the chunk within its segment.
+    pub index: i32,
+    pub data: Binary,
+    pub expires_at: DateTime,
+}
+
+/// An upload token which was already used, see `crate::util::upload_token`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UsedUploadToken {
+    #[serde(rename = "_id")]
+    pub nonce: String,
+    pub expires_at: DateTime,
+}
+
+/// Bytes used by a tag's live files, kept up to date by uploads and deletes.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuotaUsage {
+    #[serde(rename = "_id")]
+    pub tag: String,
+    pub used: i64,
+}
+
+/// Number and total size of the files in a tag.
+#[derive(Serialize, Debug)]
+pub struct TagUsage {
+    pub tag: String,
+    pub file_count: i64,
+    pub total_size_bytes: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct File {
     #[serde(rename = "_id")]
     pub id: String,
@@ -46,36 +337,110 @@ pub struct File {
     pub filename: String,
     pub metadata: Metadata,
     pub content_type: String,
+    /// Size of the stored file in bytes, exposed as `file_size` by the stats endpoint.
     pub size: isize,
+    /// Hex SHA-256 of the contents, used for deduplication and integrity checks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_count: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variants: Option<Vec<Variant>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub palette: Option<Palette>,
+    /// Hex perceptual hash of images, used to find similar ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<BTreeMap<String, serde_json::Value>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub deleted: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub reported: Option<bool>,
+    /// ID of the stored object this file shares with the file it was copied from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_id: Option<String>,
+    /// ID of the stored object, once replaced contents were written under a new one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub object_id: Option<String>,
+    /// Where the contents are hosted instead, for files in tags that redirect.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_url: Option<String>,
+    /// Whether the image was converted to sRGB from the colour profile it was uploaded with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color_space_normalized: Option<bool>,
 }
 
 impl File {
+    /// ID the contents are kept under in storage.
+    pub fn storage_id(&self) -> &str {
+        self.source_id
+            .as_deref()
+            .or(self.object_id.as_deref())
+            .unwrap_or(&self.id)
+    }
+
+    /// Identifies the contents, so anything derived from them can tell once they're replaced.
+    pub fn version(&self) -> String {
+        self.hash
+            .clone()
+            .or_else(|| {
+                self.updated_at
+                    .map(|date| date.timestamp_millis().to_string())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Number of other files sharing this file's stored object, through copies.
+    pub async fn references(&self) -> Result<u64, Error> {
+        self.count_sharing(doc! {}).await
+    }
+
+    /// Number of other files sharing this file's stored object which haven't been deleted.
+    pub async fn live_references(&self) -> Result<u64, Error> {
+        self.count_sharing(doc! { "deleted": { "$ne": true } })
+            .await
+    }
+
+    async fn count_sharing(&self, mut filter: Document) -> Result<u64, Error> {
+        filter.insert("_id", doc! { "$ne": &self.id });
+        filter.insert(
+            "$or",
+            vec![
+                doc! { "_id": self.storage_id() },
+                doc! { "source_id": self.storage_id() },
+                doc! { "object_id": self.storage_id() },
+            ],
+        );
+
+        get_collection("attachments")
+            .count_documents(filter, None)
+            .await
+            .map_err(|_| Error::DatabaseError)
+    }
+
     pub async fn delete_in_storage(&self) -> Result<(), Error> {
-        if *USE_S3 {
-            let bucket = get_s3_bucket(&self.tag)?;
+        crate::cache::invalidate(&self.id);
 
-            let (_, code) = bucket
-                .delete_object(format!("/{}", &self.id))
-                .await
-                .map_err(|_| Error::S3Error)?;
+        // The object is only removed along with the last file using it.
+        if self.original_url.is_some() || self.references().await? > 0 {
+            return Ok(());
+        }
 
-            if code != 200 {
-                return Err(Error::S3Error);
-            }
-        } else {
-            let path = format!("{}/{}", *LOCAL_STORAGE_PATH, &self.id);
-            web::block(|| std::fs::remove_file(path))
-                .await
-                .map_err(|_| Error::BlockingError)?
-                .map_err(|_| Error::IOError)?;
+        for variant in self.variants.iter().flatten() {
+            crate::storage::delete(&self.tag, &variant.id).await.ok();
         }
 
-        Ok(())
+        crate::storage::delete(&self.tag, self.storage_id()).await
     }
 
     pub async fn delete(self) -> Result<(), Error> {
@@ -86,11 +451,168 @@ impl File {
             .await
             .map_err(|_| Error::DatabaseError)?;
 
+        // Deleted files were already uncounted.
+        if self.deleted != Some(true) {
+            crate::quota::removed(&self).await;
+        }
+
         println!("Deleted attachment {}", self.id);
         Ok(())
     }
 }
 
+/// Deleted files past the retention window, optionally only those in `tag`.
+/// Reported files are kept for review.
+pub fn purgeable_filter(tag: Option<&str>, older_than: u64) -> Document {
+    let cutoff = DateTime::now().timestamp_millis() - older_than as i64 * 1000;
+    let mut filter = doc! {
+        "deleted": true,
+        "reported": { "$ne": true },
+        "$or": [
+            { "deleted_at": { "$lte": DateTime::from_millis(cutoff) } },
+            // Deleted before deletion times were recorded.
+            { "deleted_at": { "$exists": false } }
+        ]
+    };
+
+    if let Some(tag) = tag {
+        filter.insert("tag", tag);
+    }
+
+    filter
+}
+
+/// Permanently remove deleted files in `tag` which were deleted at least
+/// `older_than` seconds ago, returning how many were removed.
+pub async fn purge_deleted(tag: &str, older_than: u64) -> Result<usize, Error> {
+    let mut cursor = get_collection("attachments")
+        .find(purgeable_filter(Some(tag), older_than), None)
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    let mut count = 0;
+    while let Some(file) = cursor.next().await {
+        file.map_err(|_| Error::DatabaseError)?.delete().await?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Mark up to `limit` expired files as deleted and remove them from storage,
+/// returning how many were expired.
+pub async fn purge_expired(limit: i64) -> Result<usize, Error> {
+    let col = get_collection("attachments");
+    let mut cursor = col
+        .find(
+            doc! {
+                "expires_at": { "$lte": DateTime::now() },
+                "deleted": { "$ne": true }
+            },
+            FindOptions::builder().limit(limit).build(),
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    let mut count = 0;
+    while let Some(file) = cursor.next().await {
+        let file = file.map_err(|_| Error::DatabaseError)?;
+
+        col.update_one(
+            doc! { "_id": &file.id },
+            doc! { "$set": { "deleted": true, "deleted_at": DateTime::now() } },
+            None,
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+        crate::quota::removed(&file).await;
+        if Config::global().deleted_retention == 0 {
+            if let Err(err) = file.delete_in_storage().await {
+                warn!(
+                    "Failed to delete expired file {} from storage. {:?}",
+                    file.id, err
+                );
+            }
+        }
+
+        crate::webhook::notify(crate::webhook::Event::Delete, &file);
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Counts are summed as whichever integer type fits, so read either.
+fn number(document: &Document, key: &str) -> i64 {
+    match document.get(key) {
+        Some(Bson::Int32(value)) => *value as i64,
+        Some(Bson::Int64(value)) => *value,
+        Some(Bson::Double(value)) => *value as i64,
+        _ => 0,
+    }
+}
+
+/// Number of files and bytes stored in each tag, sorted by tag.
+///
+/// Files sharing an object through copies count it once between them, even once
+/// the original is gone. Redirected files aren't stored, so they only count towards the file count.
+pub async fn usage_by_tag(include_deleted: bool) -> Result<Vec<TagUsage>, Error> {
+    let filter = if include_deleted {
+        doc! {}
+    } else {
+        doc! { "deleted": { "$ne": true } }
+    };
+
+    let pipeline = vec![
+        doc! { "$match": filter },
+        // One group for each stored object, as `File::storage_id` names it.
+        doc! {
+            "$group": {
+                "_id": {
+                    "tag": "$tag",
+                    "object": { "$ifNull": ["$source_id", { "$ifNull": ["$object_id", "$_id"] }] }
+                },
+                "file_count": { "$sum": 1 },
+                "size": {
+                    "$max": {
+                        "$cond": [
+                            { "$eq": [{ "$ifNull": ["$original_url", null] }, null] },
+                            "$size",
+                            0
+                        ]
+                    }
+                }
+            }
+        },
+        doc! {
+            "$group": {
+                "_id": "$_id.tag",
+                "file_count": { "$sum": "$file_count" },
+                "total_size_bytes": { "$sum": "$size" }
+            }
+        },
+        doc! { "$sort": { "_id": 1 } },
+    ];
+
+    let mut cursor = get_collection("attachments")
+        .aggregate(pipeline, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    let mut usage = vec![];
+    while let Some(group) = cursor.next().await {
+        let group = group.map_err(|_| Error::DatabaseError)?;
+        usage.push(TagUsage {
+            tag: group.get_str("_id").unwrap_or_default().to_string(),
+            file_count: number(&group, "file_count"),
+            total_size_bytes: number(&group, "total_size_bytes"),
+        });
+    }
+
+    Ok(usage)
+}
+
 pub async fn find_file(id: &str, tag: (String, &Tag)) -> Result<File, Error> {
     let mut query = doc! { "_id": id, "tag": tag.0 };
 
@@ -113,3 +635,14 @@ pub async fn find_file(id: &str, tag: (String, &Tag)) -> Result<File, Error> {
         .map_err(|_| Error::DatabaseError)?
         .ok_or(Error::NotFound)
 }
+
+/// MongoDB's code for a duplicate key.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Whether a write failed because another document already has the same key.
+pub fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    matches!(
+        &*err.kind,
+        ErrorKind::Write(WriteFailure::WriteError(error)) if error.code == DUPLICATE_KEY
+    )
+}
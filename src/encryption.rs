@@ -0,0 +1,280 @@
+use crate::config::Config;
+use crate::db::{get_collection, File};
+use crate::storage::write_atomic;
+use crate::util::result::Error;
+use crate::util::variables::get_local_path;
+
+use actix_web::web;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use futures::StreamExt;
+use mongodb::bson::doc;
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::{info, warn};
+
+/// Start of every encrypted file, followed by the nonce then the sealed contents.
+/// Files without it were written before encryption was enabled and are read as they are.
+const MAGIC: &[u8; 8] = b"AUTUMNX1";
+
+/// XChaCha20 nonces are long enough to be picked at random for every file.
+const NONCE_LENGTH: usize = 24;
+
+const HEADER_LENGTH: usize = MAGIC.len() + NONCE_LENGTH;
+
+pub type Key = [u8; 32];
+
+fn to_key(bytes: Vec<u8>) -> Option<Key> {
+    let mut key = [0; 32];
+    if bytes.len() != key.len() {
+        return None;
+    }
+
+    key.copy_from_slice(&bytes);
+    Some(key)
+}
+
+/// Decode a key as written in the config.
+pub fn parse_key(key: &str) -> Option<Key> {
+    base64::decode(key).ok().and_then(to_key)
+}
+
+/// Decode a key as given on the command line.
+pub fn parse_hex_key(key: &str) -> Option<Key> {
+    hex::decode(key).ok().and_then(to_key)
+}
+
+fn current_key(tag: &str) -> Option<Key> {
+    Config::global()
+        .tags
+        .get(tag)
+        .and_then(|tag| tag.local_encryption_key.as_deref())
+        .and_then(parse_key)
+}
+
+/// Keys a tag's files may be encrypted with, the current one first.
+fn keys(tag: &str) -> Vec<Key> {
+    let tag = match Config::global().tags.get(tag) {
+        Some(tag) => tag,
+        None => return vec![],
+    };
+
+    tag.local_encryption_key
+        .iter()
+        .chain(tag.previous_local_encryption_keys.iter())
+        .filter_map(|key| parse_key(key))
+        .collect()
+}
+
+/// Whether two tags' files can be read and written with the same keys.
+pub fn same_keys(a: &str, b: &str) -> bool {
+    keys(a) == keys(b)
+}
+
+/// Whether some of a tag's files may be encrypted.
+pub fn enabled(tag: &str) -> bool {
+    !keys(tag).is_empty()
+}
+
+fn cipher(key: &Key) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(key.into())
+}
+
+/// Whether contents were written encrypted.
+pub fn is_encrypted(buf: &[u8]) -> bool {
+    buf.len() >= HEADER_LENGTH && buf.starts_with(MAGIC)
+}
+
+/// Encrypt contents under a key with a fresh nonce.
+pub fn encrypt(key: &Key, contents: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut nonce = [0; NONCE_LENGTH];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| Error::IOError)?;
+
+    let payload = Payload {
+        msg: contents,
+        aad: MAGIC,
+    };
+
+    let sealed = cipher(key)
+        .encrypt(XNonce::from_slice(&nonce), payload)
+        .map_err(|_| Error::IOError)?;
+
+    let mut buf = Vec::with_capacity(HEADER_LENGTH + sealed.len());
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&nonce);
+    buf.extend_from_slice(&sealed);
+    Ok(buf)
+}
+
+/// Decrypt contents written by `encrypt`, failing if they were encrypted under another key
+/// or have been tampered with.
+pub fn decrypt(key: &Key, buf: &[u8]) -> Result<Vec<u8>, Error> {
+    if !is_encrypted(buf) {
+        return Err(Error::IntegrityError);
+    }
+
+    let payload = Payload {
+        msg: &buf[HEADER_LENGTH..],
+        aad: MAGIC,
+    };
+
+    cipher(key)
+        .decrypt(
+            XNonce::from_slice(&buf[MAGIC.len()..HEADER_LENGTH]),
+            payload,
+        )
+        .map_err(|_| Error::IntegrityError)
+}
+
+/// Contents as they should be written to disk for a tag.
+pub fn seal(tag: &str, buf: Vec<u8>) -> Result<Vec<u8>, Error> {
+    match current_key(tag) {
+        Some(key) => encrypt(&key, &buf),
+        None => Ok(buf),
+    }
+}
+
+/// Contents as they were before being written to disk for a tag,
+/// trying each of its keys in case a rotation is in progress.
+pub fn open(tag: &str, buf: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if !is_encrypted(&buf) {
+        return Ok(buf);
+    }
+
+    keys(tag)
+        .iter()
+        .find_map(|key| decrypt(key, &buf).ok())
+        .ok_or(Error::IntegrityError)
+}
+
+enum Rotated {
+    Rotated,
+    Skipped,
+}
+
+fn rotate_file(path: &str, old_key: &Key, new_key: &Key) -> Result<Rotated, Error> {
+    let buf = match std::fs::read(path) {
+        Ok(buf) => buf,
+        // Deleted, or never written in this tag's storage.
+        Err(_) => return Ok(Rotated::Skipped),
+    };
+
+    let contents = if is_encrypted(&buf) {
+        if decrypt(new_key, &buf).is_ok() {
+            return Ok(Rotated::Skipped);
+        }
+
+        decrypt(old_key, &buf)?
+    } else {
+        buf
+    };
+
+    // The new contents are renamed over the old, so readers see one or the other.
+    write_atomic(path, &encrypt(new_key, &contents)?).map_err(|_| Error::IOError)?;
+    Ok(Rotated::Rotated)
+}
+
+/// Re-encrypt every file in a tag under a new key, encrypting any still in plaintext.
+/// Copies in a local `secondary_storage` are re-encrypted along with them.
+///
+/// The server keeps serving throughout as long as it has the new key as `local_encryption_key`
+/// and the old one in `previous_local_encryption_keys`. Files already under the new key are
+/// skipped, so an interrupted rotation can be run again.
+pub async fn rotate(tag: &str, old_key: Key, new_key: Key) -> Result<(), Error> {
+    let mut cursor = get_collection("attachments")
+        .find(doc! { "tag": tag, "source_id": { "$exists": false } }, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    let mut rotated = 0;
+    let mut skipped = 0;
+    while let Some(file) = cursor.next().await {
+        let file: File = file.map_err(|_| Error::DatabaseError)?;
+        let ids = std::iter::once(file.storage_id().to_string()).chain(
+            file.variants
+                .iter()
+                .flatten()
+                .map(|variant| variant.id.clone()),
+        );
+
+        let paths = ids.flat_map(|id| {
+            let copy = crate::mirror::local_path(tag, &id);
+            std::iter::once(get_local_path(tag, &id))
+                .chain(copy)
+                .map(move |path| (id.clone(), path))
+        });
+
+        for (id, path) in paths {
+            let result = web::block(move || rotate_file(&path, &old_key, &new_key))
+                .await
+                .map_err(|_| Error::BlockingError)?;
+
+            match result {
+                Ok(Rotated::Rotated) => rotated += 1,
+                Ok(Rotated::Skipped) => skipped += 1,
+                Err(err) => {
+                    warn!("Failed to rotate the key of {}. {:?}", id, err);
+                    skipped += 1;
+                }
+            }
+        }
+    }
+
+    info!(
+        "Re-encrypted {} files in {}, skipped {}.",
+        rotated, tag, skipped
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(start: u8) -> Key {
+        let mut key = [0; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = start + i as u8;
+        }
+
+        key
+    }
+
+    // Test vector from draft-irtf-cfrg-xchacha-03, so files written before
+    // the switch to chacha20poly1305 still open.
+    #[test]
+    fn matches_the_xchacha20_poly1305_test_vector() {
+        let nonce = hex::decode("404142434445464748494a4b4c4d4e4f5051525354555657").unwrap();
+        let aad = hex::decode("50515253c0c1c2c3c4c5c6c7").unwrap();
+        let msg = b"Ladies and Gentlemen of the class of '99: If I could offer you only one \
+            tip for the future, sunscreen would be it.";
+
+        let buf = cipher(&key(0x80))
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg, aad: &aad })
+            .unwrap();
+
+        let (ciphertext, tag) = buf.split_at(buf.len() - 16);
+        assert_eq!(
+            hex::encode(ciphertext),
+            "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb\
+             731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b452\
+             2f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9\
+             21f9664c97637da9768812f615c68b13b52e"
+        );
+        assert_eq!(hex::encode(tag), "c0875924c1c7987947deafd8780acf49");
+    }
+
+    #[test]
+    fn round_trips_and_rejects_other_keys() {
+        let sealed = encrypt(&key(0), b"hello").unwrap();
+        assert!(is_encrypted(&sealed));
+        assert_eq!(decrypt(&key(0), &sealed).unwrap(), b"hello");
+        assert!(decrypt(&key(1), &sealed).is_err());
+
+        let mut tampered = sealed;
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&key(0), &tampered).is_err());
+    }
+}
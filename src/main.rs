@@ -1,22 +1,36 @@
+pub mod cache;
+pub mod cli;
 pub mod config;
 pub mod db;
+pub mod encryption;
+pub mod mirror;
+pub mod orphans;
+#[cfg(feature = "pdf")]
+pub mod pdf;
+pub mod quota;
 pub mod routes;
+pub mod stats;
+pub mod storage;
+pub mod thumbnails;
 pub mod util;
 pub mod version;
 pub mod virus_scan;
+pub mod webhook;
 
 use futures::StreamExt;
-use util::variables::{CONFIG, HOST, LOCAL_STORAGE_PATH, USE_S3};
+use util::variables::{
+    CONFIG, GCS_SERVICE_ACCOUNT, HOST, LOCAL_STORAGE_PATH, USE_AZURE, USE_GCS, USE_S3,
+};
 
 #[macro_use]
 extern crate lazy_static;
 extern crate tree_magic;
 
 use actix_cors::Cors;
+use actix_web::http::Method;
 use actix_web::{middleware, web, App, HttpServer};
-use log::info;
-use mongodb::bson::doc;
 use std::env;
+use tracing::{error, info, warn};
 
 pub static CACHE_CONTROL: &str = "public, max-age=604800, must-revalidate";
 
@@ -24,6 +38,11 @@ pub static CACHE_CONTROL: &str = "public, max-age=604800, must-revalidate";
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
 
+    let command = cli::parse().unwrap_or_else(|usage| {
+        eprintln!("{}", usage);
+        std::process::exit(2);
+    });
+
     if let Ok(v) = env::var("MINIO_ROOT_USER") {
         env::set_var("AWS_ACCESS_KEY_ID", v);
     }
@@ -32,22 +51,112 @@ async fn main() -> std::io::Result<()> {
         env::set_var("AWS_SECRET_ACCESS_KEY", v);
     }
 
-    env_logger::init_from_env(env_logger::Env::default().filter_or("RUST_LOG", "info"));
+    // cloud-storage reads its credentials from SERVICE_ACCOUNT.
+    if let Some(path) = &*GCS_SERVICE_ACCOUNT {
+        env::set_var("SERVICE_ACCOUNT", path);
+    }
 
     config::Config::init()
         .unwrap_or_else(|err| panic!("Unable to load the config '{}'. {}", *CONFIG, err));
 
+    util::telemetry::init();
+
     info!("Starting Autumn server.");
 
+    let config = config::Config::global();
+    if config.trust_proxy_headers && config.trusted_proxy_cidrs.is_empty() {
+        warn!("trust_proxy_headers is set without trusted_proxy_cidrs, so any client can spoof its address with X-Forwarded-For.");
+    }
+
     virus_scan::init();
 
     db::connect().await;
 
-    if !*USE_S3 {
+    info!("Migrating existing attachments.");
+    if let Err(err) = db::migrate().await {
+        error!("Failed to migrate attachments. {}", err);
+        std::process::exit(1);
+    }
+
+    db::ensure_indexes().await;
+
+    match command {
+        cli::Command::Serve => {}
+        cli::Command::BackfillThumbnails { tag } => {
+            if let Err(err) = thumbnails::backfill(&tag).await {
+                error!("Failed to backfill thumbnails. {:?}", err);
+                std::process::exit(1);
+            }
+
+            return Ok(());
+        }
+        cli::Command::MigrateLocalStorage => {
+            let count = storage::migrate_local_storage()?;
+            info!("Moved {} files into sharded directories.", count);
+            return Ok(());
+        }
+        cli::Command::CleanOrphans { dry_run, min_age } => {
+            if *USE_S3 || *USE_GCS || *USE_AZURE {
+                error!("clean-orphans only works with local storage.");
+                std::process::exit(1);
+            }
+
+            let min_age = std::time::Duration::from_secs(min_age * 60);
+            if let Err(err) = orphans::clean(dry_run, min_age).await {
+                error!("Failed to clean orphaned files. {:?}", err);
+                std::process::exit(1);
+            }
+
+            return Ok(());
+        }
+        cli::Command::RotateKey {
+            tag,
+            old_key,
+            new_key,
+        } => {
+            if *USE_S3 || *USE_GCS || *USE_AZURE {
+                error!("rotate-key only works with local storage.");
+                std::process::exit(1);
+            }
+
+            let keys = (
+                encryption::parse_hex_key(&old_key),
+                encryption::parse_hex_key(&new_key),
+            );
+
+            let (old_key, new_key) = match keys {
+                (Some(old_key), Some(new_key)) => (old_key, new_key),
+                _ => {
+                    error!("Keys must be hex encoded and 32 bytes long.");
+                    std::process::exit(2);
+                }
+            };
+
+            if let Err(err) = encryption::rotate(&tag, old_key, new_key).await {
+                error!("Failed to rotate keys. {:?}", err);
+                std::process::exit(1);
+            }
+
+            return Ok(());
+        }
+    }
+
+    if *USE_AZURE {
+        info!("Skipping existence check, make sure your Azure containers exist!");
+    } else if *USE_S3 {
+        info!("Skipping existence check, make sure your S3 buckets exist!");
+        storage::check_encryption().await;
+    } else if *USE_GCS {
+        info!("Skipping existence check, make sure your GCS buckets exist!");
+    } else {
         info!("Ensuring local storage directory exists.");
         std::fs::create_dir_all(LOCAL_STORAGE_PATH.to_string()).unwrap();
-    } else {
-        info!("Skipping existence check, make sure your S3 buckets exist!");
+
+        for tag in config::Config::global().tags.values() {
+            if let Some(path) = &tag.local_path_override {
+                std::fs::create_dir_all(path).unwrap();
+            }
+        }
     }
 
     tokio::spawn(async {
@@ -60,16 +169,9 @@ async fn main() -> std::io::Result<()> {
                     |_, _| {
                         tokio::spawn(async {
                             let col = db::get_collection("attachments");
+                            let retention = config::Config::global().deleted_retention;
                             let mut cursor = col
-                                .find(
-                                    doc! {
-                                        "deleted": true,
-                                        "reported": {
-                                            "$ne": true
-                                        }
-                                    },
-                                    None,
-                                )
+                                .find(db::purgeable_filter(None, retention), None)
                                 .await
                                 .unwrap();
 
@@ -88,35 +190,281 @@ async fn main() -> std::io::Result<()> {
             )
             .unwrap();
 
+        sched
+            .add(
+                tokio_cron_scheduler::Job::new_repeated(
+                    core::time::Duration::from_secs(60),
+                    |_, _| util::ratelimit::prune(),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        let interval = config::Config::global().quota_reconcile_interval;
+        sched
+            .add(
+                tokio_cron_scheduler::Job::new_repeated(
+                    core::time::Duration::from_secs(interval),
+                    |_, _| {
+                        tokio::spawn(async {
+                            if let Err(err) = quota::reconcile().await {
+                                error!("Failed to reconcile storage quotas. {:?}", err);
+                            }
+                        });
+                    },
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        // Counters start out empty, or stale from before a restart.
+        if let Err(err) = quota::reconcile().await {
+            error!("Failed to reconcile storage quotas. {:?}", err);
+        }
+
         sched.start().await.unwrap();
     });
 
-    HttpServer::new(|| {
+    let (shutdown, mut shutdown_signal) = tokio::sync::watch::channel(false);
+    let expiry = tokio::spawn(async move {
+        let config = &config::Config::global().expiry;
+        let mut interval =
+            tokio::time::interval(core::time::Duration::from_secs(config.sweep_interval));
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    match db::purge_expired(config.batch_size).await {
+                        Ok(0) => {}
+                        Ok(count) => info!("Expired {} files.", count),
+                        Err(err) => error!("Failed to expire files. {:?}", err),
+                    }
+                }
+                _ = shutdown_signal.changed() => break,
+            }
+        }
+    });
+
+    let mut shutdown_signal = shutdown.subscribe();
+    let stats = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(core::time::Duration::from_secs(
+            config::Config::global().stats_flush_interval,
+        ));
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown_signal.changed() => break,
+            }
+
+            if let Err(err) = stats::flush().await {
+                error!("Failed to flush download counts. {:?}", err);
+            }
+        }
+
+        // Write anything counted since the last flush.
+        if let Err(err) = stats::flush().await {
+            error!("Failed to flush download counts. {:?}", err);
+        }
+    });
+
+    let mut server = HttpServer::new(|| {
+        let health_prefix = &config::Config::global().health_path_prefix;
+        let security = &config::Config::global().security_headers;
+        let mut headers = middleware::DefaultHeaders::new();
+        if security.x_content_type_options {
+            headers = headers.header("X-Content-Type-Options", "nosniff");
+        }
+
+        if security.x_frame_options {
+            headers = headers.header("X-Frame-Options", "SAMEORIGIN");
+        }
+
+        if security.referrer_policy {
+            headers = headers.header("Referrer-Policy", "strict-origin-when-cross-origin");
+        }
+
         App::new()
+            .wrap(headers)
             .wrap(
                 Cors::default()
-                    .allowed_origin_fn(|_, _| true)
-                    .allowed_methods(vec!["GET", "POST"])
-                    .allowed_headers(["X-Session-Token", "X-Bot-Token"])
+                    .allowed_origin_fn(|origin, head| {
+                        origin
+                            .to_str()
+                            .map(|origin| config::origin_allowed(origin, head.uri.path()))
+                            .unwrap_or(false)
+                    })
+                    .allowed_methods(vec!["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"])
+                    .allowed_headers([
+                        "X-Session-Token",
+                        "X-Bot-Token",
+                        "Authorization",
+                        "Content-Type",
+                        "Tus-Resumable",
+                        "Upload-Length",
+                        "Upload-Offset",
+                        "Upload-Metadata",
+                        "X-Upload-Token",
+                        "If-Match",
+                        "If-None-Match",
+                        util::request_id::HEADER,
+                    ])
+                    .expose_headers([
+                        "Location",
+                        "Tus-Resumable",
+                        "Upload-Length",
+                        "Upload-Offset",
+                        "X-Autumn-File-Id",
+                        "ETag",
+                        util::request_id::HEADER,
+                    ])
                     .supports_credentials(),
             )
-            .wrap(middleware::Logger::default())
+            .wrap(middleware::Compress::default())
+            .wrap(util::access_log::AccessLog)
+            .wrap(util::metrics::RequestMetrics)
+            .wrap(util::telemetry::RequestSpan)
+            .wrap(util::client_hints::AcceptClientHints)
+            .wrap(util::request_id::RequestIds)
+            .route(
+                &format!("{}/health", health_prefix),
+                web::get().to(routes::health::health),
+            )
+            .route(
+                &format!("{}/ready", health_prefix),
+                web::get().to(routes::health::ready),
+            )
+            .route("/metrics", web::get().to(routes::metrics::get))
+            .route("/metrics/cache", web::get().to(routes::metrics::cache))
+            .route("/admin/stats", web::get().to(routes::admin::stats))
+            .route("/admin/quotas", web::get().to(routes::admin::quotas))
+            .route(
+                "/admin/sync-to-secondary",
+                web::post().to(routes::admin::sync_to_secondary),
+            )
+            .route(
+                "/admin/upload-token",
+                web::post().to(routes::admin::upload_token),
+            )
+            .route(
+                "/admin/{tag:[^/]*}/{filename:[^/]*}/reextract",
+                web::post().to(routes::admin::reextract),
+            )
+            .route(
+                "/diagnostics/buckets",
+                web::get().to(routes::diagnostics::buckets),
+            )
             .route("/{tag:[^/]*}", web::post().to(routes::upload::post))
+            .route("/{tag:[^/]*}/", web::get().to(routes::list::get))
             .route(
                 "/{tag:[^/]*}/download/{filename:.*}",
                 web::get().to(routes::download::get),
             )
+            .route("/{tag:[^/]*}/bulk", web::delete().to(routes::bulk::delete))
+            .route("/{tag:[^/]*}/fetch", web::post().to(routes::fetch::post))
+            .service(
+                web::resource("/{tag:[^/]*}/archive")
+                    .route(web::get().to(routes::archive::get))
+                    .route(web::post().to(routes::archive::post)),
+            )
             .route(
-                "/{tag:[^/]*}/{filename:[^/]*}",
-                web::get().to(routes::serve::get),
+                "/{tag:[^/]*}/base64",
+                web::post().to(routes::base64_upload::post),
+            )
+            .service(
+                web::resource("/{tag:[^/]*}/tus/")
+                    .route(web::post().to(routes::tus::create))
+                    .route(
+                        web::route()
+                            .method(Method::OPTIONS)
+                            .to(routes::tus::options),
+                    ),
+            )
+            .service(
+                web::resource("/{tag:[^/]*}/tus/{id}")
+                    .route(web::head().to(routes::tus::head))
+                    .route(web::patch().to(routes::tus::patch)),
+            )
+            .route(
+                "/{tag:[^/]*}/purge-deleted",
+                web::post().to(routes::purge::post_deleted),
             )
             .route(
-                "/{tag:[^/]*}/{filename:[^/]*}/{fn:.*}",
-                web::get().to(routes::serve::get),
+                "/{tag:[^/]*}/similar/{filename:[^/]*}",
+                web::get().to(routes::similar::get),
+            )
+            .service(
+                web::resource("/{tag:[^/]*}/{filename:[^/]*}")
+                    .wrap(util::ratelimit::RateLimit)
+                    .route(web::get().to(routes::serve::get))
+                    .route(web::put().to(routes::upload::put))
+                    .route(web::patch().to(routes::update::patch)),
+            )
+            .route(
+                "/{tag:[^/]*}/{filename:[^/]*}/stats",
+                web::get().to(routes::stats::get),
+            )
+            .route(
+                "/{tag:[^/]*}/{filename:[^/]*}/palette",
+                web::get().to(routes::palette::get),
+            )
+            .route(
+                "/{tag:[^/]*}/{filename:[^/]*}/srcset",
+                web::get().to(routes::srcset::get),
+            )
+            .service(
+                web::resource("/{tag:[^/]*}/{filename:[^/]*}/batch-resize")
+                    .wrap(util::ratelimit::RateLimit)
+                    .route(web::get().to(routes::batch::get)),
+            )
+            .route(
+                "/{tag:[^/]*}/{filename:[^/]*}/copy",
+                web::post().to(routes::copy::post),
+            )
+            .route(
+                "/{tag:[^/]*}/{filename:[^/]*}/move",
+                web::post().to(routes::move_file::post),
+            )
+            .route(
+                "/{tag:[^/]*}/{filename:[^/]*}/purge",
+                web::post().to(routes::purge::post),
+            )
+            .service(
+                web::resource("/{tag:[^/]*}/{filename:[^/]*}/{fn:.*}")
+                    .wrap(util::ratelimit::RateLimit)
+                    .route(web::get().to(routes::serve::get)),
             )
             .route("/", web::get().to(routes::index::get))
-    })
-    .bind(HOST.clone())?
-    .run()
-    .await
+    });
+
+    let config = config::Config::global();
+    if let Some(seconds) = config.keep_alive_timeout_seconds {
+        server = server.keep_alive(seconds as usize);
+    }
+
+    if let Some(seconds) = config.client_request_timeout_seconds {
+        server = server.client_timeout(seconds * 1000);
+    }
+
+    if let Some(max_connections) = config.max_connections {
+        server = server.max_connections(max_connections);
+    }
+
+    let server = server
+        .bind(HOST.clone())?
+        // Signals are handled below, so requests can be drained first.
+        .disable_signals()
+        .shutdown_timeout(config.shutdown_timeout_seconds)
+        .run();
+
+    tokio::spawn(util::shutdown::drain_on_signal(server.clone()));
+    server.await?;
+
+    // Let an in-progress sweep finish before exiting.
+    shutdown.send(true).ok();
+    expiry.await.ok();
+    stats.await.ok();
+    util::telemetry::shutdown();
+
+    Ok(())
 }
@@ -0,0 +1,220 @@
+use crate::config::{Config, StorageConfig};
+use crate::db::{get_collection, File};
+use crate::encryption;
+use crate::orphans::walk;
+use crate::storage::write_atomic;
+use crate::util::metrics::{s3_error, SECONDARY_WRITE_FAILURES};
+use crate::util::result::Error;
+use crate::util::variables::{configured_s3_bucket, get_sharded_path};
+
+use actix_web::web;
+use futures::StreamExt;
+use mongodb::bson::doc;
+use std::collections::HashSet;
+use std::path::Path;
+use tracing::{error, warn};
+
+fn secondary(tag: &str) -> Option<&'static StorageConfig> {
+    Config::global()
+        .tags
+        .get(tag)
+        .and_then(|tag| tag.secondary_storage.as_ref())
+}
+
+/// Whether a tag's writes are mirrored.
+pub fn enabled(tag: &str) -> bool {
+    secondary(tag).is_some()
+}
+
+/// Where a file's copy is kept, if the tag is mirrored to local storage.
+pub fn local_path(tag: &str, id: &str) -> Option<String> {
+    match secondary(tag) {
+        Some(StorageConfig::Local { path }) => Some(get_sharded_path(path, id)),
+        _ => None,
+    }
+}
+
+async fn put(
+    storage: &StorageConfig,
+    tag: &str,
+    id: &str,
+    content_type: &str,
+    buf: Vec<u8>,
+) -> Result<(), Error> {
+    match storage {
+        StorageConfig::Local { path } => {
+            // Encrypted like the tag's own local storage, rather than leaving a plaintext copy.
+            let path = get_sharded_path(path, id);
+            let tag = tag.to_string();
+            web::block(move || {
+                let buf = encryption::seal(&tag, buf)?;
+                write_atomic(&path, &buf).map_err(|_| Error::IOError)
+            })
+            .await
+            .map_err(|_| Error::BlockingError)?
+        }
+        StorageConfig::S3(config) => {
+            let bucket = configured_s3_bucket(config)?;
+            let (_, code) = bucket
+                .put_object_with_content_type(format!("/{}", id), &buf, content_type)
+                .await
+                .map_err(s3_error)?;
+
+            match code {
+                200 => Ok(()),
+                code => Err(s3_error(code)),
+            }
+        }
+    }
+}
+
+/// IDs of everything in a secondary storage.
+async fn list(storage: &StorageConfig) -> Result<HashSet<String>, Error> {
+    match storage {
+        StorageConfig::Local { path } => {
+            let path = path.clone();
+            let files = web::block(move || {
+                let mut files = vec![];
+                if Path::new(&path).exists() {
+                    walk(Path::new(&path), &mut files)?;
+                }
+
+                Ok::<_, std::io::Error>(files)
+            })
+            .await
+            .map_err(|_| Error::BlockingError)?
+            .map_err(|_| Error::IOError)?;
+
+            Ok(files
+                .iter()
+                .filter_map(|path| path.file_name().and_then(|name| name.to_str()))
+                .filter(|name| !name.ends_with(".tmp"))
+                .map(str::to_string)
+                .collect())
+        }
+        StorageConfig::S3(config) => Ok(configured_s3_bucket(config)?
+            .list(String::new(), None)
+            .await
+            .map_err(s3_error)?
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| object.key.trim_start_matches('/').to_string())
+            .collect()),
+    }
+}
+
+/// Copy a file which was just written to its tag's secondary storage in the background.
+/// Failures are only logged and counted, the primary is all that reads rely on.
+pub fn write(tag: &str, id: &str, content_type: &str, buf: Vec<u8>) {
+    let storage = match secondary(tag) {
+        Some(storage) => storage,
+        None => return,
+    };
+
+    let (tag, id, content_type) = (tag.to_string(), id.to_string(), content_type.to_string());
+    tokio::spawn(async move {
+        if let Err(err) = put(storage, &tag, &id, &content_type, buf).await {
+            SECONDARY_WRITE_FAILURES.inc();
+            error!(
+                "Failed to copy {} in {} to secondary storage. {:?}",
+                id, tag, err
+            );
+        }
+    });
+}
+
+/// Number of files copied and failed for a tag.
+pub struct SyncResult {
+    pub copied: usize,
+    pub failed: usize,
+}
+
+/// Copy anything stored for a tag which is missing from its secondary storage.
+///
+/// The database records what the primary holds, which saves listing it.
+pub async fn sync(tag: &str) -> Result<SyncResult, Error> {
+    let storage = secondary(tag).ok_or(Error::NotFound)?;
+    let existing = list(storage).await?;
+
+    // Copies share their original's object, and redirected files have none.
+    let mut cursor = get_collection("attachments")
+        .find(
+            doc! {
+                "tag": tag,
+                "source_id": { "$exists": false },
+                "original_url": { "$exists": false },
+                "deleted": { "$ne": true }
+            },
+            None,
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    let mut result = SyncResult {
+        copied: 0,
+        failed: 0,
+    };
+
+    while let Some(file) = cursor.next().await {
+        let file: File = file.map_err(|_| Error::DatabaseError)?;
+        let objects = std::iter::once((file.storage_id(), file.content_type.as_str())).chain(
+            file.variants
+                .iter()
+                .flatten()
+                .map(|variant| (variant.id.as_str(), variant.content_type.as_str())),
+        );
+
+        for (id, content_type) in objects {
+            if existing.contains(id) {
+                continue;
+            }
+
+            let copied = match crate::storage::read(tag, id).await {
+                Ok(buf) => put(storage, tag, id, content_type, buf).await,
+                Err(err) => Err(err),
+            };
+
+            match copied {
+                Ok(()) => result.copied += 1,
+                Err(err) => {
+                    warn!("Failed to copy {} to secondary storage. {:?}", id, err);
+                    result.failed += 1;
+                }
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypts_local_copies() {
+        Config::init_for_tests();
+        let path = std::env::temp_dir()
+            .join(format!("autumn-mirror-{}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let storage = StorageConfig::Local { path: path.clone() };
+
+        actix_web::rt::System::new().block_on(async {
+            put(
+                &storage,
+                "encrypted",
+                "copy",
+                "text/plain",
+                b"hello".to_vec(),
+            )
+            .await
+            .unwrap();
+        });
+
+        let buf = std::fs::read(get_sharded_path(&path, "copy")).unwrap();
+        assert!(encryption::is_encrypted(&buf));
+        assert_eq!(encryption::open("encrypted", buf).unwrap(), b"hello");
+        std::fs::remove_dir_all(path).ok();
+    }
+}
@@ -0,0 +1,134 @@
+use crate::config::Config;
+use crate::db::get_collection;
+use crate::util::result::Error;
+use crate::util::variables::{get_local_path, LOCAL_STORAGE_PATH};
+
+use futures::StreamExt;
+use mongodb::bson::doc;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Every file below `dir`, descending into shard directories.
+pub fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether any document refers to a file with this name, either directly or as a thumbnail.
+async fn is_referenced(name: &str) -> Result<bool, Error> {
+    get_collection("attachments")
+        .find_one(
+            doc! {
+                "$or": [
+                    { "_id": name },
+                    { "source_id": name },
+                    { "object_id": name },
+                    { "variants.id": name }
+                ]
+            },
+            None,
+        )
+        .await
+        .map(|file| file.is_some())
+        .map_err(|_| Error::DatabaseError)
+}
+
+/// Remove files in local storage that no document refers to, and report
+/// documents whose files are missing. Files modified within `min_age`
+/// are left alone, since they may belong to uploads still in progress.
+pub async fn clean(dry_run: bool, min_age: Duration) -> Result<(), Error> {
+    let mut roots = vec![LOCAL_STORAGE_PATH.as_str()];
+    roots.extend(
+        Config::global()
+            .tags
+            .values()
+            .filter_map(|tag| tag.local_path_override.as_deref()),
+    );
+
+    let mut files = vec![];
+    for root in roots {
+        walk(Path::new(root), &mut files).map_err(|_| Error::IOError)?;
+    }
+
+    let cutoff = SystemTime::now() - min_age;
+    let mut orphans = 0;
+    let mut reclaimed = 0;
+    for path in files {
+        let metadata = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            // Deleted since we listed it.
+            Err(_) => continue,
+        };
+
+        if metadata.modified().map_err(|_| Error::IOError)? > cutoff {
+            continue;
+        }
+
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+
+        // Left behind by writes that never finished.
+        if !name.ends_with(".tmp") && is_referenced(name).await? {
+            continue;
+        }
+
+        info!(
+            "Orphaned file {} ({} bytes).",
+            path.display(),
+            metadata.len()
+        );
+        if !dry_run {
+            if let Err(err) = std::fs::remove_file(&path) {
+                warn!("Failed to remove {}. {}", path.display(), err);
+                continue;
+            }
+        }
+
+        orphans += 1;
+        reclaimed += metadata.len();
+    }
+
+    let mut missing = 0;
+    let mut cursor = get_collection("attachments")
+        .find(
+            // Redirected files were never stored.
+            doc! { "deleted": { "$ne": true }, "original_url": { "$exists": false } },
+            None,
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    while let Some(file) = cursor.next().await {
+        let file = file.map_err(|_| Error::DatabaseError)?;
+        if !Path::new(&get_local_path(&file.tag, file.storage_id())).exists() {
+            warn!("File {} in {} is missing from disk.", file.id, file.tag);
+            missing += 1;
+        }
+    }
+
+    info!(
+        "Found {} orphaned files, {} {} bytes. {} files are missing from disk.",
+        orphans,
+        if dry_run {
+            "could reclaim"
+        } else {
+            "reclaimed"
+        },
+        reclaimed,
+        missing
+    );
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use image::error::{DecodingError, ImageFormatHint};
+use image::{DynamicImage, ImageError, RgbaImage};
+use pdfium_render::prelude::{PdfRenderConfig, Pdfium};
+use tracing::warn;
+
+lazy_static! {
+    /// Pdfium is loaded from the system's libraries, previews are unavailable without it.
+    static ref PDFIUM: Option<Pdfium> = match Pdfium::bind_to_system_library() {
+        Ok(bindings) => Some(Pdfium::new(bindings)),
+        Err(err) => {
+            warn!("Failed to load Pdfium, PDFs won't be previewed. {:?}", err);
+            None
+        }
+    };
+}
+
+/// Size of the first page in points, and the number of pages.
+pub fn probe(buf: &[u8]) -> Option<(isize, isize, u32)> {
+    let document = PDFIUM.as_ref()?.load_pdf_from_byte_slice(buf, None).ok()?;
+    let pages = document.pages();
+    let page = pages.get(0).ok()?;
+
+    Some((
+        page.width().value.round() as isize,
+        page.height().value.round() as isize,
+        pages.len() as u32,
+    ))
+}
+
+/// Render the first page to fit within `width` x `height`, keeping its aspect ratio.
+pub fn render_first_page(buf: &[u8], width: u32, height: u32) -> Result<DynamicImage, ImageError> {
+    let error = |err: String| {
+        ImageError::Decoding(DecodingError::new(ImageFormatHint::Name("pdf".into()), err))
+    };
+
+    let pdfium = PDFIUM
+        .as_ref()
+        .ok_or_else(|| error("Pdfium is not available".to_string()))?;
+
+    let document = pdfium
+        .load_pdf_from_byte_slice(buf, None)
+        .map_err(|err| error(format!("{:?}", err)))?;
+
+    let page = document
+        .pages()
+        .get(0)
+        .map_err(|err| error(format!("{:?}", err)))?;
+
+    let bitmap = page
+        .render_with_config(
+            &PdfRenderConfig::new()
+                .set_target_width(width as i32)
+                .set_maximum_height(height as i32),
+        )
+        .map_err(|err| error(format!("{:?}", err)))?;
+
+    RgbaImage::from_raw(
+        bitmap.width() as u32,
+        bitmap.height() as u32,
+        bitmap.as_rgba_bytes(),
+    )
+    .map(DynamicImage::ImageRgba8)
+    .ok_or_else(|| error("Rendered page has the wrong size".to_string()))
+}
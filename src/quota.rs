@@ -0,0 +1,197 @@
+use crate::config::{Config, Tag};
+use crate::db::{get_quota_collection, usage_by_tag, File};
+use crate::util::result::Error;
+
+use futures::TryStreamExt;
+use mongodb::bson::doc;
+use mongodb::options::UpdateOptions;
+use std::collections::HashMap;
+use tracing::warn;
+
+/// Bytes used by each tag's live files, according to the counters.
+pub async fn used() -> Result<HashMap<String, i64>, Error> {
+    get_quota_collection()
+        .find(None, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?
+        .map_ok(|usage| (usage.tag, usage.used))
+        .try_collect()
+        .await
+        .map_err(|_| Error::DatabaseError)
+}
+
+/// Refuse files which would take a tag over its storage quota.
+///
+/// Concurrent uploads are checked against the same usage, so a tag can briefly go over.
+pub async fn check(tag_id: &str, tag: &Tag, size: u64) -> Result<(), Error> {
+    let quota = match tag.storage_quota_bytes {
+        Some(quota) => quota,
+        None => return Ok(()),
+    };
+
+    let used = get_quota_collection()
+        .find_one(doc! { "_id": tag_id }, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?
+        .map(|usage| usage.used.max(0) as u64)
+        .unwrap_or_default();
+
+    if used.saturating_add(size) > quota {
+        return Err(Error::InsufficientStorage { quota, used });
+    }
+
+    Ok(())
+}
+
+async fn adjust(tag: &str, bytes: i64) {
+    let result = get_quota_collection()
+        .update_one(
+            doc! { "_id": tag },
+            doc! { "$inc": { "used": bytes } },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await;
+
+    // Drift is corrected by the next reconciliation.
+    if let Err(err) = result {
+        warn!("Failed to update the storage used by {}. {}", tag, err);
+    }
+}
+
+/// Whether a file takes up storage of its own.
+fn stored(file: &File) -> bool {
+    // Copies share their original's object and redirected files aren't kept at all.
+    file.source_id.is_none() && file.original_url.is_none()
+}
+
+/// Count a new file towards its tag's usage.
+pub async fn added(file: &File) {
+    if stored(file) {
+        adjust(&file.tag, file.size as i64).await;
+    }
+}
+
+/// Stop counting a file which was deleted or moved out of its tag.
+///
+/// Its object is still counted while any other live file shares it, whichever of them
+/// was counted when it was stored. Files sharing an object which are removed together
+/// must only be passed here once between them.
+pub async fn removed(file: &File) {
+    if file.original_url.is_some() {
+        return;
+    }
+
+    match file.live_references().await {
+        Ok(0) => adjust(&file.tag, -(file.size as i64)).await,
+        Ok(_) => {}
+        // Drift is corrected by the next reconciliation.
+        Err(err) => warn!("Failed to check whether {} is shared. {:?}", file.id, err),
+    }
+}
+
+/// Recount every tag's usage from its files, correcting any drift in the counters.
+pub async fn reconcile() -> Result<(), Error> {
+    let mut counted: HashMap<String, i64> = usage_by_tag(false)
+        .await?
+        .into_iter()
+        .map(|usage| (usage.tag, usage.total_size_bytes))
+        .collect();
+
+    // Tags without files any more are reset too.
+    for tag in used()
+        .await?
+        .into_keys()
+        .chain(Config::global().tags.keys().cloned())
+    {
+        counted.entry(tag).or_insert(0);
+    }
+
+    for (tag, used) in counted {
+        get_quota_collection()
+            .update_one(
+                doc! { "_id": &tag },
+                doc! { "$set": { "used": used } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::get_collection;
+
+    async fn counted(tag: &str) -> i64 {
+        used().await.unwrap().get(tag).copied().unwrap_or_default()
+    }
+
+    async fn delete(file: &File) {
+        get_collection("attachments")
+            .update_one(
+                doc! { "_id": &file.id },
+                doc! { "$set": { "deleted": true } },
+                None,
+            )
+            .await
+            .unwrap();
+
+        removed(file).await;
+    }
+
+    #[test]
+    #[ignore = "needs MongoDB at AUTUMN_MONGO_URI"]
+    fn shared_objects_stay_counted_until_the_last_file_goes() {
+        actix_web::rt::System::new().block_on(async {
+            Config::init_for_tests();
+            crate::db::connect_for_tests().await;
+
+            let tag = format!("quota-{}", std::process::id());
+            let file = |id: &str, source_id: Option<&str>| -> File {
+                serde_json::from_value(serde_json::json!({
+                    "_id": format!("{}-{}", tag, id),
+                    "tag": &tag,
+                    "filename": "file",
+                    "metadata": { "type": "File" },
+                    "content_type": "application/octet-stream",
+                    "size": 100,
+                    "source_id": source_id.map(|id| format!("{}-{}", tag, id)),
+                }))
+                .unwrap()
+            };
+
+            let (original, copy) = (file("original", None), file("copy", Some("original")));
+            for file in [&original, &copy] {
+                get_collection("attachments")
+                    .insert_one(file, None)
+                    .await
+                    .unwrap();
+                added(file).await;
+            }
+
+            assert_eq!(counted(&tag).await, 100);
+
+            // The copy still holds onto the object.
+            delete(&original).await;
+            assert_eq!(counted(&tag).await, 100);
+            let usage = usage_by_tag(false).await.unwrap();
+            let usage = usage.iter().find(|usage| usage.tag == tag).unwrap();
+            assert_eq!(usage.total_size_bytes, 100);
+
+            delete(&copy).await;
+            assert_eq!(counted(&tag).await, 0);
+
+            get_collection("attachments")
+                .delete_many(doc! { "tag": &tag }, None)
+                .await
+                .unwrap();
+            get_quota_collection()
+                .delete_one(doc! { "_id": &tag }, None)
+                .await
+                .unwrap();
+        });
+    }
+}
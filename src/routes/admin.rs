@@ -0,0 +1,151 @@
+use crate::config::{get_tag, Config};
+use crate::db::{get_collection, usage_by_tag};
+use crate::util::auth::MasterKey;
+use crate::util::metadata::{detect_content_type, probe};
+use crate::util::result::Error;
+use crate::util::upload_token::{self, UploadClaims};
+
+use actix_web::web::{Json, Query};
+use actix_web::{HttpRequest, HttpResponse};
+use mongodb::bson::{doc, to_bson, DateTime};
+use serde::Deserialize;
+use serde_json::json;
+
+/// Longest an upload token can be valid for.
+const MAX_UPLOAD_TOKEN_TTL: u64 = 24 * 60 * 60;
+
+#[derive(Deserialize, Debug)]
+pub struct UploadTokenRequest {
+    tag: String,
+    max_size_bytes: Option<usize>,
+    ttl_seconds: u64,
+    content_type_prefix: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StatsOptions {
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+/// Number of files and bytes stored in each tag.
+pub async fn stats(_: MasterKey, options: Query<StatsOptions>) -> Result<HttpResponse, Error> {
+    let by_tag = usage_by_tag(options.include_deleted).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "total_files": by_tag.iter().map(|usage| usage.file_count).sum::<i64>(),
+        "total_size_bytes": by_tag.iter().map(|usage| usage.total_size_bytes).sum::<i64>(),
+        "by_tag": by_tag,
+    })))
+}
+
+/// Storage used by each tag against its quota, if it has one.
+pub async fn quotas(_: MasterKey) -> Result<HttpResponse, Error> {
+    let used = crate::quota::used().await?;
+    let quotas: Vec<_> = Config::global()
+        .tags
+        .iter()
+        .map(|(id, tag)| {
+            json!({
+                "tag": id,
+                "used_bytes": used.get(id).copied().unwrap_or_default(),
+                "quota_bytes": tag.storage_quota_bytes,
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({ "tags": quotas })))
+}
+
+/// Issue a token allowing a single upload to a tag, within the given limits.
+pub async fn upload_token(
+    _: MasterKey,
+    body: Json<UploadTokenRequest>,
+) -> Result<HttpResponse, Error> {
+    let body = body.into_inner();
+    match Config::global().tags.get(&body.tag) {
+        Some(tag) if tag.enabled => {}
+        _ => return Err(Error::UnknownTag),
+    }
+
+    if !(1..=MAX_UPLOAD_TOKEN_TTL).contains(&body.ttl_seconds) {
+        return Err(Error::BadRequest(format!(
+            "ttl_seconds must be between 1 and {}",
+            MAX_UPLOAD_TOKEN_TTL
+        )));
+    }
+
+    let claims = UploadClaims::new(
+        body.tag,
+        body.max_size_bytes,
+        body.content_type_prefix,
+        body.ttl_seconds,
+    );
+
+    // MasterKey already required one to be set.
+    let secret = Config::global().master_key.as_deref().unwrap_or_default();
+    Ok(HttpResponse::Ok().json(json!({
+        "token": upload_token::generate(&claims, secret),
+        "expires": claims.expires,
+    })))
+}
+
+/// Copy files missing from each tag's secondary storage.
+pub async fn sync_to_secondary(_: MasterKey) -> Result<HttpResponse, Error> {
+    let mut tags = serde_json::Map::new();
+    for id in Config::global().tags.keys() {
+        if !crate::mirror::enabled(id) {
+            continue;
+        }
+
+        let result = crate::mirror::sync(id).await?;
+        tags.insert(
+            id.clone(),
+            json!({ "copied": result.copied, "failed": result.failed }),
+        );
+    }
+
+    Ok(HttpResponse::Ok().json(json!({ "tags": tags })))
+}
+
+/// Detect a stored file's type and metadata again, fixing documents written by older versions.
+pub async fn reextract(req: HttpRequest, _: MasterKey) -> Result<HttpResponse, Error> {
+    let (tag_id, _) = get_tag(&req)?;
+    let id = req.match_info().query("filename");
+
+    let mut file = get_collection("attachments")
+        .find_one(doc! { "_id": id, "tag": &tag_id }, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?
+        .ok_or(Error::NotFound)?;
+
+    if file.original_url.is_some() {
+        return Err(Error::BadRequest(
+            "redirected files aren't stored".to_string(),
+        ));
+    }
+
+    let buf = crate::storage::read(&file.tag, file.storage_id()).await?;
+    file.content_type = detect_content_type(&buf, &file.filename);
+    file.metadata = probe(&buf, &file.content_type).await;
+    file.updated_at = Some(DateTime::now());
+
+    let metadata = to_bson(&file.metadata).map_err(|_| Error::LabelMe)?;
+    get_collection("attachments")
+        .update_one(
+            doc! { "_id": &file.id },
+            doc! {
+                "$set": {
+                    "content_type": &file.content_type,
+                    "metadata": metadata,
+                    "updated_at": file.updated_at
+                }
+            },
+            None,
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    crate::cache::invalidate(&file.id);
+    Ok(HttpResponse::Ok().json(file))
+}
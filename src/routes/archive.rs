@@ -0,0 +1,182 @@
+use super::serve::{attachment, stream_file};
+use crate::config::get_tag;
+use crate::db::{get_collection, File};
+use crate::util::auth::Authorized;
+use crate::util::result::Error;
+use crate::util::signing;
+use crate::util::zip::ZipWriter;
+
+use actix_web::web::{Bytes, Json, Query};
+use actix_web::{HttpRequest, HttpResponse};
+use futures::channel::mpsc::{channel, Sender};
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use mongodb::bson::doc;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use tracing::warn;
+
+const MAX_ARCHIVE_FILES: usize = 1000;
+
+#[derive(Deserialize, Debug)]
+pub struct ArchiveOptions {
+    ids: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ArchiveRequest {
+    ids: Vec<String>,
+}
+
+/// File extension for a content type, used to name archive entries.
+fn extension(content_type: &str) -> &str {
+    match content_type {
+        "image/jpeg" => "jpg",
+        "image/svg+xml" => "svg",
+        "image/heic" => "heic",
+        "text/plain" => "txt",
+        "audio/mpeg" => "mp3",
+        "video/quicktime" => "mov",
+        "application/vnd.android.package-archive" => "apk",
+        _ => match content_type.split_once('/') {
+            Some((_, subtype))
+                if !subtype.is_empty() && subtype.chars().all(|c| c.is_ascii_alphanumeric()) =>
+            {
+                subtype
+            }
+            _ => "bin",
+        },
+    }
+}
+
+/// Send every file's contents followed by the central directory, skipping files that can't be read.
+/// Stops early if the client goes away.
+async fn write_archive(
+    files: Vec<File>,
+    mut sender: Sender<Result<Bytes, std::io::Error>>,
+) -> Result<(), futures::channel::mpsc::SendError> {
+    let mut zip = ZipWriter::new();
+    for file in files {
+        let size = file.size as u64;
+        let mut stream = match stream_file(&file, 0, size).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!(
+                    "Skipping {} in archive, it couldn't be read. {:?}",
+                    file.id, err
+                );
+                continue;
+            }
+        };
+
+        let name = format!("{}.{}", file.id, extension(&file.content_type));
+        sender.send(Ok(zip.start_entry(&name, size))).await?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut written = 0;
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    hasher.update(&chunk);
+                    written += chunk.len() as u64;
+                    sender.send(Ok(chunk)).await?;
+                }
+                Err(err) => {
+                    // Part of the entry was already sent, so the archive can't be finished.
+                    warn!("Failed to stream {} into archive. {}", file.id, err);
+                    return sender.send(Err(err)).await;
+                }
+            }
+        }
+
+        crate::stats::record_download(&file.id);
+        sender
+            .send(Ok(zip.finish_entry(hasher.finalize(), written)))
+            .await?;
+    }
+
+    sender.send(Ok(zip.finish())).await
+}
+
+async fn archive(req: HttpRequest, ids: Vec<String>) -> Result<HttpResponse, Error> {
+    let (tag_id, tag) = get_tag(&req)?;
+
+    // Keep the requested order, without repeating entries.
+    let mut seen = HashSet::new();
+    let ids: Vec<String> = ids
+        .into_iter()
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty() && seen.insert(id.clone()))
+        .collect();
+
+    if ids.is_empty() || ids.len() > MAX_ARCHIVE_FILES {
+        return Err(Error::BadRequest(format!(
+            "ids must contain between 1 and {} entries",
+            MAX_ARCHIVE_FILES
+        )));
+    }
+
+    // Signed tags sign the comma-separated list of IDs instead of a single ID.
+    signing::check_request(&req, tag, &ids.join(","))?;
+
+    let mut found: HashMap<String, File> = get_collection("attachments")
+        .find(doc! { "_id": { "$in": &ids }, "tag": &tag_id }, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?
+        .map_ok(|file: File| (file.id.clone(), file))
+        .try_collect()
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    let files: Vec<File> = ids
+        .iter()
+        .filter_map(|id| match found.remove(id) {
+            Some(file) if file.deleted != Some(true) => Some(file),
+            _ => {
+                warn!("Skipping {} in archive, it was not found.", id);
+                None
+            }
+        })
+        .collect();
+
+    // A small buffer keeps memory bounded while reads stay ahead of the client.
+    let (sender, receiver) = channel(8);
+    actix_web::rt::spawn(async move {
+        write_archive(files, sender).await.ok();
+    });
+
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            "Content-Disposition",
+            attachment(&format!("{}.zip", tag_id)),
+        ))
+        .insert_header(("Content-Encoding", "identity"))
+        .insert_header(("Cache-Control", "no-store"))
+        .content_type("application/zip")
+        .streaming(receiver))
+}
+
+/// Download many files from a tag as one ZIP archive, named by ID, streamed as it's built.
+pub async fn get(
+    req: HttpRequest,
+    _: Authorized,
+    options: Query<ArchiveOptions>,
+) -> Result<HttpResponse, Error> {
+    let ids = options
+        .into_inner()
+        .ids
+        .ok_or_else(|| Error::BadRequest("ids is required".to_string()))?
+        .split(',')
+        .map(str::to_string)
+        .collect();
+
+    archive(req, ids).await
+}
+
+/// Same as `get`, for lists of IDs too long for a URL.
+pub async fn post(
+    req: HttpRequest,
+    _: Authorized,
+    body: Json<ArchiveRequest>,
+) -> Result<HttpResponse, Error> {
+    archive(req, body.into_inner().ids).await
+}
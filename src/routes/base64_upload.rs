@@ -0,0 +1,62 @@
+use super::upload::{store, UploadOptions};
+use crate::config::{get_tag, Config};
+use crate::util::auth::Authorized;
+use crate::util::result::Error;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::StreamExt;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct Base64Upload {
+    filename: String,
+    content_type: Option<String>,
+    data: String,
+}
+
+/// Upload a file sent as base64 in a JSON body, for clients that can't send multipart forms.
+pub async fn post(
+    req: HttpRequest,
+    _: Authorized,
+    options: web::Query<UploadOptions>,
+    mut payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let (tag_id, tag) = get_tag(&req)?;
+    let max_size = tag.max_file_size();
+    let max_body_size = Config::global().max_base64_size.unwrap_or(max_size);
+
+    // Read by hand, the JSON extractor's limit is far smaller than most uploads.
+    let mut body = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|_| Error::FailedToReceive)?;
+        if body.len() + chunk.len() > max_body_size {
+            return Err(Error::FileTooLarge {
+                max_size: max_body_size,
+            });
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    let upload: Base64Upload = serde_json::from_slice(&body)
+        .map_err(|_| Error::BadRequest("body must be a JSON upload".to_string()))?;
+    drop(body);
+
+    if let Some(declared) = &upload.content_type {
+        if declared != "application/octet-stream" && !tag.allows_mime_type(declared) {
+            return Err(Error::UnsupportedMediaType {
+                allowed: tag.allowed_mime_types.clone(),
+            });
+        }
+    }
+
+    let buf = base64::decode(upload.data.trim())
+        .map_err(|_| Error::BadRequest("data must be base64".to_string()))?;
+
+    if buf.len() > max_size {
+        return Err(Error::FileTooLarge { max_size });
+    }
+
+    let file = store(&tag_id, tag, upload.filename, buf, options.expires_in).await?;
+    Ok(HttpResponse::Ok().json(file))
+}
@@ -0,0 +1,137 @@
+use super::serve::{parse_format, read_original, try_resize_many, Transform};
+use crate::config::{get_tag, Config};
+use crate::db::{find_file, Metadata};
+use crate::util::auth::Authorized;
+use crate::util::metrics;
+use crate::util::result::Error;
+use crate::util::signing;
+
+use actix_web::{web::Query, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use std::cmp;
+
+#[derive(Deserialize, Debug)]
+pub struct BatchOptions {
+    sizes: Option<String>,
+    format: Option<String>,
+}
+
+/// Parse a list of sizes such as `100x100,400x300`.
+fn parse_sizes(sizes: &str) -> Result<Vec<(isize, isize)>, Error> {
+    let invalid =
+        || Error::BadRequest("sizes must be a list of positive sizes such as 100x100".to_string());
+
+    sizes
+        .split(',')
+        .map(|size| {
+            let (width, height) = size.trim().split_once('x').ok_or_else(invalid)?;
+            match (width.parse::<isize>(), height.parse::<isize>()) {
+                (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+                _ => Err(invalid()),
+            }
+        })
+        .collect()
+}
+
+/// Resize an image to several sizes at once, decoding it only once.
+/// Each size is treated like a request with `width` and `height`, and
+/// the results are returned base64-encoded.
+pub async fn get(
+    req: HttpRequest,
+    _: Authorized,
+    options: Query<BatchOptions>,
+) -> Result<HttpResponse, Error> {
+    let tag = get_tag(&req)?;
+
+    let id = req.match_info().query("filename");
+    signing::check_request(&req, tag.1, id)?;
+
+    let file = find_file(id, tag).await?;
+    if let Some(true) = file.deleted {
+        return Err(Error::NotFound);
+    }
+
+    let (width, height, animated) = match file.metadata {
+        Metadata::Image {
+            width,
+            height,
+            animated,
+            ..
+        } => (width, height, animated),
+        _ => return Err(Error::BadRequest("file is not an image".to_string())),
+    };
+
+    let config = Config::global();
+    let requested = parse_sizes(
+        options
+            .sizes
+            .as_deref()
+            .ok_or_else(|| Error::BadRequest("sizes is required".to_string()))?,
+    )?;
+
+    if requested.len() > config.max_batch_sizes {
+        return Err(Error::BadRequest(format!(
+            "at most {} sizes can be requested",
+            config.max_batch_sizes
+        )));
+    }
+
+    // Images are never scaled up, and oversized requests are scaled down to fit.
+    let max_dimension = config.max_resize_dimension as isize;
+    let sizes: Vec<(u32, u32)> = requested
+        .into_iter()
+        .map(|(w, h)| {
+            let (w, h) = (cmp::min(width, w), cmp::min(height, h));
+            let longest = cmp::max(w, h);
+            if longest > max_dimension {
+                (
+                    cmp::max(1, w * max_dimension / longest) as u32,
+                    cmp::max(1, h * max_dimension / longest) as u32,
+                )
+            } else {
+                (w as u32, h as u32)
+            }
+        })
+        .collect();
+
+    let transform = Transform {
+        animated,
+        svg: file.content_type == "image/svg+xml",
+        format: parse_format(options.format.as_deref())?,
+        ..Default::default()
+    };
+
+    let contents = read_original(&file).await?;
+    let timer = metrics::RESIZE_DURATION
+        .with_label_values(&[metrics::tag_label(&file.tag)])
+        .start_timer();
+
+    let cloned = sizes.clone();
+    let resized = crate::util::pool::run(move || try_resize_many(contents, &cloned, transform))
+        .await?
+        .map_err(|err| {
+            tracing::error!("Failed to resize {}. {}", file.id, err);
+            Error::IOError
+        })?;
+
+    timer.observe_duration();
+    crate::stats::record_download(&file.id);
+
+    let results: Vec<_> = sizes
+        .into_iter()
+        .zip(resized)
+        .map(|((width, height), (bytes, content_type))| {
+            json!({
+                "width": width,
+                "height": height,
+                "content_type": content_type,
+                "data": base64::encode(bytes),
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(results))
+}
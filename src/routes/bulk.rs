@@ -0,0 +1,92 @@
+use crate::config::{get_tag, Config};
+use crate::db::get_collection;
+use crate::util::auth::Authorized;
+use crate::util::result::Error;
+
+use actix_web::{web::Json, HttpRequest, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, DateTime};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+
+const MAX_BATCH_SIZE: usize = 1000;
+
+#[derive(Deserialize, Debug)]
+pub struct BulkDelete {
+    ids: Vec<String>,
+}
+
+/// Mark many files as deleted at once, storage is cleaned up in the background
+/// unless `deleted_retention` is set.
+pub async fn delete(
+    req: HttpRequest,
+    _: Authorized,
+    data: Json<BulkDelete>,
+) -> Result<HttpResponse, Error> {
+    let (tag_id, tag) = get_tag(&req)?;
+
+    // Without a token anyone could delete any file.
+    if tag.auth_token.is_none() {
+        return Err(Error::Forbidden);
+    }
+
+    let ids: HashSet<String> = data.into_inner().ids.into_iter().collect();
+    if ids.is_empty() || ids.len() > MAX_BATCH_SIZE {
+        return Err(Error::BadRequest(format!(
+            "ids must contain between 1 and {} entries",
+            MAX_BATCH_SIZE
+        )));
+    }
+
+    let ids: Vec<String> = ids.into_iter().collect();
+    let col = get_collection("attachments");
+    let files: Vec<crate::db::File> = col
+        .find(doc! { "_id": { "$in": &ids }, "tag": &tag_id }, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?
+        .try_collect()
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    let not_found = ids.len() - files.len();
+    let (already_deleted, files): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .partition(|file| file.deleted == Some(true));
+
+    if !files.is_empty() {
+        let ids: Vec<&str> = files.iter().map(|file| file.id.as_str()).collect();
+        col.update_many(
+            doc! { "_id": { "$in": ids }, "tag": &tag_id, "deleted": { "$ne": true } },
+            doc! { "$set": { "deleted": true, "deleted_at": DateTime::now() } },
+            None,
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+    }
+
+    let deleted = files.len();
+    let remove = Config::global().deleted_retention == 0;
+    tokio::spawn(async move {
+        // Files sharing an object were all marked deleted at once, so it's only uncounted once.
+        let mut uncounted = HashSet::new();
+        for file in files {
+            if uncounted.insert(file.storage_id().to_string()) {
+                crate::quota::removed(&file).await;
+            }
+
+            crate::webhook::notify(crate::webhook::Event::Delete, &file);
+
+            // Otherwise storage is kept until the file is purged.
+            if remove {
+                file.delete_in_storage().await.ok();
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok().json(json!({
+        "deleted": deleted,
+        "already_deleted": already_deleted.len(),
+        "not_found": not_found,
+    })))
+}
@@ -0,0 +1,40 @@
+use super::upload::new_id;
+use crate::config::get_tag;
+use crate::db::{find_file, get_collection, File};
+use crate::util::auth::Authorized;
+use crate::util::result::Error;
+
+use actix_web::{HttpRequest, HttpResponse};
+use mongodb::bson::DateTime;
+
+/// Copy a file under a new ID without duplicating its contents,
+/// the copy shares the original's stored object until both are deleted.
+pub async fn post(req: HttpRequest, _: Authorized) -> Result<HttpResponse, Error> {
+    let (tag_id, tag) = get_tag(&req)?;
+
+    let id = req.match_info().query("filename");
+    let file = find_file(id, (tag_id, tag)).await?;
+    if let Some(true) = file.deleted {
+        return Err(Error::NotFound);
+    }
+
+    let now = DateTime::now();
+    let copy = File {
+        id: new_id(tag),
+        source_id: Some(file.storage_id().to_string()),
+        object_id: None,
+        created_at: Some(now),
+        updated_at: Some(now),
+        download_count: None,
+        reported: None,
+        ..file
+    };
+
+    get_collection("attachments")
+        .insert_one(&copy, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    crate::webhook::notify(crate::webhook::Event::Upload, &copy);
+    Ok(HttpResponse::Ok().json(copy))
+}
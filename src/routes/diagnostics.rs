@@ -0,0 +1,29 @@
+use crate::config::Config;
+use crate::storage::s3_bucket_healthy;
+use crate::util::auth::MasterKey;
+use crate::util::result::Error;
+use crate::util::variables::{get_s3_buckets, USE_S3};
+
+use actix_web::HttpResponse;
+use serde_json::{json, Map, Value};
+
+/// Whether each tag's buckets are reachable, in order of preference.
+pub async fn buckets(_: MasterKey) -> Result<HttpResponse, Error> {
+    let mut tags = Map::new();
+    if *USE_S3 {
+        for id in Config::global().tags.keys() {
+            let mut statuses = vec![];
+            for bucket in get_s3_buckets(id)? {
+                statuses.push(json!({
+                    "name": bucket.name,
+                    "endpoint": bucket.region.endpoint(),
+                    "healthy": s3_bucket_healthy(&bucket).await,
+                }));
+            }
+
+            tags.insert(id.clone(), Value::Array(statuses));
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({ "tags": tags })))
+}
@@ -1,29 +1,46 @@
-use crate::config::get_tag;
+use crate::config::{get_tag, Config};
 use crate::db::find_file;
+use crate::util::auth::Authorized;
 use crate::util::result::Error;
+use crate::util::signing;
 
-use super::serve::fetch_file;
+use super::serve::{attachment, redirect_to_original, skip_compression, stream_file};
 
+use actix_web::body::{AnyBody, SizedStream};
 use actix_web::{HttpRequest, HttpResponse};
 
-pub async fn get(req: HttpRequest) -> Result<HttpResponse, Error> {
+pub async fn get(req: HttpRequest, _: Authorized) -> Result<HttpResponse, Error> {
     let tag = get_tag(&req)?;
 
     let id = req.match_info().query("filename");
+    signing::check_request(&req, tag.1, id)?;
+
     let file = find_file(id, tag.clone()).await?;
 
     if let Some(true) = file.deleted {
         return Err(Error::NotFound);
     }
 
-    let (contents, _) = fetch_file(id, &tag.0, file.metadata, None).await?;
+    if let Some(response) = redirect_to_original(&file) {
+        return Ok(response);
+    }
+
+    let size = file.size as u64;
+    let stream = stream_file(&file, 0, size).await?;
+    crate::stats::record_download(&file.id);
+
+    let mut response = HttpResponse::Ok();
+    if skip_compression(&file.content_type, size) {
+        response.insert_header(("Content-Encoding", "identity"));
+    }
 
-    Ok(HttpResponse::Ok()
+    Ok(response
+        .insert_header(("Content-Disposition", attachment(&file.filename)))
         .insert_header((
-            "Content-Disposition",
-            format!("attachment; filename=\"{}\"", file.filename),
+            "Cache-Control",
+            Config::global().cache_control(&file.tag, &file.content_type),
         ))
-        .insert_header(("Cache-Control", crate::CACHE_CONTROL))
+        .insert_header(("Vary", "Accept-Encoding"))
         .content_type(file.content_type)
-        .body(contents))
+        .body(AnyBody::from_message(SizedStream::new(size, stream))))
 }
@@ -0,0 +1,151 @@
+use super::upload::{register, store, UploadOptions};
+use crate::config::{get_tag, Config};
+use crate::util::auth::Authorized;
+use crate::util::ip::is_public;
+use crate::util::result::Error;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use reqwest::header::LOCATION;
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use serde::Deserialize;
+use std::cmp;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::lookup_host;
+
+/// Redirects followed before giving up on a remote file.
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Deserialize)]
+pub struct FetchRequest {
+    url: String,
+}
+
+/// Resolve the host of a URL, refusing anything that isn't on the public internet
+/// so the server can't be used to reach internal services.
+async fn resolve_public(url: &Url) -> Result<SocketAddr, Error> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(Error::BadRequest("url must use http or https".to_string()));
+    }
+
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::BadRequest("url must have a host".to_string()))?;
+
+    let port = url.port_or_known_default().unwrap_or(80);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => lookup_host((host, port))
+            .await
+            .map_err(|_| Error::BadRequest("url's host could not be resolved".to_string()))?
+            .collect(),
+    };
+
+    // A host could resolve to both, so every address has to be public.
+    match addrs.first() {
+        Some(addr) if addrs.iter().all(|addr| is_public(addr.ip())) => Ok(*addr),
+        _ => Err(Error::BadRequest(
+            "url must point to a public address".to_string(),
+        )),
+    }
+}
+
+/// Download a remote file, checking every redirect against the same rules.
+async fn download(mut url: Url, max_size: usize) -> Result<(Url, Vec<u8>), Error> {
+    let timeout = Duration::from_secs(Config::global().remote_fetch.timeout);
+    for _ in 0..=MAX_REDIRECTS {
+        let addr = resolve_public(&url).await?;
+
+        // Pin the checked address, otherwise the host could resolve differently for the request.
+        let client = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .timeout(timeout)
+            .resolve(url.host_str().unwrap_or_default(), addr)
+            .build()
+            .map_err(|_| Error::LabelMe)?;
+
+        let mut response = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|_| Error::FailedToReceive)?;
+
+        if response.status().is_redirection() {
+            url = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|location| url.join(location).ok())
+                .ok_or(Error::FailedToReceive)?;
+
+            continue;
+        }
+
+        if !response.status().is_success() {
+            return Err(Error::FailedToReceive);
+        }
+
+        if response.content_length().unwrap_or(0) > max_size as u64 {
+            return Err(Error::FileTooLarge { max_size });
+        }
+
+        let mut buf = vec![];
+        while let Some(chunk) = response.chunk().await.map_err(|_| Error::FailedToReceive)? {
+            if buf.len() + chunk.len() > max_size {
+                return Err(Error::FileTooLarge { max_size });
+            }
+
+            buf.extend_from_slice(&chunk);
+        }
+
+        return Ok((url, buf));
+    }
+
+    Err(Error::BadRequest(
+        "url redirected too many times".to_string(),
+    ))
+}
+
+/// Import a file from a remote URL, stored just as if it had been uploaded.
+/// Tags that redirect only record the URL, which files are then served from.
+pub async fn post(
+    req: HttpRequest,
+    _: Authorized,
+    options: web::Query<UploadOptions>,
+    body: web::Json<FetchRequest>,
+) -> Result<HttpResponse, Error> {
+    let (tag_id, tag) = get_tag(&req)?;
+    let url = Url::parse(&body.url).map_err(|_| Error::BadRequest("url is invalid".to_string()))?;
+
+    let max_size = match Config::global().remote_fetch.max_size {
+        Some(limit) => cmp::min(limit, tag.max_file_size()),
+        None => tag.max_file_size(),
+    };
+
+    let original_url = url.to_string();
+    let (url, buf) = download(url, max_size).await?;
+    let filename = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|segment| !segment.is_empty())
+        .unwrap_or("file")
+        .to_string();
+
+    let file = if tag.redirects() {
+        register(
+            &tag_id,
+            tag,
+            filename,
+            buf,
+            original_url,
+            options.expires_in,
+        )
+        .await?
+    } else {
+        store(&tag_id, tag, filename, buf, options.expires_in).await?
+    };
+
+    Ok(HttpResponse::Ok().json(file))
+}
@@ -0,0 +1,69 @@
+use crate::config::Config;
+use crate::storage::s3_bucket_healthy;
+use crate::util::variables::{get_s3_buckets, USE_S3};
+
+use actix_web::HttpResponse;
+use serde_json::json;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// How long each dependency gets to answer a readiness check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub async fn health() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+async fn s3_reachable() -> bool {
+    for (id, tag) in &Config::global().tags {
+        if !tag.enabled {
+            continue;
+        }
+
+        let buckets = match get_s3_buckets(id) {
+            Ok(buckets) => buckets,
+            Err(_) => return false,
+        };
+
+        // Uploads can still go to the others while any bucket is up.
+        let mut healthy = false;
+        for bucket in &buckets {
+            if s3_bucket_healthy(bucket).await {
+                healthy = true;
+                break;
+            }
+        }
+
+        if !healthy {
+            return false;
+        }
+    }
+
+    true
+}
+
+pub async fn ready() -> HttpResponse {
+    // Tell load balancers to stop sending requests while draining.
+    if crate::util::shutdown::is_shutting_down() {
+        return HttpResponse::ServiceUnavailable().json(json!({ "status": "shutting_down" }));
+    }
+
+    let mut failed = vec![];
+
+    if !matches!(timeout(CHECK_TIMEOUT, crate::db::ping()).await, Ok(Ok(()))) {
+        failed.push("mongodb");
+    }
+
+    if *USE_S3 && !s3_reachable().await {
+        failed.push("s3");
+    }
+
+    if failed.is_empty() {
+        HttpResponse::Ok().json(json!({ "status": "ok" }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(json!({
+            "status": "unavailable",
+            "failed": failed,
+        }))
+    }
+}
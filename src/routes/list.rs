@@ -0,0 +1,142 @@
+use crate::config::get_tag;
+use crate::db::get_collection;
+use crate::util::auth::Authorized;
+use crate::util::result::Error;
+
+use actix_web::{web::Query, HttpRequest, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Regex};
+use mongodb::options::FindOptions;
+use serde::Deserialize;
+use serde_json::json;
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Deserialize, Debug)]
+pub struct ListOptions {
+    limit: Option<i64>,
+    before: Option<String>,
+    after: Option<String>,
+    content_type: Option<String>,
+    include_deleted: Option<bool>,
+}
+
+fn escape_regex(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// List files in a tag, ordered by ID.
+///
+/// Paging backwards with `before` returns the page in ascending
+/// order and `next_cursor` continues further backwards.
+pub async fn get(
+    req: HttpRequest,
+    _: Authorized,
+    options: Query<ListOptions>,
+) -> Result<HttpResponse, Error> {
+    let (tag_id, tag) = get_tag(&req)?;
+
+    // Anyone could enumerate files on tags without a token.
+    if tag.auth_token.is_none() {
+        return Err(Error::Forbidden);
+    }
+
+    let options = options.into_inner();
+    let limit = match options.limit {
+        Some(limit) if !(1..=MAX_LIMIT).contains(&limit) => {
+            return Err(Error::BadRequest(format!(
+                "limit must be between 1 and {}",
+                MAX_LIMIT
+            )))
+        }
+        limit => limit.unwrap_or(DEFAULT_LIMIT),
+    };
+
+    let mut filter = doc! { "tag": tag_id };
+    if !options.include_deleted.unwrap_or(false) {
+        filter.insert("deleted", doc! { "$ne": true });
+    }
+
+    if let Some(content_type) = &options.content_type {
+        match content_type.strip_suffix("/*") {
+            Some(kind) => filter.insert(
+                "content_type",
+                Regex {
+                    pattern: format!("^{}/", escape_regex(kind)),
+                    options: String::new(),
+                },
+            ),
+            None => filter.insert("content_type", content_type),
+        };
+    }
+
+    let backwards = match (&options.before, &options.after) {
+        (Some(_), Some(_)) => {
+            return Err(Error::BadRequest(
+                "before and after cannot be used together".to_string(),
+            ))
+        }
+        (Some(before), None) => {
+            filter.insert("_id", doc! { "$lt": before });
+            true
+        }
+        (None, Some(after)) => {
+            filter.insert("_id", doc! { "$gt": after });
+            false
+        }
+        (None, None) => false,
+    };
+
+    let mut files: Vec<crate::db::File> = get_collection("attachments")
+        .find(
+            filter,
+            FindOptions::builder()
+                .sort(doc! { "_id": if backwards { -1 } else { 1 } })
+                .limit(limit)
+                .build(),
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?
+        .try_collect()
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    let next_cursor = if files.len() as i64 == limit {
+        files.last().map(|file| file.id.clone())
+    } else {
+        None
+    };
+
+    if backwards {
+        files.reverse();
+    }
+
+    let files: Vec<_> = files
+        .into_iter()
+        .map(|file| {
+            json!({
+                "id": file.id,
+                "content_type": file.content_type,
+                "size": file.size,
+                "frame_count": file.metadata.frame_count(),
+                "created_at": file.created_at.map(|date| date.timestamp_millis()),
+                "deleted": file.deleted.unwrap_or(false),
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "files": files,
+        "next_cursor": next_cursor,
+    })))
+}
@@ -0,0 +1,65 @@
+use crate::config::Config;
+use crate::util::auth::{bearer_token, constant_time_eq};
+use crate::util::result::Error;
+
+use actix_web::{HttpRequest, HttpResponse};
+use serde_json::json;
+
+/// Metrics are only served with the configured `metrics_auth_token`.
+fn authorize(req: &HttpRequest) -> Result<(), Error> {
+    let expected = match &Config::global().metrics_auth_token {
+        Some(token) => token,
+        None => return Err(Error::NotFound),
+    };
+
+    match bearer_token(req) {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => Err(Error::Unauthorized),
+    }
+}
+
+/// Prometheus metrics.
+pub async fn get(req: HttpRequest) -> Result<HttpResponse, Error> {
+    authorize(&req)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(crate::util::metrics::gather()))
+}
+
+/// Resize cache statistics.
+pub async fn cache(req: HttpRequest) -> Result<HttpResponse, Error> {
+    authorize(&req)?;
+    let stats = crate::cache::stats();
+    let lookups = stats.hits + stats.misses;
+    let hit_rate = if lookups == 0 {
+        0.0
+    } else {
+        stats.hits as f64 / lookups as f64
+    };
+
+    Ok(HttpResponse::Ok().json(json!({
+        "hits": stats.hits,
+        "misses": stats.misses,
+        "hit_rate": hit_rate,
+        "entries": stats.entries,
+        "bytes": stats.bytes,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn cache_stats_need_the_metrics_token() {
+        // The test config has no token, so metrics aren't served at all.
+        Config::init_for_tests();
+        actix_web::rt::System::new().block_on(async {
+            let req = TestRequest::default()
+                .insert_header(("Authorization", "Bearer guess"))
+                .to_http_request();
+            assert!(matches!(cache(req).await, Err(Error::NotFound)));
+        });
+    }
+}
@@ -1,4 +1,23 @@
+pub mod admin;
+pub mod archive;
+pub mod base64_upload;
+pub mod batch;
+pub mod bulk;
+pub mod copy;
+pub mod diagnostics;
 pub mod download;
+pub mod fetch;
+pub mod health;
 pub mod index;
+pub mod list;
+pub mod metrics;
+pub mod move_file;
+pub mod palette;
+pub mod purge;
 pub mod serve;
+pub mod similar;
+pub mod srcset;
+pub mod stats;
+pub mod tus;
+pub mod update;
 pub mod upload;
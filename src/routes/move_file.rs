@@ -0,0 +1,105 @@
+use crate::config::{get_tag, Config};
+use crate::db::{find_file, get_collection, File};
+use crate::storage::transfer;
+use crate::util::auth::MasterKey;
+use crate::util::result::Error;
+
+use actix_web::{web::Json, HttpRequest, HttpResponse};
+use mongodb::bson::{doc, DateTime};
+use serde::Deserialize;
+use tracing::warn;
+
+#[derive(Deserialize, Debug)]
+pub struct MoveFile {
+    target_tag: String,
+}
+
+/// IDs and content types of everything stored for a file.
+fn objects(file: &File) -> Vec<(&str, &str)> {
+    std::iter::once((file.storage_id(), file.content_type.as_str()))
+        .chain(
+            file.variants
+                .iter()
+                .flatten()
+                .map(|variant| (variant.id.as_str(), variant.content_type.as_str())),
+        )
+        .collect()
+}
+
+/// Move objects back after a failed move, it's too late to report errors from here.
+async fn restore(objects: &[(&str, &str)], from: &str, to: &str) {
+    for (id, content_type) in objects {
+        if let Err(err) = transfer(from, to, id, content_type).await {
+            warn!("Failed to move {} back to {}. {:?}", id, to, err);
+        }
+    }
+}
+
+/// Move a file to another tag, along with its stored object.
+pub async fn post(
+    req: HttpRequest,
+    _: MasterKey,
+    body: Json<MoveFile>,
+) -> Result<HttpResponse, Error> {
+    let (tag_id, tag) = get_tag(&req)?;
+    let target = body.into_inner().target_tag;
+
+    match Config::global().tags.get(&target) {
+        Some(target) if target.enabled => {}
+        _ => return Err(Error::UnknownTag),
+    }
+
+    let id = req.match_info().query("filename");
+    let file = find_file(id, (tag_id.clone(), tag)).await?;
+    if let Some(true) = file.deleted {
+        return Err(Error::NotFound);
+    }
+
+    if target == tag_id {
+        return Ok(HttpResponse::Ok().json(file));
+    }
+
+    // Copies would be left pointing at an object in the wrong tag.
+    if file.source_id.is_some() || file.references().await? > 0 {
+        return Err(Error::BadRequest(
+            "files sharing contents with a copy can't be moved".to_string(),
+        ));
+    }
+
+    let objects = objects(&file);
+    for (index, (id, content_type)) in objects.iter().enumerate() {
+        if let Err(err) = transfer(&tag_id, &target, id, content_type).await {
+            restore(&objects[..index], &target, &tag_id).await;
+            return Err(err);
+        }
+    }
+
+    let now = DateTime::now();
+    let updated = get_collection("attachments")
+        .update_one(
+            doc! { "_id": &file.id, "tag": &tag_id },
+            doc! { "$set": { "tag": &target, "updated_at": now } },
+            None,
+        )
+        .await;
+
+    match updated {
+        Ok(result) if result.matched_count == 1 => {}
+        _ => {
+            restore(&objects, &target, &tag_id).await;
+            return Err(Error::DatabaseError);
+        }
+    }
+
+    crate::cache::invalidate(&file.id);
+    crate::quota::removed(&file).await;
+
+    let file = File {
+        tag: target,
+        updated_at: Some(now),
+        ..file
+    };
+
+    crate::quota::added(&file).await;
+    Ok(HttpResponse::Ok().json(file))
+}
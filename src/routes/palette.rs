@@ -0,0 +1,103 @@
+use super::serve::{decode_image, fetch_file, render_svg};
+use crate::config::get_tag;
+use crate::db::{find_file, get_collection, Metadata, Palette, Swatch};
+use crate::util::auth::Authorized;
+use crate::util::image::dominant_colours;
+use crate::util::result::Error;
+use crate::util::signing;
+
+use actix_web::{web::Query, HttpRequest, HttpResponse};
+use mongodb::bson::{doc, to_bson};
+use serde::Deserialize;
+use std::cmp;
+use tracing::warn;
+
+const DEFAULT_COLORS: u32 = 5;
+const MAX_COLORS: u32 = 16;
+
+/// Largest side SVGs are rendered at, which is plenty to find their colours.
+const SVG_RENDER_SIZE: isize = 256;
+
+#[derive(Deserialize, Debug)]
+pub struct PaletteOptions {
+    colors: Option<u32>,
+}
+
+/// Dominant colours of an image, most common first.
+pub async fn get(
+    req: HttpRequest,
+    _: Authorized,
+    options: Query<PaletteOptions>,
+) -> Result<HttpResponse, Error> {
+    let tag = get_tag(&req)?;
+
+    let id = req.match_info().query("filename");
+    signing::check_request(&req, tag.1, id)?;
+
+    let file = find_file(id, tag).await?;
+    if let Some(true) = file.deleted {
+        return Err(Error::NotFound);
+    }
+
+    let (width, height) = match file.metadata {
+        Metadata::Image { width, height, .. } => (width, height),
+        _ => return Err(Error::BadRequest("file is not an image".to_string())),
+    };
+
+    let colors = match options.colors {
+        Some(colors) if !(1..=MAX_COLORS).contains(&colors) => {
+            return Err(Error::BadRequest(format!(
+                "colors must be between 1 and {}",
+                MAX_COLORS
+            )))
+        }
+        colors => colors.unwrap_or(DEFAULT_COLORS),
+    };
+
+    if let Some(palette) = &file.palette {
+        if palette.colors == colors {
+            return Ok(HttpResponse::Ok().json(&palette.swatches));
+        }
+    }
+
+    let (contents, _) = fetch_file(&file, None, None).await?;
+    let svg = file.content_type == "image/svg+xml";
+    let swatches = crate::util::pool::run(move || {
+        let image = if svg {
+            let longest = cmp::max(width, height);
+            let scale = |side: isize| cmp::max(1, side * SVG_RENDER_SIZE / longest) as u32;
+            render_svg(&contents, (scale(width), scale(height)))?
+        } else {
+            decode_image(contents)?
+        };
+
+        Ok::<_, image::ImageError>(
+            dominant_colours(&image, colors as usize)
+                .into_iter()
+                .map(|([r, g, b], fraction)| Swatch {
+                    hex: format!("#{:02x}{:02x}{:02x}", r, g, b),
+                    fraction,
+                })
+                .collect::<Vec<_>>(),
+        )
+    })
+    .await?
+    .map_err(|_| Error::IOError)?;
+
+    let palette = Palette { colors, swatches };
+
+    // Only a cache, so the palette is still returned if it can't be saved.
+    let saved = get_collection("attachments")
+        .update_one(
+            doc! { "_id": &file.id },
+            doc! { "$set": { "palette": to_bson(&palette).map_err(|_| Error::DatabaseError)? } },
+            None,
+        )
+        .await;
+
+    if let Err(err) = saved {
+        warn!("Failed to save the palette of {}. {}", file.id, err);
+    }
+
+    Ok(HttpResponse::Ok().json(palette.swatches))
+}
@@ -0,0 +1,42 @@
+use crate::config::{get_tag, Config};
+use crate::db::find_file;
+use crate::util::auth::MasterKey;
+use crate::util::result::Error;
+
+use actix_web::{web::Query, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize, Debug)]
+pub struct PurgeOptions {
+    /// Only purge files deleted at least this many seconds ago,
+    /// defaults to `deleted_retention`.
+    older_than: Option<u64>,
+}
+
+/// Permanently remove a file from storage and the database.
+pub async fn post(req: HttpRequest, _: MasterKey) -> Result<HttpResponse, Error> {
+    let tag = get_tag(&req)?;
+
+    let id = req.match_info().query("filename");
+    let file = find_file(id, tag).await?;
+    file.delete().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Permanently remove every deleted file in a tag past the retention window.
+pub async fn post_deleted(
+    req: HttpRequest,
+    _: MasterKey,
+    options: Query<PurgeOptions>,
+) -> Result<HttpResponse, Error> {
+    let (tag_id, _) = get_tag(&req)?;
+
+    let older_than = options
+        .older_than
+        .unwrap_or(Config::global().deleted_retention);
+
+    let purged = crate::db::purge_deleted(&tag_id, older_than).await?;
+    Ok(HttpResponse::Ok().json(json!({ "purged": purged })))
+}
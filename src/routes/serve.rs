@@ -1,90 +1,814 @@
-use crate::config::{get_tag, Config, ServeConfig};
+use crate::config::{get_tag, Config, DispositionPolicy, ServeConfig, Tag};
 use crate::db::*;
+use crate::util::auth::Authorized;
+use crate::util::client_hints::ClientHints;
+use crate::util::etag;
+use crate::util::metrics;
 use crate::util::result::Error;
-use crate::util::variables::{get_s3_bucket, LOCAL_STORAGE_PATH, USE_S3};
+use crate::util::signing;
+use crate::util::variables::USE_S3;
 
-use actix_web::{web::Query, HttpRequest, HttpResponse};
-use image::{io::Reader as ImageReader, ImageError};
+use actix_web::body::{AnyBody, SizedStream};
+use actix_web::http::header::HttpDate;
+use actix_web::web::Bytes;
+use actix_web::{web::Query, HttpRequest, HttpResponse, HttpResponseBuilder};
+use futures::stream::{self, BoxStream, StreamExt};
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image::error::{DecodingError, EncodingError, ImageFormatHint, LimitError, LimitErrorKind};
+use image::imageops::FilterType;
+use image::{
+    io::Reader as ImageReader, AnimationDecoder, DynamicImage, Frame, ImageEncoder, ImageError,
+    ImageFormat, Rgba, RgbaImage,
+};
 use mongodb::bson::doc;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::cmp;
+use std::convert::TryInto;
 use std::io::Cursor;
-use std::path::PathBuf;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default, PartialEq)]
 pub struct Resize {
     pub size: Option<isize>,
     pub width: Option<isize>,
     pub height: Option<isize>,
     pub max_side: Option<isize>,
+    pub crop: Option<String>,
+    pub rotate: Option<i16>,
+    pub flip: Option<String>,
+    pub quality: Option<f32>,
+    pub blur: Option<f32>,
+    pub grayscale: Option<bool>,
+    pub format: Option<String>,
+    pub fit: Option<String>,
+    pub bg: Option<String>,
+    pub strip_metadata: Option<bool>,
+    pub dpr: Option<f32>,
 }
 
-pub fn try_resize(buf: Vec<u8>, width: u32, height: u32) -> Result<Vec<u8>, ImageError> {
-    let mut bytes: Vec<u8> = Vec::new();
-    let config = Config::global();
+impl Resize {
+    /// Whether no parameters were given, meaning the original file is served.
+    pub fn is_empty(&self) -> bool {
+        *self == Resize::default()
+    }
+
+    /// Scale the requested sizes by `dpr`, for URLs which can't rely on client hints.
+    /// Returns whether it was given, in which case it's used instead of a `DPR` hint.
+    pub fn apply_dpr(&mut self) -> Result<bool, Error> {
+        let dpr = match self.dpr.take() {
+            Some(dpr) => dpr,
+            None => return Ok(false),
+        };
+
+        let config = Config::global();
+        if !(config.min_dpr..=config.max_dpr).contains(&dpr) {
+            return Err(Error::BadRequest(format!(
+                "dpr must be between {} and {}",
+                config.min_dpr, config.max_dpr
+            )));
+        }
+
+        // The result is still capped by max_resize_dimension when resizing.
+        let scale = |side: isize| cmp::max(1, (side as f32 * dpr).round() as isize);
+        self.size = self.size.map(scale);
+        self.width = self.width.map(scale);
+        self.height = self.height.map(scale);
+        self.max_side = self.max_side.map(scale);
+        Ok(true)
+    }
+
+    /// Scale a requested width or longest side for the client's pixel density,
+    /// never asking for a width wider than its viewport. Returns whether the size changed.
+    pub fn apply_client_hints(&mut self, hints: ClientHints) -> bool {
+        if self.width.is_none() && self.max_side.is_none() {
+            return false;
+        }
+
+        let mut factor = hints.dpr.unwrap_or(1.0);
+        if let (Some(width), Some(viewport_width)) = (self.width, hints.viewport_width) {
+            if width > viewport_width as isize {
+                factor *= viewport_width as f32 / width as f32;
+            }
+        }
+
+        if factor == 1.0 {
+            return false;
+        }
+
+        // The result is still capped by max_resize_dimension when resizing.
+        let scale = |side: isize| cmp::max(1, (side as f32 * factor).round() as isize);
+        self.width = self.width.map(scale);
+        self.height = self.height.filter(|_| self.width.is_some()).map(scale);
+        self.max_side = self.max_side.map(scale);
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    PNG,
+    WEBP,
+    JPEG,
+}
+
+/// How an image is fit into both a width and height.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fit {
+    /// Fill the whole area, cropping the overflow.
+    Cover,
+    /// Show the whole image, padding the remainder with a background colour.
+    /// Without one, images with transparency are padded with transparency
+    /// and anything else with white.
+    Contain { background: Option<[u8; 3]> },
+    /// Stretch the image to the exact size, distorting it if the aspect ratio differs.
+    Fill,
+}
+
+/// Parse a colour in the form `rrggbb`.
+fn parse_colour(colour: &str) -> Result<[u8; 3], Error> {
+    let error = || Error::BadRequest("bg must be a colour in the form rrggbb".to_string());
+    if colour.len() != 6 {
+        return Err(error());
+    }
+
+    let mut rgb = [0; 3];
+    for (i, channel) in rgb.iter_mut().enumerate() {
+        *channel = colour
+            .get(i * 2..i * 2 + 2)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(error)?;
+    }
+
+    Ok(rgb)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Flip {
+    Horizontal,
+    Vertical,
+}
+
+/// Additional operations applied to an image by `try_resize`.
+#[derive(Default, Debug, Clone)]
+pub struct Transform {
+    pub animated: bool,
+    pub svg: bool,
+    pub crop: Option<(u32, u32, u32, u32)>,
+    pub rotate: Option<i16>,
+    pub flip: Option<Flip>,
+    pub quality: Option<f32>,
+    pub blur: Option<f32>,
+    pub grayscale: bool,
+    pub format: Option<OutputFormat>,
+    pub fit: Option<Fit>,
+    pub strip_metadata: bool,
+}
+
+impl Transform {
+    /// The output format to encode with, honouring any per-request override.
+    fn serve_config(&self) -> ServeConfig {
+        let config = Config::global();
+        match (self.format, config.serve) {
+            (None, serve) => serve,
+            (Some(OutputFormat::PNG), serve @ ServeConfig::PNG { .. })
+            | (Some(OutputFormat::WEBP), serve @ ServeConfig::WEBP { .. })
+            | (Some(OutputFormat::JPEG), serve @ ServeConfig::JPEG { .. }) => serve,
+            (Some(OutputFormat::PNG), _) => ServeConfig::PNG { compression: None },
+            (Some(OutputFormat::WEBP), _) => ServeConfig::WEBP { quality: None },
+            (Some(OutputFormat::JPEG), _) => ServeConfig::JPEG {
+                quality: config.jpeg_quality,
+            },
+        }
+    }
+
+    /// Pick the WebP quality to encode with, never exceeding the configured quality.
+    fn webp_quality(&self, configured: Option<f32>) -> Option<f32> {
+        match (configured, self.quality) {
+            (Some(max), Some(quality)) => Some(quality.min(max)),
+            (None, Some(quality)) => Some(quality),
+            (configured, None) => configured,
+        }
+    }
+
+    /// Whether this transform changes the image even without scaling it.
+    fn has_operations(&self) -> bool {
+        self.crop.is_some()
+            || self.rotate.is_some()
+            || self.flip.is_some()
+            || self.quality.is_some()
+            || self.blur.is_some()
+            || self.grayscale
+            || self.format.is_some()
+            || self.strip_metadata
+    }
+
+    fn apply(&self, image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+        let image = match self.crop {
+            Some((x, y, w, h)) => image.crop_imm(x, y, w, h),
+            None => image,
+        };
 
-    let image = ImageReader::new(Cursor::new(buf))
-        .with_guessed_format()?
-        .decode()?
         // resize_exact is about 2.5x slower,
         //  thumb approximation doesn't have terrible quality so it's fine to stick with
         //.resize_exact(width as u32, height as u32, image::imageops::FilterType::Gaussian)
-        .thumbnail_exact(width as u32, height as u32);
+        let image = match self.fit {
+            Some(Fit::Cover) => crate::util::image::crop_to_fit(image, width, height),
+            Some(Fit::Contain { background }) => {
+                let background = match background {
+                    Some([r, g, b]) => Rgba([r, g, b, 255]),
+                    None if image.color().has_alpha() => Rgba([0, 0, 0, 0]),
+                    None => Rgba([255, 255, 255, 255]),
+                };
 
-    match config.serve {
-        ServeConfig::PNG => {
-            let mut writer = Cursor::new(&mut bytes);
-            image.write_to(&mut writer, image::ImageOutputFormat::Png)?;
+                crate::util::image::pad_to_fit(image, width, height, background)
+            }
+            // Lanczos3 is slower again, but the distortion makes the thumbnail's
+            // approximation noticeably blocky, so use it here.
+            Some(Fit::Fill) => image.resize_exact(width, height, FilterType::Lanczos3),
+            None => image.thumbnail_exact(width, height),
+        };
+
+        let image = match self.rotate {
+            Some(90) => image.rotate90(),
+            Some(180) => image.rotate180(),
+            Some(270) => image.rotate270(),
+            _ => image,
+        };
+
+        let image = match self.flip {
+            Some(Flip::Horizontal) => image.fliph(),
+            Some(Flip::Vertical) => image.flipv(),
+            None => image,
+        };
+
+        let image = match self.blur {
+            Some(sigma) => image.blur(sigma),
+            None => image,
+        };
+
+        if self.grayscale {
+            image.grayscale()
+        } else {
+            image
+        }
+    }
+}
+
+/// Parse a crop region in the form `x,y,width,height`,
+/// clamping the region to the bounds of the image.
+fn parse_crop(crop: &str, width: isize, height: isize) -> Result<(u32, u32, u32, u32), Error> {
+    let values = crop
+        .split(',')
+        .map(|value| value.trim().parse::<u32>())
+        .collect::<Result<Vec<u32>, _>>()
+        .map_err(|_| {
+            Error::BadRequest("crop must be four whole numbers: x,y,width,height".to_string())
+        })?;
+
+    let width: u32 = width.try_into().map_err(|_| Error::IOError)?;
+    let height: u32 = height.try_into().map_err(|_| Error::IOError)?;
+
+    if let [x, y, w, h] = values[..] {
+        if x >= width || y >= height || w == 0 || h == 0 {
+            return Err(Error::BadRequest(
+                "crop region is outside of the image".to_string(),
+            ));
         }
+
+        Ok((x, y, cmp::min(w, width - x), cmp::min(h, height - y)))
+    } else {
+        Err(Error::BadRequest(
+            "crop must be four whole numbers: x,y,width,height".to_string(),
+        ))
+    }
+}
+
+fn parse_svg(buf: &[u8]) -> Result<resvg::usvg::Tree, ImageError> {
+    resvg::usvg::Tree::from_data(buf, &resvg::usvg::Options::default()).map_err(|err| {
+        ImageError::Decoding(DecodingError::new(ImageFormatHint::Name("svg".into()), err))
+    })
+}
+
+/// Rasterise a tree into a `width` x `height` image, placing it with `transform`.
+fn rasterise_svg(
+    tree: &resvg::usvg::Tree,
+    width: u32,
+    height: u32,
+    transform: resvg::tiny_skia::Transform,
+) -> Result<DynamicImage, ImageError> {
+    let dimension_error =
+        || ImageError::Limits(LimitError::from_kind(LimitErrorKind::DimensionError));
+
+    let mut pixmap = resvg::tiny_skia::Pixmap::new(width, height).ok_or_else(dimension_error)?;
+    resvg::render(tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia stores premultiplied alpha, image expects it to be straight.
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+
+    RgbaImage::from_raw(width, height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(dimension_error)
+}
+
+/// Render an SVG stretched to the given dimensions.
+pub fn render_svg(buf: &[u8], (width, height): (u32, u32)) -> Result<DynamicImage, ImageError> {
+    let tree = parse_svg(buf)?;
+    let svg_size = tree.size();
+    rasterise_svg(
+        &tree,
+        width,
+        height,
+        resvg::tiny_skia::Transform::from_scale(
+            width as f32 / svg_size.width(),
+            height as f32 / svg_size.height(),
+        ),
+    )
+}
+
+/// Render only a region of an SVG, given in its own units. The SVG's declared size
+/// can be anything, so the region is scaled down to fit `max_resize_dimension`.
+pub fn render_svg_region(
+    buf: &[u8],
+    (x, y, width, height): (u32, u32, u32, u32),
+) -> Result<DynamicImage, ImageError> {
+    let tree = parse_svg(buf)?;
+    let max_dimension = Config::global().max_resize_dimension;
+    let scale = (max_dimension as f32 / cmp::max(width, height) as f32).min(1.0);
+
+    let scaled = |side: u32| cmp::max(1, (side as f32 * scale).round() as u32);
+    rasterise_svg(
+        &tree,
+        scaled(width),
+        scaled(height),
+        resvg::tiny_skia::Transform::from_translate(-(x as f32), -(y as f32))
+            .post_scale(scale, scale),
+    )
+}
+
+fn encode_webp(image: &DynamicImage, quality: Option<f32>) -> Vec<u8> {
+    // The webp crate only accepts raw RGB / RGBA buffers,
+    //  so grayscale images are expanded back out to RGB(A) here.
+    let (pixels, layout) = if image.color().has_alpha() {
+        (image.to_rgba8().into_raw(), webp::PixelLayout::Rgba)
+    } else {
+        (image.to_rgb8().into_raw(), webp::PixelLayout::Rgb)
+    };
+
+    let encoder = webp::Encoder::new(&pixels, layout, image.width(), image.height());
+    if let Some(quality) = quality {
+        encoder.encode(quality).to_vec()
+    } else {
+        encoder.encode_lossless().to_vec()
+    }
+}
+
+fn try_resize_animated(
+    frames: Vec<Frame>,
+    width: u32,
+    height: u32,
+    transform: &Transform,
+) -> Result<(Vec<u8>, &'static str), ImageError> {
+    let mut bytes: Vec<u8> = Vec::new();
+
+    let frames: Vec<Frame> = frames
+        .into_iter()
+        .map(|frame| {
+            let delay = frame.delay();
+            let buffer = transform
+                .apply(DynamicImage::ImageRgba8(frame.into_buffer()), width, height)
+                .into_rgba8();
+
+            Frame::from_parts(buffer, 0, 0, delay)
+        })
+        .collect();
+
+    // Rotating swaps the dimensions, so take them from the transformed frames.
+    let (width, height) = frames
+        .first()
+        .map(|frame| frame.buffer().dimensions())
+        .unwrap_or((width, height));
+
+    match transform.serve_config() {
         ServeConfig::WEBP { quality } => {
-            let encoder = webp::Encoder::from_image(&image).expect("Could not create encoder.");
-            if let Some(quality) = quality {
-                bytes = encoder.encode(quality).to_vec();
+            let mut webp_config = webp::WebPConfig::new().expect("Could not create encoder.");
+            if let Some(quality) = transform.webp_quality(quality) {
+                webp_config.quality = quality;
             } else {
-                bytes = encoder.encode_lossless().to_vec();
+                webp_config.lossless = 1;
             }
+
+            let mut encoder = webp::AnimEncoder::new(width, height, &webp_config);
+            let mut timestamp = 0;
+            for frame in &frames {
+                encoder.add_frame(webp::AnimFrame::from_rgba(
+                    frame.buffer(),
+                    width,
+                    height,
+                    timestamp,
+                ));
+
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                timestamp += (numer / cmp::max(denom, 1)) as i32;
+            }
+
+            bytes = encoder
+                .try_encode()
+                .map_err(|err| {
+                    ImageError::Encoding(EncodingError::new(
+                        ImageFormatHint::Exact(ImageFormat::WebP),
+                        format!("{:?}", err),
+                    ))
+                })?
+                .to_vec();
+
+            Ok((bytes, "image/webp"))
+        }
+        // There is no widely supported animated PNG output,
+        // so animations are kept as GIFs instead.
+        _ => {
+            let mut encoder = GifEncoder::new(&mut bytes);
+            encoder.set_repeat(Repeat::Infinite)?;
+            encoder.encode_frames(frames)?;
+            drop(encoder);
+
+            Ok((bytes, "image/gif"))
+        }
+    }
+}
+
+/// Decode, transform and re-encode an image to the configured output format.
+///
+/// The output is always encoded from the decoded pixel data alone, none of the
+/// encoders are given the source container, so EXIF / XMP / IPTC metadata is
+/// never carried over to the served image.
+#[tracing::instrument(
+    skip(buf, transform),
+    fields(image.width = width, image.height = height, resize.duration_ms)
+)]
+pub fn try_resize(
+    buf: Vec<u8>,
+    width: u32,
+    height: u32,
+    transform: Transform,
+) -> Result<(Vec<u8>, &'static str), ImageError> {
+    let start = Instant::now();
+    let result = resize_image(buf, width, height, transform);
+
+    tracing::Span::current().record("resize.duration_ms", start.elapsed().as_millis() as u64);
+    result
+}
+
+fn resize_image(
+    buf: Vec<u8>,
+    width: u32,
+    height: u32,
+    transform: Transform,
+) -> Result<(Vec<u8>, &'static str), ImageError> {
+    // AVIF output has no animation support, so it always falls through to the first frame.
+    if transform.animated
+        && !matches!(transform.serve_config(), ServeConfig::AVIF { .. })
+        && image::guess_format(&buf).ok() == Some(ImageFormat::Gif)
+    {
+        let frames = GifDecoder::new(Cursor::new(&buf))?
+            .into_frames()
+            .collect_frames()?;
+
+        if frames.len() > 1 {
+            return try_resize_animated(frames, width, height, &transform);
         }
     }
 
-    Ok(bytes)
+    let (image, transform) = match (transform.svg, transform.crop) {
+        // The region is cropped while rendering, so it's left out of the transform.
+        (true, Some(region)) => (
+            render_svg_region(&buf, region)?,
+            Transform {
+                crop: None,
+                ..transform
+            },
+        ),
+        (true, None) => (render_svg(&buf, (width, height))?, transform),
+        (false, _) => (decode_image(buf)?, transform),
+    };
+
+    let image = transform.apply(image, width, height);
+    encode_image(&image, &transform)
 }
 
-pub async fn fetch_file(
-    id: &str,
-    tag: &str,
-    metadata: Metadata,
-    resize: Option<Resize>,
-) -> Result<(Vec<u8>, Option<String>), Error> {
-    let mut contents = vec![];
-    let config = Config::global();
+/// Resize an image to several sizes, decoding it only once where possible.
+/// Animations and SVGs are handled by `try_resize` for each size instead.
+pub fn try_resize_many(
+    buf: Vec<u8>,
+    sizes: &[(u32, u32)],
+    transform: Transform,
+) -> Result<Vec<(Vec<u8>, &'static str)>, ImageError> {
+    if transform.animated || transform.svg {
+        return sizes
+            .iter()
+            .map(|&(width, height)| try_resize(buf.clone(), width, height, transform.clone()))
+            .collect();
+    }
+
+    let image = decode_image(buf)?;
+    sizes
+        .iter()
+        .map(|&(width, height)| {
+            let resized = transform.apply(image.clone(), width, height);
+            encode_image(&resized, &transform)
+        })
+        .collect()
+}
+
+/// Check whether the buffer is an ISO media file with a HEIF brand.
+#[cfg(feature = "heif")]
+pub fn is_heif(buf: &[u8]) -> bool {
+    buf.len() >= 12
+        && &buf[4..8] == b"ftyp"
+        && matches!(
+            &buf[8..12],
+            b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" | b"mif1" | b"msf1"
+        )
+}
+
+#[cfg(feature = "heif")]
+pub fn decode_heif(buf: &[u8]) -> Result<DynamicImage, ImageError> {
+    use libheif_rs::{ColorSpace, HeifContext, HeifError, LibHeif, RgbChroma};
+
+    let hint = || ImageFormatHint::Name("heif".into());
+    let error = |err: HeifError| ImageError::Decoding(DecodingError::new(hint(), err));
 
-    if *USE_S3 {
-        let bucket = get_s3_bucket(tag)?;
-        let (data, code) = bucket
-            .get_object(format!("/{}", id))
-            .await
-            .map_err(|_| Error::S3Error)?;
+    let context = HeifContext::read_from_bytes(buf).map_err(error)?;
+    let handle = context.primary_image_handle().map_err(error)?;
+    let image = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+        .map_err(error)?;
+
+    let planes = image.planes();
+    let plane = planes
+        .interleaved
+        .ok_or_else(|| ImageError::Decoding(DecodingError::from_format_hint(hint())))?;
+
+    // Rows may be padded, so copy them out one at a time.
+    let row_length = plane.width as usize * 4;
+    let mut pixels = Vec::with_capacity(row_length * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&row[..row_length]);
+    }
+
+    RgbaImage::from_raw(plane.width, plane.height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| ImageError::Decoding(DecodingError::from_format_hint(hint())))
+}
+
+pub fn decode_image(buf: Vec<u8>) -> Result<DynamicImage, ImageError> {
+    #[cfg(feature = "heif")]
+    if is_heif(&buf) {
+        return decode_heif(&buf);
+    }
 
-        if code != 200 {
-            return Err(Error::S3Error);
+    ImageReader::new(Cursor::new(buf))
+        .with_guessed_format()?
+        .decode()
+}
+
+/// Encode an image using the configured output format.
+pub fn encode_image(
+    image: &DynamicImage,
+    transform: &Transform,
+) -> Result<(Vec<u8>, &'static str), ImageError> {
+    let mut bytes: Vec<u8> = Vec::new();
+
+    let content_type = match transform.serve_config() {
+        ServeConfig::PNG { compression } => {
+            // The encoder only exposes three presets, so bucket the 0-9 level into them.
+            let compression = match compression {
+                Some(0..=3) => CompressionType::Fast,
+                Some(7..=9) => CompressionType::Best,
+                _ => CompressionType::Default,
+            };
+
+            PngEncoder::new_with_quality(&mut bytes, compression, PngFilterType::Adaptive)
+                .write_image(
+                    image.as_bytes(),
+                    image.width(),
+                    image.height(),
+                    image.color(),
+                )?;
+            "image/png"
         }
+        ServeConfig::WEBP { quality } => {
+            bytes = encode_webp(image, transform.webp_quality(quality));
+            "image/webp"
+        }
+        ServeConfig::AVIF { quality, speed } => {
+            // ravif panics outside of these ranges, so clamp them first.
+            let encoder = ravif::Encoder::new()
+                .with_quality(quality.unwrap_or(80.0).clamp(1.0, 100.0))
+                .with_speed(speed.unwrap_or(4).clamp(1, 10));
 
-        contents = data;
-    } else {
-        let path: PathBuf = format!("{}/{}", *LOCAL_STORAGE_PATH, id)
-            .parse()
-            .map_err(|_| Error::IOError)?;
+            let rgba = image.to_rgba8();
+            let pixels: Vec<ravif::RGBA8> = rgba
+                .pixels()
+                .map(|p| ravif::RGBA8::new(p[0], p[1], p[2], p[3]))
+                .collect();
 
-        let mut f = File::open(path.clone()).await.map_err(|_| Error::IOError)?;
-        f.read_to_end(&mut contents)
-            .await
-            .map_err(|_| Error::IOError)?;
+            bytes = encoder
+                .encode_rgba(ravif::Img::new(
+                    &pixels[..],
+                    rgba.width() as usize,
+                    rgba.height() as usize,
+                ))
+                .map_err(|err| {
+                    ImageError::Encoding(EncodingError::new(
+                        ImageFormatHint::Exact(ImageFormat::Avif),
+                        err,
+                    ))
+                })?
+                .avif_file;
+
+            "image/avif"
+        }
+        ServeConfig::JPEG { quality } => {
+            // JPEG has no alpha channel and only supports 8-bit colour.
+            let mut writer = Cursor::new(&mut bytes);
+            DynamicImage::ImageRgb8(image.to_rgb8())
+                .write_to(&mut writer, image::ImageOutputFormat::Jpeg(quality))?;
+            "image/jpeg"
+        }
+    };
+
+    Ok((bytes, content_type))
+}
+
+/// Quality an `Accept` header gives a media type, from the most specific range matching it.
+fn accept_quality(accept: &str, media: &str) -> f32 {
+    let (kind, _) = media.split_once('/').unwrap_or((media, ""));
+
+    // Specificity then quality, `*/*` being the least specific.
+    let mut best: Option<(u8, f32)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let range = parts.next().unwrap_or("").trim();
+        let specificity = if range.eq_ignore_ascii_case(media) {
+            2
+        } else if range
+            .strip_suffix("/*")
+            .map(|range| range.eq_ignore_ascii_case(kind))
+            .unwrap_or(false)
+        {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+
+        let quality = parts
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .and_then(|(_, value)| value.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if best.map(|(best, _)| specificity > best).unwrap_or(true) {
+            best = Some((specificity, quality));
+        }
+    }
+
+    best.map(|(_, quality)| quality).unwrap_or(0.0)
+}
+
+/// Pick a fallback format for clients which do not accept the configured one.
+pub fn negotiate_format(accept: Option<&str>) -> Option<OutputFormat> {
+    let required = match Config::global().serve {
+        ServeConfig::WEBP { .. } => "image/webp",
+        ServeConfig::AVIF { .. } => "image/avif",
+        _ => return None,
+    };
+
+    if accept_quality(accept?, required) > 0.0 {
+        None
+    } else {
+        Some(OutputFormat::PNG)
     }
+}
 
+#[tracing::instrument(skip_all, fields(file.id = %file.id, storage.backend = crate::storage::backend()))]
+pub async fn fetch_file(
+    file: &crate::db::File,
+    resize: Option<Resize>,
+    negotiated: Option<OutputFormat>,
+) -> Result<(Vec<u8>, Option<String>), Error> {
     if let Some(parameters) = resize {
-        if let Metadata::Image { width, height } = metadata {
+        if let Metadata::Image {
+            width,
+            height,
+            animated,
+            ..
+        } = file.metadata
+        {
+            let crop = match &parameters.crop {
+                Some(crop) => Some(parse_crop(crop, width, height)?),
+                None => None,
+            };
+
+            let rotate = match parameters.rotate {
+                Some(rotate @ (90 | 180 | 270)) => Some(rotate),
+                Some(_) => {
+                    return Err(Error::BadRequest(
+                        "rotate must be 90, 180 or 270".to_string(),
+                    ))
+                }
+                None => None,
+            };
+
+            let flip = match parameters.flip.as_deref() {
+                Some("h") => Some(Flip::Horizontal),
+                Some("v") => Some(Flip::Vertical),
+                Some(_) => return Err(Error::BadRequest("flip must be h or v".to_string())),
+                None => None,
+            };
+
+            let quality = parse_quality(parameters.quality)?;
+
+            // Blurring gets expensive quickly, so cap the sigma.
+            let blur = match parameters.blur {
+                Some(sigma) if sigma.is_nan() || sigma <= 0.0 => {
+                    return Err(Error::BadRequest(
+                        "blur must be a positive number".to_string(),
+                    ))
+                }
+                Some(sigma) => Some(sigma.min(Config::global().max_blur_sigma)),
+                None => None,
+            };
+
+            let format = parse_format(parameters.format.as_deref())?;
+
+            // Fitting only applies when both dimensions are given.
+            let fit = match parameters.fit.as_deref() {
+                Some("cover") => Some(Fit::Cover),
+                Some("contain") => Some(Fit::Contain {
+                    background: parameters.bg.as_deref().map(parse_colour).transpose()?,
+                }),
+                Some("fill") => {
+                    let allowed = Config::global()
+                        .tags
+                        .get(&file.tag)
+                        .map(|tag| tag.allow_fit_fill)
+                        .unwrap_or(true);
+
+                    if !allowed {
+                        return Err(Error::Forbidden);
+                    }
+
+                    Some(Fit::Fill)
+                }
+                Some(_) => {
+                    return Err(Error::BadRequest(
+                        "fit must be cover, contain or fill".to_string(),
+                    ))
+                }
+                None => None,
+            }
+            .filter(|_| parameters.width.is_some() && parameters.height.is_some());
+
+            let original_format = match file.content_type.as_str() {
+                "image/jpeg" => Some(OutputFormat::JPEG),
+                "image/png" => Some(OutputFormat::PNG),
+                "image/webp" => Some(OutputFormat::WEBP),
+                _ => None,
+            };
+
+            let mut transform = Transform {
+                animated,
+                svg: file.content_type == "image/svg+xml",
+                crop,
+                rotate,
+                flip,
+                quality,
+                blur,
+                grayscale: parameters.grayscale.unwrap_or(false),
+                format,
+                fit,
+                // Decoding drops metadata, though GIFs and SVGs have no encoder to keep their format.
+                strip_metadata: parameters.strip_metadata.unwrap_or(false)
+                    && original_format.is_some(),
+            };
+
+            // Cropping happens first, so scale relative to the cropped region.
+            let (width, height) = match crop {
+                Some((_, _, w, h)) => (w as isize, h as isize),
+                None => (width, height),
+            };
+
             let shortest_length = cmp::min(width, height);
             let (target_width, target_height) = match (
                 parameters.size,
@@ -105,6 +829,8 @@ pub async fn fetch_file(
                         (w, (height as f32 * (w as f32 / width as f32)) as isize)
                     }
                 }
+                // The box is used as given when fitting into it.
+                (_, _, Some(w), Some(h)) if transform.fit.is_some() => (w, h),
                 (_, _, Some(w), Some(h)) => (cmp::min(width, w), cmp::min(height, h)),
                 (_, _, Some(w), _) => {
                     let w = cmp::min(width, w);
@@ -114,57 +840,991 @@ pub async fn fetch_file(
                     let h = cmp::min(height, h);
                     ((h as f32 * (width as f32 / height as f32)) as isize, h)
                 }
-                _ => return Ok((contents, None)),
+                _ if transform.has_operations() => {
+                    // Without resizing, stripping keeps the original format unless one is asked for.
+                    if transform.strip_metadata && transform.format.is_none() {
+                        transform.format = original_format;
+                    }
+
+                    (width, height)
+                }
+                _ => {
+                    let contents = read_original(file).await?;
+                    return Ok((contents, None));
+                }
             };
 
+            // Negative sizes would wrap around when cast to u32.
+            if target_width <= 0 || target_height <= 0 {
+                return Err(Error::BadRequest(
+                    "width and height must be positive".to_string(),
+                ));
+            }
+
+            // Scale oversized requests down to fit rather than rejecting them,
+            // so existing URLs keep working. Fitted sizes come straight from the
+            // query, so scale in a wider type to keep huge ones from overflowing.
+            let max_dimension = Config::global().max_resize_dimension as isize;
+            let longest = cmp::max(target_width, target_height);
+            let (target_width, target_height) = if longest > max_dimension {
+                let scale = |side: isize| {
+                    cmp::max(1, side as i128 * max_dimension as i128 / longest as i128) as isize
+                };
+
+                (scale(target_width), scale(target_height))
+            } else {
+                (target_width, target_height)
+            };
+
+            if transform.format.is_none() {
+                transform.format = negotiated;
+            }
+
+            // Plain resizes may have been generated at upload.
+            if !transform.has_operations() && transform.fit.is_none() {
+                let variant = file.variants.iter().flatten().find(|variant| {
+                    variant.width == target_width as u32 && variant.height == target_height as u32
+                });
+
+                if let Some(variant) = variant {
+                    if let Ok(bytes) = crate::storage::read(&file.tag, &variant.id).await {
+                        return Ok((bytes, Some(variant.content_type.clone())));
+                    }
+                }
+            }
+
+            let key = (
+                file.id.clone(),
+                file.version(),
+                target_width as u32,
+                target_height as u32,
+                format!("{:?}", transform),
+            );
+
+            if let Some((bytes, content_type)) = crate::cache::get(&key) {
+                return Ok((bytes, Some(content_type.to_string())));
+            }
+
+            let shared_key = crate::cache::shared_key(&key);
+            if let Some((bytes, content_type)) = crate::cache::get_shared(&shared_key).await {
+                return Ok((bytes, Some(content_type)));
+            }
+
+            let contents = read_original(file).await?;
+
             // There should be a way to do this zero-copy, but I can't be asked to figure it out right now.
             let cloned = contents.clone();
-            if let Ok(Ok(bytes)) = actix_web::web::block(move || {
-                try_resize(cloned, target_width as u32, target_height as u32)
+            let timer = metrics::RESIZE_DURATION
+                .with_label_values(&[metrics::tag_label(&file.tag)])
+                .start_timer();
+
+            let resized = crate::util::pool::run(move || {
+                try_resize(cloned, target_width as u32, target_height as u32, transform)
             })
-            .await
-            {
-                return Ok((
-                    bytes,
-                    Some(
-                        match config.serve {
-                            ServeConfig::PNG => "image/png",
-                            ServeConfig::WEBP { .. } => "image/webp",
-                        }
-                        .to_string(),
-                    ),
-                ));
+            .await;
+
+            timer.observe_duration();
+            match resized {
+                Ok(Ok((bytes, content_type))) => {
+                    crate::cache::insert_shared(&shared_key, &bytes, content_type).await;
+                    crate::cache::insert(key, bytes.clone(), content_type);
+                    return Ok((bytes, Some(content_type.to_string())));
+                }
+                // Logged within the request's span, so the request ID is included.
+                Ok(Err(err)) => tracing::error!("Failed to resize {}. {}", file.id, err),
+                Err(err) => tracing::error!("Failed to resize {}. {:?}", file.id, err),
             }
+
+            return Ok((contents, None));
+        }
+
+        #[cfg(feature = "pdf")]
+        if let Metadata::Document { width, height, .. } = file.metadata {
+            if Config::global().pdf_preview {
+                return preview_document(file, &parameters, width, height, negotiated).await;
+            }
+        }
+    }
+
+    let contents = read_original(file).await?;
+    Ok((contents, None))
+}
+
+fn parse_quality(quality: Option<f32>) -> Result<Option<f32>, Error> {
+    match quality {
+        Some(quality) if !(0.0..=100.0).contains(&quality) => Err(Error::BadRequest(
+            "quality must be between 0 and 100".to_string(),
+        )),
+        quality => Ok(quality),
+    }
+}
+
+pub fn parse_format(format: Option<&str>) -> Result<Option<OutputFormat>, Error> {
+    match format {
+        Some("png") => Ok(Some(OutputFormat::PNG)),
+        Some("webp") => Ok(Some(OutputFormat::WEBP)),
+        Some("jpeg") => Ok(Some(OutputFormat::JPEG)),
+        Some(_) => Err(Error::BadRequest(
+            "format must be png, webp or jpeg".to_string(),
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Render the first page of a PDF to fit the requested size.
+/// Pages are vectors, so unlike images they're scaled up as well as down.
+#[cfg(feature = "pdf")]
+async fn preview_document(
+    file: &crate::db::File,
+    parameters: &Resize,
+    width: isize,
+    height: isize,
+    negotiated: Option<OutputFormat>,
+) -> Result<(Vec<u8>, Option<String>), Error> {
+    let transform = Transform {
+        quality: parse_quality(parameters.quality)?,
+        format: parse_format(parameters.format.as_deref())?.or(negotiated),
+        ..Default::default()
+    };
+
+    let (target_width, target_height) = match (
+        parameters.size.or(parameters.max_side),
+        parameters.width,
+        parameters.height,
+    ) {
+        (Some(size), _, _) => (size, size),
+        (_, Some(w), Some(h)) => (w, h),
+        (_, Some(w), _) => (w, (w as f32 * (height as f32 / width as f32)) as isize),
+        (_, _, Some(h)) => ((h as f32 * (width as f32 / height as f32)) as isize, h),
+        _ => (width, height),
+    };
+
+    if target_width <= 0 || target_height <= 0 {
+        return Err(Error::BadRequest(
+            "width and height must be positive".to_string(),
+        ));
+    }
+
+    let max_dimension = Config::global().max_resize_dimension as isize;
+    let target_width = cmp::min(target_width, max_dimension) as u32;
+    let target_height = cmp::min(target_height, max_dimension) as u32;
+
+    let key = (
+        file.id.clone(),
+        file.version(),
+        target_width,
+        target_height,
+        format!("{:?}", transform),
+    );
+
+    if let Some((bytes, content_type)) = crate::cache::get(&key) {
+        return Ok((bytes, Some(content_type.to_string())));
+    }
+
+    let contents = read_original(file).await?;
+    let cloned = contents.clone();
+    let rendered = crate::util::pool::run(move || {
+        let image = crate::pdf::render_first_page(&cloned, target_width, target_height)?;
+        encode_image(&image, &transform)
+    })
+    .await;
+
+    match rendered {
+        Ok(Ok((bytes, content_type))) => {
+            crate::cache::insert(key, bytes.clone(), content_type);
+            return Ok((bytes, Some(content_type.to_string())));
         }
+        Ok(Err(err)) => tracing::error!("Failed to preview {}. {}", file.id, err),
+        Err(err) => tracing::error!("Failed to preview {}. {:?}", file.id, err),
     }
 
     Ok((contents, None))
 }
 
-pub async fn get(req: HttpRequest, resize: Query<Resize>) -> Result<HttpResponse, Error> {
+/// Read a file from storage, checking it against its hash if `verify_integrity` is set.
+pub async fn read_original(file: &crate::db::File) -> Result<Vec<u8>, Error> {
+    let contents = crate::storage::read(&file.tag, file.storage_id()).await?;
+    if !Config::global().verify_integrity {
+        return Ok(contents);
+    }
+
+    if let Some(hash) = &file.hash {
+        if hex::encode(Sha256::digest(&contents)) != *hash {
+            metrics::INTEGRITY_FAILURES.inc();
+            tracing::error!(
+                "File {} does not match its hash, storage may be corrupted.",
+                file.id
+            );
+            return Err(Error::IntegrityError);
+        }
+    }
+
+    Ok(contents)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteRange {
+    /// Inclusive start and end offsets.
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header for a file of the given length.
+///
+/// Only a single byte range is supported, anything else
+/// returns `None` and the full file should be served instead.
+pub fn parse_range(header: &str, length: u64) -> Option<ByteRange> {
+    let spec = header.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || length == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+
+        return Some(ByteRange::Satisfiable(
+            length.saturating_sub(suffix),
+            length - 1,
+        ));
+    }
+
+    let start: u64 = start.parse().ok()?;
+    let end: Option<u64> = if end.is_empty() {
+        None
+    } else {
+        Some(end.parse().ok()?)
+    };
+
+    if start >= length {
+        return Some(ByteRange::Unsatisfiable);
+    }
+
+    let end = cmp::min(end.unwrap_or(length - 1), length - 1);
+    if end < start {
+        return None;
+    }
+
+    Some(ByteRange::Satisfiable(start, end))
+}
+
+/// Stream part of a file from storage without buffering it.
+pub async fn stream_file(
+    file: &crate::db::File,
+    offset: u64,
+    length: u64,
+) -> Result<BoxStream<'static, Result<Bytes, std::io::Error>>, Error> {
+    // Only whole files can be checked, which means reading them into memory.
+    if Config::global().verify_integrity && offset == 0 && length == file.size as u64 {
+        let contents = read_original(file).await?;
+        return Ok(stream::once(async { Ok(Bytes::from(contents)) }).boxed());
+    }
+
+    crate::storage::stream(
+        &file.tag,
+        file.storage_id(),
+        offset,
+        length,
+        file.size as u64,
+    )
+    .await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ServeOptions {
+    filename: Option<String>,
+    redirect: Option<bool>,
+}
+
+/// Validate a client supplied download name,
+/// truncating it to at most 255 bytes.
+fn parse_filename(filename: &str) -> Result<String, Error> {
+    if filename.is_empty()
+        || filename.contains("..")
+        || filename.contains('/')
+        || filename.contains('\\')
+    {
+        return Err(Error::BadRequest("filename is not allowed".to_string()));
+    }
+
+    let mut end = cmp::min(filename.len(), 255);
+    while !filename.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    Ok(filename[..end].to_string())
+}
+
+/// Send clients to where a file is hosted, for files recorded by tags that redirect.
+/// Signed URLs are checked before this, so they protect the original URL too.
+pub fn redirect_to_original(file: &File) -> Option<HttpResponse> {
+    file.original_url.as_ref().map(|url| {
+        HttpResponse::Found()
+            .insert_header(("Location", url.as_str()))
+            .finish()
+    })
+}
+
+/// Build an attachment disposition for the given filename,
+/// with an RFC 5987 encoded copy for non-ASCII names.
+pub fn attachment(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    if fallback == filename {
+        return format!("attachment; filename=\"{}\"", filename);
+    }
+
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+/// Whether resize parameters apply to a file.
+fn transformable(metadata: &Metadata) -> bool {
+    match metadata {
+        Metadata::Image { .. } => true,
+        Metadata::Document { .. } => cfg!(feature = "pdf") && Config::global().pdf_preview,
+        _ => false,
+    }
+}
+
+fn disposition(tag: &Tag, content_type: &str, filename: Option<&str>) -> String {
+    if let Some(filename) = filename {
+        return attachment(filename);
+    }
+
+    match tag.disposition_policy {
+        Some(DispositionPolicy::Inline) => return "inline".to_string(),
+        Some(DispositionPolicy::Attachment) => return "attachment".to_string(),
+        Some(DispositionPolicy::Auto) | None => {}
+    }
+
+    // This list should match files accepted
+    // by upload.rs#L68 as allowed images / videos.
+    match content_type {
+        "image/jpeg" | "image/png" | "image/gif" | "image/webp" | "image/avif" | "video/mp4"
+        | "video/webm" | "video/webp" | "audio/quicktime" | "audio/mpeg" => "inline",
+        _ => "attachment",
+    }
+    .to_string()
+}
+
+/// The stored content type, unless it's missing or invalid.
+fn served_content_type(file: &File) -> String {
+    let content_type = file.content_type.trim();
+    let config = Config::global();
+
+    if content_type.is_empty() {
+        if let Metadata::Image { .. } = file.metadata {
+            return config.fallback_image_content_type.clone();
+        }
+    }
+
+    match content_type.parse::<mime::Mime>() {
+        Ok(_) => content_type.to_string(),
+        Err(_) => config.default_content_type.clone(),
+    }
+}
+
+/// Whether a response should be sent as-is rather than compressed,
+/// either because it is too small or already compressed.
+pub fn skip_compression(content_type: &str, size: u64) -> bool {
+    if size < Config::global().compression_threshold {
+        return true;
+    }
+
+    matches!(
+        content_type,
+        "image/jpeg"
+            | "image/webp"
+            | "image/avif"
+            | "image/gif"
+            | "image/heic"
+            | "application/zip"
+            | "application/gzip"
+    ) || content_type.starts_with("video/")
+        || content_type.starts_with("audio/")
+}
+
+pub async fn get(
+    req: HttpRequest,
+    _: Authorized,
+    resize: Query<Resize>,
+    options: Query<ServeOptions>,
+) -> Result<HttpResponse, Error> {
+    let mut resize = resize.into_inner();
+    let mut hints = ClientHints::from_request(&req);
+    if resize.apply_dpr()? {
+        hints.dpr = None;
+    }
+
+    let hinted = resize.apply_client_hints(hints);
     let tag = get_tag(&req)?;
+    let filename = options
+        .filename
+        .as_deref()
+        .map(parse_filename)
+        .transpose()?;
 
     let id = req.match_info().query("filename");
-    let file = find_file(id, tag.clone()).await?;
+    signing::check_request(&req, tag.1, id)?;
+
+    let mut file = find_file(id, tag.clone()).await?;
 
     if let Some(true) = file.deleted {
         return Err(Error::NotFound);
     }
 
-    let (contents, content_type) = fetch_file(id, &tag.0, file.metadata, Some(resize.0)).await?;
-    let content_type = content_type.unwrap_or(file.content_type);
+    if let Some(response) = redirect_to_original(&file) {
+        return Ok(response);
+    }
 
-    // This list should match files accepted
-    // by upload.rs#L68 as allowed images / videos.
-    let diposition = match content_type.as_ref() {
-        "image/jpeg" | "image/png" | "image/gif" | "image/webp" | "video/mp4" | "video/webm"
-        | "video/webp" | "audio/quicktime" | "audio/mpeg" => "inline",
-        _ => "attachment",
+    file.content_type = served_content_type(&file);
+
+    // Hand the client straight to S3 for unmodified files,
+    // unless none of the buckets can be found holding it.
+    if *USE_S3 && options.redirect == Some(true) && resize.is_empty() {
+        let storage_id = file.storage_id();
+        if let Some(bucket) = crate::storage::s3_bucket_holding(&file.tag, storage_id).await? {
+            let url = bucket
+                .presign_get(format!("/{}", storage_id), tag.1.presign_expiry)
+                .map_err(metrics::s3_error)?;
+
+            return Ok(HttpResponse::Found()
+                .insert_header(("Location", url))
+                .finish());
+        }
+    }
+
+    let negotiated = negotiate_format(
+        req.headers()
+            .get("Accept")
+            .and_then(|value| value.to_str().ok()),
+    );
+
+    // Negotiated variants of the same URL need their own ETag.
+    let mut variant = match negotiated {
+        Some(format) if !resize.is_empty() => format!("{}:{:?}", req.query_string(), format),
+        _ => req.query_string().to_string(),
+    };
+
+    // As do sizes scaled by client hints.
+    if hinted {
+        variant = format!(
+            "{}:{:?}",
+            variant,
+            (resize.width, resize.height, resize.max_side)
+        );
+    }
+
+    let etag = etag::generate(&file.id, file.updated_at, &variant);
+    // HTTP dates only have second precision.
+    let last_modified = file.created_at.map(|date| {
+        HttpDate::from(UNIX_EPOCH + Duration::from_secs(date.timestamp_millis() as u64 / 1000))
+    });
+
+    // If-Modified-Since is only considered without If-None-Match.
+    let not_modified = if let Some(header) = req
+        .headers()
+        .get("If-None-Match")
+        .and_then(|value| value.to_str().ok())
+    {
+        etag::matches(header, &etag)
+    } else if let (Some(header), Some(last_modified)) = (
+        req.headers()
+            .get("If-Modified-Since")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<HttpDate>().ok()),
+        last_modified,
+    ) {
+        SystemTime::from(last_modified) <= SystemTime::from(header)
+    } else {
+        false
     };
 
-    Ok(HttpResponse::Ok()
+    if not_modified {
+        let mut response = HttpResponse::NotModified();
+        if let Some(last_modified) = last_modified {
+            response.insert_header(("Last-Modified", last_modified));
+        }
+
+        return Ok(response
+            .insert_header(("ETag", etag))
+            .insert_header((
+                "Cache-Control",
+                Config::global().cache_control(&file.tag, &file.content_type),
+            ))
+            .insert_header(("Vary", "Accept, Accept-Encoding, DPR, Viewport-Width"))
+            .finish());
+    }
+
+    // Original files are streamed, ranges only make sense for them.
+    // Only images and previewed documents are ever transformed, so anything else ignores the
+    // resize parameters rather than being read into memory, which keeps large video and audio seekable.
+    if resize.is_empty() || !transformable(&file.metadata) {
+        let size = file.size as u64;
+        let range = req
+            .headers()
+            .get("Range")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_range(value, size));
+
+        let (mut response, offset, length) = match range {
+            Some(ByteRange::Satisfiable(start, end)) => {
+                let mut response = HttpResponse::PartialContent();
+                response
+                    .insert_header(("Content-Range", format!("bytes {}-{}/{}", start, end, size)));
+
+                // Content-Range refers to the uncompressed bytes.
+                response.insert_header(("Content-Encoding", "identity"));
+                (response, start, end - start + 1)
+            }
+            Some(ByteRange::Unsatisfiable) => {
+                return Ok(HttpResponse::RangeNotSatisfiable()
+                    .insert_header(("Content-Range", format!("bytes */{}", size)))
+                    .finish())
+            }
+            None => (HttpResponse::Ok(), 0, size),
+        };
+
+        let stream = stream_file(&file, offset, length).await.map_err(|err| {
+            tracing::error!("Failed to stream {}. {}", file.id, err);
+            err
+        })?;
+
+        // Players fetch media in many ranges, only count the first.
+        if offset == 0 {
+            crate::stats::record_download(&file.id);
+        }
+
+        if let Some(last_modified) = last_modified {
+            response.insert_header(("Last-Modified", last_modified));
+        }
+
+        if skip_compression(&file.content_type, size) {
+            response.insert_header(("Content-Encoding", "identity"));
+        }
+
+        response
+            .insert_header((
+                "Content-Disposition",
+                disposition(tag.1, &file.content_type, filename.as_deref()),
+            ))
+            .insert_header((
+                "Cache-Control",
+                Config::global().cache_control(&file.tag, &file.content_type),
+            ))
+            .insert_header(("Accept-Ranges", "bytes"))
+            .insert_header(("ETag", etag))
+            .insert_header(("Vary", "Accept-Encoding"))
+            .content_type(file.content_type);
+
+        insert_extra_headers(&mut response, tag.1);
+        return Ok(response.body(AnyBody::from_message(SizedStream::new(length, stream))));
+    }
+
+    let (contents, content_type) = fetch_file(&file, Some(resize), negotiated).await?;
+    crate::stats::record_download(&file.id);
+    let content_type = content_type.unwrap_or(file.content_type);
+    let diposition = disposition(tag.1, &content_type, filename.as_deref());
+
+    let mut response = HttpResponse::Ok();
+    if let Some(last_modified) = last_modified {
+        response.insert_header(("Last-Modified", last_modified));
+    }
+
+    if skip_compression(&content_type, contents.len() as u64) {
+        response.insert_header(("Content-Encoding", "identity"));
+    }
+
+    response
         .insert_header(("Content-Disposition", diposition))
-        .insert_header(("Cache-Control", crate::CACHE_CONTROL))
-        .content_type(content_type)
-        .body(contents))
+        .insert_header((
+            "Cache-Control",
+            Config::global().cache_control(&file.tag, &content_type),
+        ))
+        .insert_header(("ETag", etag))
+        .insert_header(("Vary", "Accept, Accept-Encoding, DPR, Viewport-Width"))
+        .content_type(content_type);
+
+    insert_extra_headers(&mut response, tag.1);
+    Ok(response.body(contents))
+}
+
+/// Add the tag's `extra_headers`, which were checked against reserved names on startup.
+fn insert_extra_headers(response: &mut HttpResponseBuilder, tag: &Tag) {
+    for (name, value) in &tag.extra_headers {
+        response.insert_header((name.as_str(), value.as_str()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid_png(width: u32, height: u32, colour: Rgba<u8>) -> Vec<u8> {
+        let mut bytes = vec![];
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, colour))
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageOutputFormat::Png)
+            .unwrap();
+
+        bytes
+    }
+
+    #[test]
+    fn blurs_a_single_pixel() {
+        Config::init_for_tests();
+
+        let transform = Transform {
+            blur: Some(5.0),
+            ..Default::default()
+        };
+
+        let (bytes, content_type) =
+            try_resize(solid_png(1, 1, Rgba([200, 30, 60, 255])), 1, 1, transform).unwrap();
+
+        assert_eq!(content_type, "image/webp");
+        let decoded = webp::Decoder::new(&bytes).decode().unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (1, 1));
+    }
+
+    #[test]
+    fn contain_defaults_to_white_or_transparent() {
+        Config::init_for_tests();
+
+        let contain = || Transform {
+            fit: Some(Fit::Contain { background: None }),
+            ..Default::default()
+        };
+
+        let corner = |bytes: Vec<u8>| {
+            let decoded = webp::Decoder::new(&bytes).decode().unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (4, 4));
+            let channels = if decoded.is_alpha() { 4 } else { 3 };
+            decoded[..channels].to_vec()
+        };
+
+        let mut opaque = vec![];
+        DynamicImage::ImageRgb8(image::RgbImage::from_pixel(4, 2, image::Rgb([200, 30, 60])))
+            .write_to(&mut Cursor::new(&mut opaque), image::ImageOutputFormat::Png)
+            .unwrap();
+
+        let (bytes, _) = try_resize(opaque, 4, 4, contain()).unwrap();
+        assert_eq!(corner(bytes), [255, 255, 255]);
+
+        let transparent = solid_png(4, 2, Rgba([200, 30, 60, 100]));
+        let (bytes, _) = try_resize(transparent, 4, 4, contain()).unwrap();
+        assert_eq!(corner(bytes)[3], 0);
+    }
+
+    /// A JPEG which says where it was taken.
+    fn jpeg_with_location() -> Vec<u8> {
+        use exif::experimental::Writer;
+        use exif::{Field, In, Value};
+
+        let fields = [
+            Field {
+                tag: exif::Tag::GPSLatitudeRef,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"N".to_vec()]),
+            },
+            Field {
+                tag: exif::Tag::GPSLatitude,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![(51, 1).into(), (30, 1).into(), (26, 1).into()]),
+            },
+        ];
+
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+
+        let mut tiff = Cursor::new(vec![]);
+        writer.write(&mut tiff, false).unwrap();
+
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend(tiff.into_inner());
+
+        let mut jpeg = vec![];
+        DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            4,
+            4,
+            image::Rgb([90, 120, 150]),
+        ))
+        .write_to(
+            &mut Cursor::new(&mut jpeg),
+            image::ImageOutputFormat::Jpeg(90),
+        )
+        .unwrap();
+
+        // Right after the start of image marker, where cameras put it.
+        let mut with_exif = jpeg[..2].to_vec();
+        with_exif.extend_from_slice(&[0xFF, 0xE1]);
+        with_exif.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+        with_exif.extend(app1);
+        with_exif.extend_from_slice(&jpeg[2..]);
+        with_exif
+    }
+
+    #[test]
+    fn serves_webp_without_location() {
+        Config::init_for_tests();
+
+        let jpeg = jpeg_with_location();
+        let exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(&jpeg))
+            .unwrap();
+        assert!(exif
+            .get_field(exif::Tag::GPSLatitude, exif::In::PRIMARY)
+            .is_some());
+
+        let (bytes, content_type) = try_resize(jpeg, 2, 2, Transform::default()).unwrap();
+        assert_eq!(content_type, "image/webp");
+
+        let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|window| window == needle);
+        assert!(!contains(b"EXIF") && !contains(b"Exif") && !contains(b"XMP "));
+        assert!(exif::Reader::new()
+            .read_from_container(&mut Cursor::new(&bytes))
+            .is_err());
+    }
+
+    /// Store a solid PNG in the test tag, returning the document it would have.
+    fn stored_png(id: &str, width: u32, height: u32) -> crate::db::File {
+        let bytes = solid_png(width, height, Rgba([120, 80, 40, 255]));
+        let path = crate::util::variables::get_local_path("test", id);
+        crate::storage::write_atomic(&path, &bytes).unwrap();
+
+        serde_json::from_value(serde_json::json!({
+            "_id": id,
+            "tag": "test",
+            "filename": format!("{}.png", id),
+            "metadata": { "type": "Image", "width": width, "height": height },
+            "content_type": "image/png",
+            "size": bytes.len(),
+        }))
+        .unwrap()
+    }
+
+    /// Fetch a file as it would be served, without negotiating a format.
+    fn fetch(file: &crate::db::File, resize: Resize) -> Result<Vec<u8>, Error> {
+        actix_web::rt::System::new()
+            .block_on(fetch_file(file, Some(resize), None))
+            .map(|(bytes, _)| bytes)
+    }
+
+    /// Sizes around the edges of every range a dimension passes through.
+    const EDGE_SIZES: [isize; 12] = [
+        isize::MIN,
+        -1,
+        0,
+        1,
+        15,
+        16,
+        17,
+        256,
+        257,
+        u32::MAX as isize + 1,
+        isize::MAX - 1,
+        isize::MAX,
+    ];
+
+    #[test]
+    fn never_panics_for_any_size() {
+        Config::init_for_tests();
+        let file = stored_png("any-size", 16, 16);
+        let system = actix_web::rt::System::new();
+
+        // Panics in the resize are caught by the blocking pool and reported as BlockingError.
+        let check = |resize: Resize| {
+            let description = format!("{:?}", resize);
+            let result = system.block_on(fetch_file(&file, Some(resize), None));
+            assert!(
+                !matches!(result, Err(Error::BlockingError)),
+                "{} panicked",
+                description
+            );
+        };
+
+        for &value in &EDGE_SIZES {
+            check(Resize {
+                size: Some(value),
+                ..Default::default()
+            });
+            check(Resize {
+                max_side: Some(value),
+                ..Default::default()
+            });
+            check(Resize {
+                width: Some(value),
+                ..Default::default()
+            });
+            check(Resize {
+                height: Some(value),
+                ..Default::default()
+            });
+
+            for &other in &EDGE_SIZES {
+                for fit in [None, Some("cover"), Some("contain")] {
+                    check(Resize {
+                        width: Some(value),
+                        height: Some(other),
+                        fit: fit.map(str::to_string),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        // Then a fixed sequence of arbitrary values, so failures can be reproduced.
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as isize
+        };
+
+        for _ in 0..200 {
+            let fit = ["cover", "contain", "fill"][next().rem_euclid(3) as usize];
+            check(Resize {
+                size: Some(next()).filter(|value| value % 4 == 0),
+                max_side: Some(next()).filter(|value| value % 4 == 0),
+                width: Some(next()),
+                height: Some(next()),
+                fit: Some(fit.to_string()),
+                ..Default::default()
+            });
+        }
+    }
+
+    #[test]
+    fn caps_huge_requests() {
+        let config = Config::init_for_tests();
+        let max = config.max_resize_dimension;
+        let file = stored_png("huge-request", 16, 16);
+
+        for fit in [None, Some("cover"), Some("contain"), Some("fill")] {
+            let bytes = fetch(
+                &file,
+                Resize {
+                    width: Some(99999),
+                    height: Some(99999),
+                    fit: fit.map(str::to_string),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            let decoded = webp::Decoder::new(&bytes).decode().unwrap();
+            // Without fitting, images are never scaled up past their own size.
+            let expected = if fit.is_some() { max } else { 16 };
+            assert_eq!((decoded.width(), decoded.height()), (expected, expected));
+        }
+    }
+
+    #[test]
+    fn falls_back_to_png_without_webp_support() {
+        Config::init_for_tests();
+
+        let accept = "image/png,image/jpeg;q=0.8";
+        assert_eq!(negotiate_format(Some(accept)), Some(OutputFormat::PNG));
+        assert_eq!(negotiate_format(None), None);
+        assert_eq!(negotiate_format(Some("image/avif,image/webp;q=0.9")), None);
+
+        // Ranges count too, unless something more specific refuses WebP.
+        assert_eq!(negotiate_format(Some("*/*")), None);
+        assert_eq!(negotiate_format(Some("image/png,image/*;q=0.8")), None);
+        assert_eq!(
+            negotiate_format(Some("image/webp;q=0")),
+            Some(OutputFormat::PNG)
+        );
+        assert_eq!(
+            negotiate_format(Some("image/*,image/webp; q=0")),
+            Some(OutputFormat::PNG)
+        );
+        assert_eq!(
+            negotiate_format(Some("image/*;q=0,*/*")),
+            Some(OutputFormat::PNG)
+        );
+
+        let transform = Transform {
+            format: negotiate_format(Some(accept)),
+            ..Default::default()
+        };
+
+        let (bytes, content_type) =
+            try_resize(solid_png(4, 4, Rgba([10, 20, 30, 255])), 2, 2, transform).unwrap();
+        assert_eq!(content_type, "image/png");
+        assert_eq!(
+            image::guess_format(&bytes).unwrap(),
+            image::ImageFormat::Png
+        );
+    }
+
+    #[cfg(feature = "heif")]
+    #[test]
+    fn decodes_a_synthetic_heif() {
+        use libheif_rs::{
+            Channel, ColorSpace, CompressionFormat, EncoderQuality, HeifContext, Image, LibHeif,
+            RgbChroma,
+        };
+
+        let mut image = Image::new(8, 8, ColorSpace::Rgb(RgbChroma::Rgb)).unwrap();
+        image.create_plane(Channel::Interleaved, 8, 8, 8).unwrap();
+
+        let planes = image.planes_mut();
+        let plane = planes.interleaved.unwrap();
+        for row in plane.data.chunks_mut(plane.stride).take(8) {
+            for pixel in row[..8 * 3].chunks_mut(3) {
+                pixel.copy_from_slice(&[40, 160, 90]);
+            }
+        }
+
+        let lib = LibHeif::new();
+        let mut context = HeifContext::new().unwrap();
+        let mut encoder = lib.encoder_for_format(CompressionFormat::Hevc).unwrap();
+        encoder.set_quality(EncoderQuality::LossLess).unwrap();
+        context.encode_image(&image, &mut encoder, None).unwrap();
+        let heif = context.write_to_bytes().unwrap();
+
+        assert!(is_heif(&heif));
+        let decoded = decode_image(heif).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (8, 8));
+    }
 }
@@ -0,0 +1,100 @@
+use crate::config::get_tag;
+use crate::db::{find_file, get_collection};
+use crate::util::auth::Authorized;
+use crate::util::result::Error;
+use crate::util::signing;
+
+use actix_web::{web::Query, HttpRequest, HttpResponse};
+use futures::StreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::options::FindOptions;
+use serde::Deserialize;
+use serde_json::json;
+
+const DEFAULT_THRESHOLD: u32 = 10;
+const MAX_RESULTS: usize = 100;
+
+#[derive(Deserialize, Debug)]
+pub struct SimilarOptions {
+    threshold: Option<u32>,
+}
+
+fn parse_hash(hash: &str) -> Option<u64> {
+    u64::from_str_radix(hash, 16).ok()
+}
+
+/// Images in the same tag which look like this one, closest first.
+///
+/// MongoDB can't compare hashes by Hamming distance, so every hashed image
+/// in the tag is compared here instead.
+pub async fn get(
+    req: HttpRequest,
+    _: Authorized,
+    options: Query<SimilarOptions>,
+) -> Result<HttpResponse, Error> {
+    let tag = get_tag(&req)?;
+    let tag_id = tag.0.clone();
+
+    let threshold = options.threshold.unwrap_or(DEFAULT_THRESHOLD);
+    if threshold > 64 {
+        return Err(Error::BadRequest(
+            "threshold must be between 0 and 64".to_string(),
+        ));
+    }
+
+    let id = req.match_info().query("filename");
+    signing::check_request(&req, tag.1, id)?;
+
+    let file = find_file(id, tag).await?;
+    if let Some(true) = file.deleted {
+        return Err(Error::NotFound);
+    }
+
+    let hash = file
+        .phash
+        .as_deref()
+        .and_then(parse_hash)
+        .ok_or_else(|| Error::BadRequest("file has no perceptual hash".to_string()))?;
+
+    let mut cursor = get_collection("attachments")
+        .clone_with_type::<Document>()
+        .find(
+            doc! {
+                "tag": &tag_id,
+                "_id": { "$ne": &file.id },
+                "phash": { "$exists": true },
+                "deleted": { "$ne": true }
+            },
+            FindOptions::builder()
+                .projection(doc! { "_id": 1, "phash": 1 })
+                .build(),
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    let mut similar = vec![];
+    while let Some(document) = cursor.next().await {
+        let document = document.map_err(|_| Error::DatabaseError)?;
+        let other = match document.get_str("phash").ok().and_then(parse_hash) {
+            Some(other) => other,
+            None => continue,
+        };
+
+        let distance = (hash ^ other).count_ones();
+        if distance <= threshold {
+            if let Ok(id) = document.get_str("_id") {
+                similar.push((id.to_string(), distance));
+            }
+        }
+    }
+
+    similar.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+    similar.truncate(MAX_RESULTS);
+
+    let similar: Vec<_> = similar
+        .into_iter()
+        .map(|(id, distance)| json!({ "id": id, "distance": distance }))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(similar))
+}
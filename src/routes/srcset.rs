@@ -0,0 +1,116 @@
+use crate::config::get_tag;
+use crate::db::{find_file, Metadata};
+use crate::util::auth::Authorized;
+use crate::util::result::Error;
+use crate::util::signing;
+
+use actix_web::{web::Query, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use std::cmp;
+
+const MAX_WIDTHS: usize = 10;
+
+#[derive(Deserialize, Debug)]
+pub struct SrcsetOptions {
+    widths: Option<String>,
+}
+
+/// URLs of an image at several widths, for use in `<img srcset>`.
+///
+/// Nothing is resized here, widths are only clamped to the original.
+/// On tags with signed URLs, each URL carries the token this was requested with.
+pub async fn get(
+    req: HttpRequest,
+    _: Authorized,
+    options: Query<SrcsetOptions>,
+) -> Result<HttpResponse, Error> {
+    let tag = get_tag(&req)?;
+    let tag_id = tag.0.clone();
+
+    let id = req.match_info().query("filename");
+    signing::check_request(&req, tag.1, id)?;
+
+    // Tokens cover every size of a file, so the same one works for each URL.
+    let token = match tag.1.signed_url {
+        Some(_) => signing::request_token(&req).map(|token| format!("&token={}", token)),
+        None => None,
+    }
+    .unwrap_or_default();
+
+    let file = find_file(id, tag).await?;
+    if let Some(true) = file.deleted {
+        return Err(Error::NotFound);
+    }
+
+    let original_width = match file.metadata {
+        Metadata::Image { width, .. } => width,
+        _ => return Err(Error::BadRequest("file is not an image".to_string())),
+    };
+
+    let invalid = || Error::BadRequest("widths must be a list of positive numbers".to_string());
+    let requested = options
+        .widths
+        .as_deref()
+        .ok_or_else(|| Error::BadRequest("widths is required".to_string()))?
+        .split(',')
+        .map(|width| match width.trim().parse::<isize>() {
+            Ok(width) if width > 0 => Ok(width),
+            _ => Err(invalid()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if requested.len() > MAX_WIDTHS {
+        return Err(Error::BadRequest(format!(
+            "at most {} widths can be requested",
+            MAX_WIDTHS
+        )));
+    }
+
+    // Images are never scaled up, so larger widths would all be the same.
+    let mut widths: Vec<isize> = requested
+        .into_iter()
+        .map(|width| cmp::min(width, original_width))
+        .collect();
+
+    widths.sort_unstable();
+    widths.dedup();
+
+    let sources: Vec<(isize, String)> = widths
+        .into_iter()
+        .map(|width| {
+            // Pre-generated thumbnails are served as-is when asked for by their exact size.
+            let variant = file
+                .variants
+                .iter()
+                .flatten()
+                .find(|variant| variant.width as isize == width);
+
+            let url = match variant {
+                Some(variant) => format!(
+                    "/{}/{}?width={}&height={}{}",
+                    tag_id, file.id, variant.width, variant.height, token
+                ),
+                None => format!("/{}/{}?width={}{}", tag_id, file.id, width, token),
+            };
+
+            (width, url)
+        })
+        .collect();
+
+    let srcset = sources
+        .iter()
+        .map(|(width, url)| format!("{} {}w", url, width))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let sources: Vec<_> = sources
+        .into_iter()
+        .map(|(width, url)| json!({ "width": width, "url": url }))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "sources": sources,
+        "srcset": srcset,
+    })))
+}
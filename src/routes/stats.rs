@@ -0,0 +1,28 @@
+use crate::config::get_tag;
+use crate::db::find_file;
+use crate::util::auth::Authorized;
+use crate::util::result::Error;
+
+use actix_web::{HttpRequest, HttpResponse};
+use serde_json::json;
+
+pub async fn get(req: HttpRequest, _: Authorized) -> Result<HttpResponse, Error> {
+    let tag = get_tag(&req)?;
+
+    let id = req.match_info().query("filename");
+    let file = find_file(id, tag).await?;
+
+    if let Some(true) = file.deleted {
+        return Err(Error::NotFound);
+    }
+
+    let download_count =
+        file.download_count.unwrap_or_default() as u64 + crate::stats::pending_downloads(&file.id);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "download_count": download_count,
+        "created_at": file.created_at.map(|date| date.timestamp_millis()),
+        "file_size": file.size,
+        "frame_count": file.metadata.frame_count(),
+    })))
+}
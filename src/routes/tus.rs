@@ -0,0 +1,334 @@
+use super::upload::store;
+use crate::config::{get_tag, Config};
+use crate::db::{get_upload_chunk_collection, get_upload_collection, Upload, UploadChunk};
+use crate::util::auth::Authorized;
+use crate::util::result::Error;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::{StreamExt, TryStreamExt};
+use mongodb::bson::{doc, spec::BinarySubtype, Binary, DateTime};
+use nanoid::nanoid;
+use std::time::{Duration, SystemTime};
+
+/// The only version of the tus protocol supported.
+const TUS_VERSION: &str = "1.0.0";
+
+/// Content type required for the body of PATCH requests.
+const PATCH_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// Most data kept in one chunk, well within MongoDB's 16 MiB document limit.
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Clients must say which version of the protocol they speak.
+fn check_version(req: &HttpRequest) -> Result<(), HttpResponse> {
+    match req.headers().get("Tus-Resumable") {
+        Some(version) if version == TUS_VERSION => Ok(()),
+        _ => Err(HttpResponse::PreconditionFailed()
+            .insert_header(("Tus-Version", TUS_VERSION))
+            .finish()),
+    }
+}
+
+fn header_u64(req: &HttpRequest, name: &str) -> Option<u64> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Read the filename from `Upload-Metadata`, a list of keys and base64 values.
+fn metadata_filename(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get("Upload-Metadata")?
+        .to_str()
+        .ok()?
+        .split(',')
+        .find_map(|pair| {
+            let mut parts = pair.trim().splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some("filename"), Some(value)) => base64::decode(value)
+                    .ok()
+                    .and_then(|bytes| String::from_utf8(bytes).ok()),
+                _ => None,
+            }
+        })
+}
+
+fn expires_at() -> DateTime {
+    DateTime::from_system_time(SystemTime::now() + Duration::from_secs(Config::global().tus_expiry))
+}
+
+async fn find_upload(tag: &str, id: &str) -> Result<Upload, Error> {
+    get_upload_collection()
+        .find_one(doc! { "_id": id, "tag": tag }, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?
+        .ok_or(Error::NotFound)
+}
+
+async fn save_chunk(upload: &str, segment: &str, index: i32, data: Vec<u8>) -> Result<(), Error> {
+    let chunk = UploadChunk {
+        id: nanoid!(),
+        upload: upload.to_string(),
+        segment: segment.to_string(),
+        index,
+        data: Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: data,
+        },
+        expires_at: expires_at(),
+    };
+
+    get_upload_chunk_collection()
+        .insert_one(chunk, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    Ok(())
+}
+
+/// Save a request's body as chunks of `segment`, returning how much was received.
+async fn save_segment(
+    upload: &str,
+    segment: &str,
+    remaining: u64,
+    payload: &mut web::Payload,
+) -> Result<u64, Error> {
+    let mut received = 0;
+    let mut pending = vec![];
+    let mut index = 0;
+
+    while let Some(chunk) = payload.next().await {
+        // Keep whatever arrived before the connection dropped, so it can be resumed.
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) => break,
+        };
+
+        received += chunk.len() as u64;
+        if received > remaining {
+            return Err(Error::BadRequest(
+                "body is longer than Upload-Length".to_string(),
+            ));
+        }
+
+        pending.extend_from_slice(&chunk);
+        if pending.len() >= CHUNK_SIZE {
+            save_chunk(upload, segment, index, std::mem::take(&mut pending)).await?;
+            index += 1;
+        }
+    }
+
+    if !pending.is_empty() {
+        save_chunk(upload, segment, index, pending).await?;
+    }
+
+    Ok(received)
+}
+
+/// Remove the chunks of a request which didn't make it into the upload.
+async fn discard_segment(upload: &str, segment: &str) {
+    get_upload_chunk_collection()
+        .delete_many(doc! { "upload": upload, "segment": segment }, None)
+        .await
+        .ok();
+}
+
+/// Put the data received for an upload back together, in order.
+async fn assemble(upload: &Upload) -> Result<Vec<u8>, Error> {
+    let mut chunks: Vec<UploadChunk> = get_upload_chunk_collection()
+        .find(
+            doc! { "upload": &upload.id, "segment": { "$in": &upload.segments } },
+            None,
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?
+        .try_collect()
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    chunks.sort_by_key(|chunk| {
+        let segment = upload.segments.iter().position(|id| *id == chunk.segment);
+        (segment, chunk.index)
+    });
+
+    let mut buf = Vec::with_capacity(upload.length as usize);
+    for chunk in chunks {
+        buf.extend(chunk.data.bytes);
+    }
+
+    // Chunks which expired early would otherwise silently leave a gap.
+    if buf.len() as i64 != upload.length {
+        return Err(Error::IOError);
+    }
+
+    Ok(buf)
+}
+
+/// Describe what this server supports.
+pub async fn options(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let (_, tag) = get_tag(&req)?;
+    Ok(HttpResponse::NoContent()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", "creation"))
+        .insert_header(("Tus-Max-Size", tag.max_file_size().to_string()))
+        .finish())
+}
+
+/// Create an upload, its data is then sent with PATCH requests.
+pub async fn create(req: HttpRequest, _: Authorized) -> Result<HttpResponse, Error> {
+    if let Err(response) = check_version(&req) {
+        return Ok(response);
+    }
+
+    let (tag_id, tag) = get_tag(&req)?;
+    let length = header_u64(&req, "Upload-Length")
+        .ok_or_else(|| Error::BadRequest("Upload-Length is required".to_string()))?;
+
+    let max_size = tag.max_file_size();
+    if length > max_size as u64 {
+        return Err(Error::FileTooLarge { max_size });
+    }
+
+    let upload = Upload {
+        id: nanoid!(42),
+        tag: tag_id.clone(),
+        filename: metadata_filename(&req).unwrap_or_else(|| "file".to_string()),
+        length: length as i64,
+        offset: 0,
+        segments: vec![],
+        expires_at: expires_at(),
+    };
+
+    get_upload_collection()
+        .insert_one(&upload, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    Ok(HttpResponse::Created()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Location", format!("/{}/tus/{}", tag_id, upload.id)))
+        .finish())
+}
+
+/// Report how much of an upload has been received.
+pub async fn head(req: HttpRequest, _: Authorized) -> Result<HttpResponse, Error> {
+    if let Err(response) = check_version(&req) {
+        return Ok(response);
+    }
+
+    let (tag_id, _) = get_tag(&req)?;
+    let upload = find_upload(&tag_id, req.match_info().query("id")).await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Upload-Offset", upload.offset.to_string()))
+        .insert_header(("Upload-Length", upload.length.to_string()))
+        .insert_header(("Cache-Control", "no-store"))
+        .finish())
+}
+
+/// Append data to an upload, storing the file once all of it has arrived.
+pub async fn patch(
+    req: HttpRequest,
+    _: Authorized,
+    mut payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    if let Err(response) = check_version(&req) {
+        return Ok(response);
+    }
+
+    let content_type = req
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok());
+
+    if content_type != Some(PATCH_CONTENT_TYPE) {
+        return Err(Error::UnsupportedMediaType {
+            allowed: vec![PATCH_CONTENT_TYPE.to_string()],
+        });
+    }
+
+    let (tag_id, tag) = get_tag(&req)?;
+    let upload = find_upload(&tag_id, req.match_info().query("id")).await?;
+    let offset = header_u64(&req, "Upload-Offset")
+        .ok_or_else(|| Error::BadRequest("Upload-Offset is required".to_string()))?;
+
+    if offset != upload.offset as u64 {
+        return Ok(HttpResponse::Conflict()
+            .insert_header(("Tus-Resumable", TUS_VERSION))
+            .finish());
+    }
+
+    // Each request's data is kept apart until it's recorded as the next segment,
+    // so requests racing for the same offset can't mix their bytes together.
+    let segment = nanoid!();
+    let remaining = upload.length as u64 - offset;
+    let received = match save_segment(&upload.id, &segment, remaining, &mut payload).await {
+        Ok(received) => received,
+        Err(err) => {
+            discard_segment(&upload.id, &segment).await;
+            return Err(err);
+        }
+    };
+
+    let new_offset = (offset + received) as i64;
+    let expires_at = expires_at();
+    let updated = get_upload_collection()
+        .update_one(
+            doc! { "_id": &upload.id, "offset": upload.offset },
+            doc! {
+                "$set": { "offset": new_offset, "expires_at": expires_at },
+                "$push": { "segments": &segment }
+            },
+            None,
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    // Another request appended to the upload at the same time.
+    if updated.matched_count == 0 {
+        discard_segment(&upload.id, &segment).await;
+        return Ok(HttpResponse::Conflict()
+            .insert_header(("Tus-Resumable", TUS_VERSION))
+            .finish());
+    }
+
+    // Earlier chunks are kept for as long as the upload is.
+    get_upload_chunk_collection()
+        .update_many(
+            doc! { "upload": &upload.id },
+            doc! { "$set": { "expires_at": expires_at } },
+            None,
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    let mut response = HttpResponse::NoContent();
+    response
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Upload-Offset", new_offset.to_string()));
+
+    if new_offset == upload.length {
+        let mut upload = upload;
+        upload.segments.push(segment);
+        let buf = assemble(&upload).await;
+
+        // Uploads which fail validation can't be retried, so clean up either way.
+        get_upload_collection()
+            .delete_one(doc! { "_id": &upload.id }, None)
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+
+        get_upload_chunk_collection()
+            .delete_many(doc! { "upload": &upload.id }, None)
+            .await
+            .ok();
+
+        let file = store(&tag_id, tag, upload.filename, buf?, None).await?;
+        response.insert_header(("X-Autumn-File-Id", file.id));
+    }
+
+    Ok(response.finish())
+}
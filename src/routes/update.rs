@@ -0,0 +1,247 @@
+use crate::config::{get_tag, ContentType, Tag};
+use crate::db::{find_file, get_collection, File, Metadata};
+use crate::util::auth::Authorized;
+use crate::util::result::Error;
+
+use actix_web::{web::Json, HttpRequest, HttpResponse};
+use mongodb::bson::{doc, to_bson, DateTime, Document};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Fields which may be changed after upload, anything else is rejected.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FilePatch {
+    content_type: Option<String>,
+    meta: Option<BTreeMap<String, serde_json::Value>>,
+}
+
+/// Types browsers run scripts in. Files can keep them, but can't be relabelled as them,
+/// otherwise any text could be served as a page from our origin.
+const ACTIVE_TYPES: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/xml",
+    "application/xml",
+    "text/javascript",
+    "application/javascript",
+];
+
+/// Check a file may be relabelled, with a type its tag would accept at upload
+/// and which still describes its contents.
+fn check_content_type(tag: &Tag, file: &File, mime: &mime::Mime) -> Result<(), Error> {
+    let essence = mime.essence_str();
+    if !tag.allows_mime_type(essence) {
+        return Err(Error::UnsupportedMediaType {
+            allowed: tag.allowed_mime_types.clone(),
+        });
+    }
+
+    let kind = match &file.metadata {
+        Metadata::Image { .. } => Some(mime::IMAGE),
+        Metadata::Video { .. } => Some(mime::VIDEO),
+        Metadata::Audio => Some(mime::AUDIO),
+        _ => None,
+    };
+
+    let restricted = match &tag.restrict_content_type {
+        Some(ContentType::Image) => Some(mime::IMAGE),
+        Some(ContentType::Video) => Some(mime::VIDEO),
+        Some(ContentType::Audio) => Some(mime::AUDIO),
+        None => None,
+    };
+
+    let unchanged = file.content_type.eq_ignore_ascii_case(essence);
+    if kind
+        .iter()
+        .chain(restricted.iter())
+        .any(|kind| mime.type_() != *kind)
+        || (ACTIVE_TYPES.contains(&essence) && !unchanged)
+    {
+        return Err(Error::FileTypeNotAllowed);
+    }
+
+    Ok(())
+}
+
+pub async fn patch(
+    req: HttpRequest,
+    _: Authorized,
+    data: Json<FilePatch>,
+) -> Result<HttpResponse, Error> {
+    let tag = get_tag(&req)?;
+
+    // Without a token anyone could change any file.
+    if tag.1.auth_token.is_none() {
+        return Err(Error::Forbidden);
+    }
+
+    let id = req.match_info().query("filename");
+    let config = tag.1;
+    let file = find_file(id, tag).await?;
+
+    if let Some(true) = file.deleted {
+        return Err(Error::NotFound);
+    }
+
+    let data = data.into_inner();
+    let mut set = Document::new();
+
+    if let Some(content_type) = data.content_type {
+        let mime: mime::Mime = content_type
+            .parse()
+            .map_err(|_| Error::BadRequest("content_type is not a valid MIME type".to_string()))?;
+        check_content_type(config, &file, &mime)?;
+        set.insert("content_type", mime.to_string());
+    }
+
+    // Merge into the existing bag rather than replacing it.
+    for (key, value) in data.meta.unwrap_or_default() {
+        if key.is_empty() || key.contains('.') || key.starts_with('$') {
+            return Err(Error::BadRequest(format!(
+                "meta key {:?} is not allowed",
+                key
+            )));
+        }
+
+        let value = to_bson(&value)
+            .map_err(|_| Error::BadRequest("meta value could not be stored".to_string()))?;
+        set.insert(format!("meta.{}", key), value);
+    }
+
+    if set.is_empty() {
+        return Err(Error::MissingData);
+    }
+
+    set.insert("updated_at", DateTime::now());
+
+    let file = get_collection("attachments")
+        .find_one_and_update(
+            doc! { "_id": &file.id },
+            doc! { "$set": set },
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?
+        .ok_or(Error::NotFound)?;
+
+    Ok(HttpResponse::Ok().json(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, Tag};
+    use crate::db::File;
+    use crate::routes::{serve, update, upload};
+
+    use actix_web::{test, web, App};
+
+    fn file(content_type: &str, metadata: serde_json::Value) -> File {
+        serde_json::from_value(serde_json::json!({
+            "_id": "abcd",
+            "tag": "test",
+            "filename": "file",
+            "metadata": metadata,
+            "content_type": content_type,
+            "size": 5
+        }))
+        .unwrap()
+    }
+
+    fn allowed(tag: &Tag, file: &File, content_type: &str) -> bool {
+        update::check_content_type(tag, file, &content_type.parse().unwrap()).is_ok()
+    }
+
+    #[test]
+    fn refuses_content_types_the_tag_would_not_accept() {
+        let tag = |extra: &str| -> Tag {
+            toml::from_str(&format!("max_size = 1000\n{}", extra)).unwrap()
+        };
+        let text = file("text/plain", serde_json::json!({ "type": "Text" }));
+        let image = file(
+            "image/png",
+            serde_json::json!({ "type": "Image", "width": 1, "height": 1 }),
+        );
+
+        let open = tag("");
+        assert!(allowed(&open, &text, "text/markdown"));
+        assert!(allowed(&open, &image, "image/webp"));
+
+        // Relabelling text as a page would let it run scripts from our origin.
+        assert!(!allowed(&open, &text, "text/html"));
+        assert!(!allowed(&open, &text, "image/svg+xml"));
+        assert!(!allowed(&open, &image, "text/plain"));
+
+        let listed = tag(r#"allowed_mime_types = ["text/plain"]"#);
+        assert!(allowed(&listed, &text, "text/plain"));
+        assert!(!allowed(&listed, &text, "text/markdown"));
+
+        let restricted = tag(r#"restrict_content_type = "Image""#);
+        assert!(!allowed(&restricted, &text, "text/markdown"));
+    }
+
+    #[test]
+    #[ignore = "needs MongoDB at AUTUMN_MONGO_URI"]
+    fn patch_is_reflected_on_get() {
+        actix_web::rt::System::new().block_on(async {
+            let config = Config::init_for_tests();
+            crate::db::connect_for_tests().await;
+
+            let tag = config.tags.get("private").unwrap();
+            let file = upload::store("private", tag, "notes.txt".into(), b"hello".to_vec(), None)
+                .await
+                .unwrap();
+
+            let app = test::init_service(
+                App::new().service(
+                    web::resource("/{tag:[^/]*}/{filename:[^/]*}")
+                        .route(web::get().to(serve::get))
+                        .route(web::patch().to(update::patch)),
+                ),
+            )
+            .await;
+
+            let uri = format!("/private/{}", file.id);
+            let response = test::call_service(
+                &app,
+                test::TestRequest::patch()
+                    .uri(&uri)
+                    .insert_header(("Authorization", "Bearer test-token"))
+                    .set_json(&serde_json::json!({
+                        "content_type": "text/markdown",
+                        "meta": { "author": "someone" }
+                    }))
+                    .to_request(),
+            )
+            .await;
+            assert!(response.status().is_success());
+
+            let response = test::call_service(
+                &app,
+                test::TestRequest::get()
+                    .uri(&uri)
+                    .insert_header(("Authorization", "Bearer test-token"))
+                    .to_request(),
+            )
+            .await;
+            assert!(response.status().is_success());
+            assert_eq!(
+                response.headers().get("Content-Type").unwrap(),
+                "text/markdown"
+            );
+            assert_eq!(test::read_body(response).await, &b"hello"[..]);
+
+            let updated = crate::db::find_file(&file.id, ("private".to_string(), tag))
+                .await
+                .unwrap();
+            assert_eq!(
+                updated.meta.unwrap().get("author"),
+                Some(&serde_json::json!("someone"))
+            );
+        });
+    }
+}
@@ -1,40 +1,61 @@
-use crate::config::{get_tag, Config, ContentType};
+use crate::config::{get_tag, Config, ContentType, Tag};
 use crate::db::*;
+use crate::util::auth::{Authorized, UploadAuthorized};
+use crate::util::etag;
+use crate::util::image::{
+    frame_count, icc_profile, perceptual_hash, strip_webp_metadata, to_srgb, truncate_png,
+};
+use crate::util::metadata::{detect_content_type, determine_video_size, probe};
 use crate::util::result::Error;
-use crate::util::variables::{get_s3_bucket, CLAMD_HOST, LOCAL_STORAGE_PATH, USE_CLAMD, USE_S3};
+use crate::util::upload_token;
+
+#[cfg(feature = "heif")]
+use super::serve::{decode_heif, encode_image, Transform};
 
 use actix_multipart::Multipart;
 use actix_web::{web, HttpRequest, HttpResponse};
-use content_inspector::inspect;
-use ffprobe::ffprobe;
 use futures::{StreamExt, TryStreamExt};
 use image::io::Reader as ImageReader;
 use imagesize;
+use mongodb::bson::doc;
 use nanoid::nanoid;
+use serde::Deserialize;
 use serde_json::json;
-use std::convert::TryInto;
+use sha2::{Digest, Sha256};
+use std::cmp;
+use std::convert::{TryFrom, TryInto};
 use std::io::{Cursor, Read, Write};
 use std::process::Command;
 use tempfile::NamedTempFile;
 
-pub fn determine_video_size(path: &std::path::Path) -> Result<(isize, isize), Error> {
-    let data = ffprobe(path).map_err(|_| Error::ProbeError)?;
-
-    // Take the first valid stream.
-    for stream in data.streams {
-        if let (Some(w), Some(h)) = (stream.width, stream.height) {
-            if let (Ok(w), Ok(h)) = (w.try_into(), h.try_into()) {
-                return Ok((w, h));
-            }
-        }
-    }
+/// Allowance for multipart boundaries and headers in the request body.
+const MULTIPART_OVERHEAD: usize = 16 * 1024;
 
-    Err(Error::ProbeError)
+#[derive(Deserialize)]
+pub struct UploadOptions {
+    /// Delete the file automatically after this many seconds.
+    pub expires_in: Option<u64>,
 }
 
-pub async fn post(req: HttpRequest, mut payload: Multipart) -> Result<HttpResponse, Error> {
-    let config = Config::global();
-    let (tag_id, tag) = get_tag(&req)?;
+/// Read the first field of a multipart upload, returning its filename and contents.
+async fn receive(
+    req: &HttpRequest,
+    tag: &Tag,
+    max_size: usize,
+    payload: &mut Multipart,
+) -> Result<(String, Vec<u8>), Error> {
+    // Reject oversized bodies up front, leaving room for the multipart framing.
+    let content_length = req
+        .headers()
+        .get("Content-Length")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    if let Some(length) = content_length {
+        if length > max_size.saturating_add(MULTIPART_OVERHEAD) as u64 {
+            return Err(Error::FileTooLarge { max_size });
+        }
+    }
 
     if let Ok(Some(mut field)) = payload.try_next().await {
         let content_type = field.content_disposition().ok_or(Error::FailedToReceive)?;
@@ -43,6 +64,15 @@ pub async fn post(req: HttpRequest, mut payload: Multipart) -> Result<HttpRespon
             .ok_or(Error::FailedToReceive)?
             .to_string();
 
+        // Fields without a Content-Type are reported as octet streams,
+        // those are only checked against the sniffed type below.
+        let declared = field.content_type().essence_str();
+        if declared != "application/octet-stream" && !tag.allows_mime_type(declared) {
+            return Err(Error::UnsupportedMediaType {
+                allowed: tag.allowed_mime_types.clone(),
+            });
+        }
+
         // ? Read multipart data into a buffer.
         let mut file_size: usize = 0;
         let mut buf: Vec<u8> = Vec::new();
@@ -50,253 +80,848 @@ pub async fn post(req: HttpRequest, mut payload: Multipart) -> Result<HttpRespon
             let data = chunk.map_err(|_| Error::FailedToReceive)?;
             file_size += data.len();
 
-            if file_size > tag.max_size {
-                return Err(Error::FileTooLarge {
-                    max_size: tag.max_size,
-                });
+            if file_size > max_size {
+                return Err(Error::FileTooLarge { max_size });
             }
 
             buf.append(&mut data.to_vec());
         }
 
-        // ? Find the content-type of the data.
-        let mut content_type = tree_magic::from_u8(&buf);
+        Ok((filename, buf))
+    } else {
+        Err(Error::MissingData)
+    }
+}
+
+pub async fn post(
+    req: HttpRequest,
+    auth: UploadAuthorized,
+    options: web::Query<UploadOptions>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    let (tag_id, tag) = get_tag(&req)?;
+    let mut max_size = tag.max_file_size();
 
-        // Intercept known file extensions with certain content types
-        if content_type == "application/zip" && filename.to_lowercase().ends_with(".apk") {
-            content_type = "application/vnd.android.package-archive".to_string();
+    let claims = auth.0;
+    if let Some(claims) = &claims {
+        if let Some(limit) = claims.max_size_bytes {
+            max_size = cmp::min(max_size, limit);
         }
 
-        if content_type == "application/x-riff" {
-            if filename.to_lowercase().ends_with(".webp") {
-                content_type = "image/webp".to_string();
-            } else if filename.to_lowercase().ends_with(".wav")
-                || filename.to_lowercase().ends_with(".wave")
-            {
-                content_type = "audio/wav".to_string();
-            }
+        upload_token::redeem(claims).await?;
+    }
+
+    let (filename, buf) = receive(&req, tag, max_size, &mut payload).await?;
+    if let Some(claims) = &claims {
+        if !claims.allows_content_type(&detect_content_type(&buf, &filename)) {
+            return Err(Error::FileTypeNotAllowed);
         }
+    }
+
+    let file = store(&tag_id, tag, filename, buf, options.expires_in).await?;
+    Ok(HttpResponse::Ok().json(json!({ "id": file.id })))
+}
 
-        let s = &content_type[..];
-
-        let metadata = match s {
-            /* jpg */ "image/jpeg" |
-            /* png */ "image/png" |
-            /* gif */ "image/gif" |
-            /* webp */ "image/webp"  => {
-                if let Ok(imagesize::ImageSize { width, height }) = imagesize::blob_size(&buf) {
-                    if s == "image/jpeg" || s == "image/png" {
-                        let mut cursor = Cursor::new(buf);
-
-                        // Attempt to extract orientation data.
-                        let exif_reader = exif::Reader::new();
-                        let rotation = match exif_reader.read_from_container(&mut cursor) {
-                            Ok(exif) => {
-                                match exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY) {
-                                    Some(orientation) => {
-                                        match orientation.value.get_uint(0) {
-                                            Some(v @ 1..=8) => v,
-                                            _ => 0
-                                        }
-                                    }
-                                    _ => 0
+/// Generate an ID for a new file in the tag.
+pub fn new_id(tag: &Tag) -> String {
+    if tag.use_ulid {
+        ulid::Ulid::new().to_string()
+    } else {
+        nanoid!(42)
+    }
+}
+
+/// A file's contents once they have been checked and processed for its tag.
+struct Processed {
+    content_type: String,
+    metadata: Metadata,
+    buf: Vec<u8>,
+    color_space_normalized: bool,
+}
+
+/// Contents once re-encoded to strip metadata, or converted to a format browsers can show.
+struct Reencoded {
+    buf: Vec<u8>,
+    content_type: String,
+    color_space_normalized: bool,
+}
+
+/// Re-encode the contents of a file where it's needed before they can be stored.
+/// Describing them is left to `probe`, so uploads and stored files get the same metadata.
+async fn reencode(tag: &Tag, content_type: String, mut buf: Vec<u8>) -> Result<Reencoded, Error> {
+    let config = Config::global();
+    #[allow(unused_mut)]
+    let mut content_type = content_type;
+    let s = &content_type[..];
+    let mut color_space_normalized = false;
+
+    match s {
+        /* jpg */ "image/jpeg" |
+        /* png */ "image/png" |
+        /* gif */ "image/gif" |
+        /* webp */ "image/webp"  => {
+            if let Ok(imagesize::ImageSize { width, height }) = imagesize::blob_size(&buf) {
+                // Checked before decoding, a small file can expand to gigabytes in memory.
+                tag.check_dimensions(
+                    width.try_into().unwrap_or(u32::MAX),
+                    height.try_into().unwrap_or(u32::MAX)
+                )?;
+
+                let is_apng = s == "image/png" && frame_count(&buf, s).unwrap_or(0) > 1;
+                if is_apng {
+                    // Re-encoding would lose the animation, so only remove trailing data.
+                    truncate_png(&mut buf);
+                } else if s == "image/jpeg" || s == "image/png" {
+                    // Read before decoding, the profile is lost once the image is re-encoded.
+                    let icc = if tag.normalize_color_space { icc_profile(&buf, s) } else { None };
+                    let mut cursor = Cursor::new(buf);
+
+                    // Attempt to extract orientation data.
+                    let exif_reader = exif::Reader::new();
+                    let rotation = match exif_reader.read_from_container(&mut cursor) {
+                        Ok(exif) => {
+                            match exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY) {
+                                Some(orientation) => {
+                                    orientation.value.get_uint(0).filter(|v| (1..=8).contains(v)).unwrap_or(0)
                                 }
+                                _ => 0
                             }
-                            _ => 0
-                        };
-
-                        cursor.set_position(0);
-
-                        // Re-encode JPEGs to remove EXIF data.
-                        // Also re-encode PNGs to mitigate CVE-2023-21036
-                        let output_format: image::ImageOutputFormat = if s == "image/jpeg" {
-                            image::ImageOutputFormat::Jpeg(config.jpeg_quality)
-                        } else { // It's a PNG
-                            image::ImageOutputFormat::Png
-                        };
-
-                        let image = ImageReader::new(cursor)
-                            .with_guessed_format()
-                            .map_err(|_| Error::IOError)?
-                            .decode()
-                            .map_err(|_| Error::IOError);
-
-                        let mut bytes: Vec<u8> = Vec::new();
-                        let mut writer = Cursor::new(&mut bytes);
-
-                        // See https://jdhao.github.io/2019/07/31/image_rotation_exif_info/
-                        match &rotation {
-                            2 => { image?.fliph() }
-                            3 => { image?.rotate180() }
-                            4 => { image?.rotate180().fliph() }
-                            5 => { image?.rotate90().fliph() }
-                            6 => { image?.rotate90() }
-                            7 => { image?.rotate270().fliph() }
-                            8 => { image?.rotate270() }
-                            _ => { image? }
                         }
+                        _ => 0
+                    };
+
+                    cursor.set_position(0);
+
+                    // Re-encode JPEGs to remove EXIF data.
+                    // Also re-encode PNGs to mitigate CVE-2023-21036
+                    let output_format: image::ImageOutputFormat = if s == "image/jpeg" {
+                        image::ImageOutputFormat::Jpeg(config.jpeg_quality)
+                    } else { // It's a PNG
+                        image::ImageOutputFormat::Png
+                    };
+
+                    let image = ImageReader::new(cursor)
+                        .with_guessed_format()
+                        .map_err(|_| Error::IOError)?
+                        .decode()
+                        .map_err(|_| Error::IOError);
+
+                    let mut bytes: Vec<u8> = Vec::new();
+                    let mut writer = Cursor::new(&mut bytes);
+
+                    // See https://jdhao.github.io/2019/07/31/image_rotation_exif_info/
+                    let mut image = match &rotation {
+                        2 => { image?.fliph() }
+                        3 => { image?.rotate180() }
+                        4 => { image?.rotate180().fliph() }
+                        5 => { image?.rotate90().fliph() }
+                        6 => { image?.rotate90() }
+                        7 => { image?.rotate270().fliph() }
+                        8 => { image?.rotate270() }
+                        _ => { image? }
+                    };
+
+                    if let Some(converted) = icc.and_then(|icc| to_srgb(&image, &icc)) {
+                        image = converted;
+                        color_space_normalized = true;
+                    }
+
+                    image
                         .write_to(&mut writer, output_format)
                         .map_err(|_| Error::IOError)?;
 
-                        buf = bytes;
-
-                        // Calculate dimensions after rotation.
-                        let (width, height) = match &rotation {
-                            2 | 4 | 5 | 7 => (height, width),
-                            _ => (width, height)
-                        };
-
-                        Metadata::Image {
-                            width: width.try_into().map_err(|_| Error::IOError)?,
-                            height: height.try_into().map_err(|_| Error::IOError)?
-                        }
-                    } else {
-                        // GIFs and WebPs will not be re-encoded.
-                        Metadata::Image {
-                            width: width.try_into().map_err(|_| Error::IOError)?,
-                            height: height.try_into().map_err(|_| Error::IOError)?
-                        }
-                    }
-                } else {
-                    Metadata::File
+                    buf = bytes;
+                } else if s == "image/webp" {
+                    // GIFs and WebPs will not be re-encoded, so only remove the metadata WebPs carry.
+                    strip_webp_metadata(&mut buf);
                 }
             }
-            /*  mp4 */ "video/mp4" |
-            /* webm */ "video/webm" |
-            /*  mov */ "video/quicktime" => {
-                let ext = match s {
-                    "video/mp4" => "mp4",
-                    "video/webm" => "webm",
-                    "video/quicktime" => "mov",
-                    _ => unreachable!()
-                };
-
-                let mut tmp = NamedTempFile::new().map_err(|_| Error::IOError)?;
-                tmp.write_all(&buf).map_err(|_| Error::IOError)?;
-
-                if let Ok(Ok(((width, height), tmp))) = web::block(move || determine_video_size(tmp.path()).map(|t| (t, tmp))).await {
-                    buf = vec![];
-                    let out_tmp = NamedTempFile::new().map_err(|_| Error::IOError)?;
-                    let out_tmp = web::block(move ||
-                        Command::new("ffmpeg")
-                            .args(&[
-                                "-y",                                               // Overwrite the temporary file.
-                                "-i", tmp.path().to_str().ok_or(Error::IOError)?,   // Read the original uploaded file.
-                                "-map_metadata", "-1",                              // Strip any metadata.
-                                "-c:v", "copy", "-c:a", "copy",                     // Copy video / audio data to new file.
-                                "-f", ext,                                          // Select the correct file format.
-                                out_tmp.path().to_str().ok_or(Error::IOError)?])    // Save to new temporary file.
-                            .output()
-                            .map(|_| out_tmp)
-                            .map_err(|_| Error::IOError)
-                    )
+        }
+        #[cfg(feature = "heif")]
+        /* heic */ "image/heic" |
+        /* heif */ "image/heif" => {
+            // Most browsers can't display HEIF, so store it in the configured serve format.
+            let image = decode_heif(&buf).map_err(|_| Error::IOError)?;
+            let (bytes, converted) = encode_image(&image, &Transform::default())
+                .map_err(|_| Error::IOError)?;
+
+            buf = bytes;
+            content_type = converted.to_string();
+        }
+        /*  mp4 */ "video/mp4" |
+        /* webm */ "video/webm" |
+        /*  mov */ "video/quicktime" => {
+            let ext = match s {
+                "video/mp4" => "mp4",
+                "video/webm" => "webm",
+                "video/quicktime" => "mov",
+                _ => unreachable!()
+            };
+
+            let mut tmp = NamedTempFile::new().map_err(|_| Error::IOError)?;
+            tmp.write_all(&buf).map_err(|_| Error::IOError)?;
+
+            // Anything ffprobe can't read is kept as it is, and stored as a plain file.
+            if let Ok(Ok(tmp)) = web::block(move || determine_video_size(tmp.path()).map(|_| tmp)).await {
+                buf = vec![];
+                let out_tmp = NamedTempFile::new().map_err(|_| Error::IOError)?;
+                let out_tmp = web::block(move ||
+                    Command::new("ffmpeg")
+                        .args([
+                            "-y",                                               // Overwrite the temporary file.
+                            "-i", tmp.path().to_str().ok_or(Error::IOError)?,   // Read the original uploaded file.
+                            "-map_metadata", "-1",                              // Strip any metadata.
+                            "-c:v", "copy", "-c:a", "copy",                     // Copy video / audio data to new file.
+                            "-f", ext,                                          // Select the correct file format.
+                            out_tmp.path().to_str().ok_or(Error::IOError)?])    // Save to new temporary file.
+                        .output()
+                        .map(|_| out_tmp)
+                        .map_err(|_| Error::IOError)
+                )
+                .await
+                .map_err(|_| Error::BlockingError)?
+                .map_err(|_| Error::IOError)?;
+
+                let mut file = web::block(move || std::fs::File::open(out_tmp.path()).map(|f| (f, out_tmp)))
                     .await
                     .map_err(|_| Error::BlockingError)?
                     .map_err(|_| Error::IOError)?;
 
-                    let mut file = web::block(move || std::fs::File::open(out_tmp.path()).map(|f| (f, out_tmp)))
-                        .await
-                        .map_err(|_| Error::BlockingError)?
-                        .map_err(|_| Error::IOError)?;
+                buf = web::block(move || file.0.read_to_end(&mut buf).map(|_| buf))
+                    .await
+                    .map_err(|_| Error::BlockingError)?
+                    .map_err(|_| Error::IOError)?;
+            }
+        }
+        _ => {}
+    }
 
-                    buf = web::block(move || file.0.read_to_end(&mut buf).map(|_| buf))
-                        .await
-                        .map_err(|_| Error::BlockingError)?
-                        .map_err(|_| Error::IOError)?;
+    Ok(Reencoded {
+        buf,
+        content_type,
+        color_space_normalized,
+    })
+}
 
-                    Metadata::Video {
-                        width,
-                        height
-                    }
-                } else {
-                    Metadata::File
+/// Check and process the contents of a file as a tag would for any upload,
+/// stripping metadata and re-encoding where needed.
+async fn process(tag: &Tag, filename: &str, buf: Vec<u8>) -> Result<Processed, Error> {
+    let content_type = detect_content_type(&buf, filename);
+
+    if !tag.allows_mime_type(&content_type) {
+        return Err(Error::UnsupportedMediaType {
+            allowed: tag.allowed_mime_types.clone(),
+        });
+    }
+
+    if !tag.allows_extension(filename) {
+        return Err(Error::UnsupportedMediaType {
+            allowed: tag.allowed_extensions.clone().unwrap_or_default(),
+        });
+    }
+
+    let Reencoded {
+        buf,
+        content_type,
+        color_space_normalized,
+    } = reencode(tag, content_type, buf).await?;
+
+    // Described the same way as stored contents, from what will be stored.
+    let metadata = probe(&buf, &content_type).await;
+
+    // Anything that isn't media could be opened by anything, so it's scanned.
+    if let Metadata::File | Metadata::Document { .. } = metadata {
+        crate::virus_scan::scan(&buf)?;
+    }
+
+    if let Metadata::Image { width, height, .. } = metadata {
+        tag.check_dimensions(width as u32, height as u32)?;
+        tag.check_aspect_ratio(width as u32, height as u32)?;
+    }
+
+    if let Some(content_type) = &tag.restrict_content_type {
+        if !matches!(
+            (content_type, &metadata),
+            (ContentType::Image, Metadata::Image { .. })
+                | (ContentType::Video, Metadata::Video { .. })
+                | (ContentType::Audio, Metadata::Audio)
+        ) {
+            return Err(Error::FileTypeNotAllowed);
+        }
+    }
+
+    Ok(Processed {
+        content_type,
+        metadata,
+        buf,
+        color_space_normalized,
+    })
+}
+
+/// Perceptual hash of an image, as stored on its file.
+async fn phash(
+    content_type: &str,
+    metadata: &Metadata,
+    buf: &[u8],
+) -> Result<Option<String>, Error> {
+    // SVGs would have to be rendered first, so they are left out.
+    match metadata {
+        Metadata::Image { .. } if content_type != "image/svg+xml" => {
+            let copy = buf.to_vec();
+            crate::util::pool::run(move || {
+                super::serve::decode_image(copy)
+                    .ok()
+                    .map(|image| format!("{:016x}", perceptual_hash(&image)))
+            })
+            .await
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Document for a processed file, which is yet to be saved.
+async fn document(
+    id: String,
+    tag_id: &str,
+    filename: String,
+    processed: &Processed,
+    hash: String,
+    expires_in: Option<u64>,
+) -> Result<File, Error> {
+    let phash = phash(&processed.content_type, &processed.metadata, &processed.buf).await?;
+
+    let now = mongodb::bson::DateTime::now();
+    let expires_at = expires_in
+        .map(|seconds| {
+            seconds
+                .checked_mul(1000)
+                .and_then(|millis| i64::try_from(millis).ok())
+                .and_then(|millis| now.timestamp_millis().checked_add(millis))
+                .map(mongodb::bson::DateTime::from_millis)
+                .ok_or_else(|| Error::BadRequest("expires_in is too large".to_string()))
+        })
+        .transpose()?;
+
+    Ok(crate::db::File {
+        id,
+        tag: tag_id.to_string(),
+        filename,
+        metadata: processed.metadata.clone(),
+        content_type: processed.content_type.clone(),
+        size: processed.buf.len() as isize,
+        hash: Some(hash),
+        created_at: Some(now),
+        updated_at: Some(now),
+        expires_at,
+        download_count: None,
+        variants: None,
+        palette: None,
+        phash,
+        meta: None,
+        deleted: None,
+        deleted_at: None,
+        reported: None,
+        source_id: None,
+        object_id: None,
+        original_url: None,
+        color_space_normalized: Some(true).filter(|_| processed.color_space_normalized),
+    })
+}
+
+/// Process and store the contents of a file as a tag would for any upload.
+/// Deduplicated files share the stored object of an existing file with the same contents.
+pub async fn store(
+    tag_id: &str,
+    tag: &Tag,
+    filename: String,
+    buf: Vec<u8>,
+    expires_in: Option<u64>,
+) -> Result<File, Error> {
+    let processed = process(tag, &filename, buf).await?;
+
+    let hash = hex::encode(Sha256::digest(&processed.buf));
+    // Expiring files shouldn't be shared with anyone else.
+    if tag.deduplication && expires_in.is_none() {
+        let existing = get_collection("attachments")
+            .find_one(
+                doc! {
+                    "hash": &hash,
+                    "tag": &tag_id,
+                    "deleted": { "$ne": true },
+                    "expires_at": { "$exists": false },
+                    "original_url": { "$exists": false }
+                },
+                None,
+            )
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+
+        // Shared objects don't count towards the quota again, as with copies.
+        if let Some(existing) = existing {
+            let now = mongodb::bson::DateTime::now();
+            let file = File {
+                id: new_id(tag),
+                filename,
+                source_id: Some(existing.storage_id().to_string()),
+                object_id: None,
+                created_at: Some(now),
+                updated_at: Some(now),
+                download_count: None,
+                meta: None,
+                reported: None,
+                ..existing
+            };
+
+            get_collection("attachments")
+                .insert_one(&file, None)
+                .await
+                .map_err(|_| Error::DatabaseError)?;
+
+            crate::webhook::notify(crate::webhook::Event::Upload, &file);
+            return Ok(file);
+        }
+    }
+
+    crate::quota::check(tag_id, tag, processed.buf.len() as u64).await?;
+
+    let file = document(new_id(tag), tag_id, filename, &processed, hash, expires_in).await?;
+    get_collection("attachments")
+        .insert_one(&file, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    finish(file, processed.buf).await
+}
+
+/// Record a file hosted at `url` without storing its contents, for tags that redirect.
+/// The contents are still checked and probed as they would be for an upload.
+pub async fn register(
+    tag_id: &str,
+    tag: &Tag,
+    filename: String,
+    buf: Vec<u8>,
+    url: String,
+    expires_in: Option<u64>,
+) -> Result<File, Error> {
+    // Clients are sent the original, not what it would have been processed into.
+    let size = buf.len();
+    let hash = hex::encode(Sha256::digest(&buf));
+
+    let processed = process(tag, &filename, buf).await?;
+    let file = File {
+        size: size as isize,
+        original_url: Some(url),
+        ..document(new_id(tag), tag_id, filename, &processed, hash, expires_in).await?
+    };
+
+    get_collection("attachments")
+        .insert_one(&file, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    crate::webhook::notify(crate::webhook::Event::Upload, &file);
+    Ok(file)
+}
+
+/// Write out a file whose document was just saved, then let everything else know about it.
+async fn finish(file: File, buf: Vec<u8>) -> Result<File, Error> {
+    // Hold onto a copy for thumbnails, they are generated after responding.
+    let source = if crate::thumbnails::wanted(&file) {
+        Some(buf.clone())
+    } else {
+        None
+    };
+
+    crate::storage::write(&file.tag, file.storage_id(), &file.content_type, buf).await?;
+    Ok(announce(file, source).await)
+}
+
+/// Let everything else know about a file whose contents and document were saved,
+/// generating its thumbnails from `source` if there is one.
+async fn announce(file: File, source: Option<Vec<u8>>) -> File {
+    crate::quota::added(&file).await;
+
+    crate::util::metrics::FILE_SIZE
+        .with_label_values(&[&file.tag])
+        .observe(file.size as f64);
+
+    crate::webhook::notify(crate::webhook::Event::Upload, &file);
+
+    if let Some(source) = source {
+        let file = file.clone();
+        tokio::spawn(async move {
+            if let Err(err) = crate::thumbnails::pregenerate(&file, source).await {
+                tracing::error!("Failed to generate thumbnails for {}. {:?}", file.id, err);
+            }
+        });
+    }
+
+    file
+}
+
+/// Paths under a tag which are routed elsewhere, so a file with one of these IDs couldn't be served.
+const RESERVED_IDS: &[&str] = &[
+    "archive",
+    "base64",
+    "bulk",
+    "download",
+    "fetch",
+    "purge-deleted",
+    "similar",
+    "tus",
+];
+
+/// Whether a client chosen ID looks like one we would generate.
+/// Shorter IDs would be stored where the directories sharding longer ones go.
+fn valid_id(id: &str) -> bool {
+    (4..=64).contains(&id.len())
+        && !RESERVED_IDS.contains(&id)
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Upload a file under an ID chosen by the client, so a retried upload can't be stored twice.
+///
+/// `If-None-Match: *` only creates the file if no other live file has the ID, and
+/// `If-Match: <etag>` only replaces the file while it's unchanged. One of them is required.
+pub async fn put(
+    req: HttpRequest,
+    _: Authorized,
+    options: web::Query<UploadOptions>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    let (tag_id, tag) = get_tag(&req)?;
+    let id = req.match_info().query("filename").to_string();
+    if !valid_id(&id) {
+        return Err(Error::BadRequest(
+            "ids must be 4 to 64 letters, numbers, - and _, and not a reserved path".to_string(),
+        ));
+    }
+
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+
+    let existing = get_collection("attachments")
+        .find_one(doc! { "_id": &id }, None)
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    let replacing = match (header("If-None-Match"), header("If-Match")) {
+        (Some(header), _) => {
+            if header.trim() != "*" {
+                return Err(Error::BadRequest(
+                    "if-none-match must be * when uploading".to_string(),
+                ));
+            }
+
+            match existing {
+                // A deleted file's ID is reused, it would be purged otherwise.
+                Some(file) if file.tag == tag_id && file.deleted == Some(true) => Some(file),
+                Some(_) => return Err(Error::PreconditionFailed),
+                None => None,
+            }
+        }
+        (None, Some(header)) => match existing {
+            Some(file)
+                if file.tag == tag_id
+                    && file.deleted != Some(true)
+                    && etag::matches(header, &etag::generate(&file.id, file.updated_at, "")) =>
+            {
+                Some(file)
+            }
+            _ => return Err(Error::PreconditionFailed),
+        },
+        (None, None) => return Err(Error::PreconditionRequired),
+    };
+
+    // Copies would change along with it.
+    if let Some(file) = &replacing {
+        if file.source_id.is_some() || file.references().await? > 0 {
+            return Err(Error::BadRequest(
+                "files sharing contents with a copy can't be replaced".to_string(),
+            ));
+        }
+    }
+
+    let (filename, buf) = receive(&req, tag, tag.max_file_size(), &mut payload).await?;
+    let processed = process(tag, &filename, buf).await?;
+    let hash = hex::encode(Sha256::digest(&processed.buf));
+
+    // Only the growth counts when a live file is replaced.
+    let freed = match &replacing {
+        Some(file) if file.deleted != Some(true) => file.size as u64,
+        _ => 0,
+    };
+
+    let size = processed.buf.len() as u64;
+    crate::quota::check(&tag_id, tag, size.saturating_sub(freed)).await?;
+
+    let file = document(id, &tag_id, filename, &processed, hash, options.expires_in).await?;
+    let collection = get_collection("attachments");
+    let (file, created) = match &replacing {
+        Some(previous) => {
+            // Written beside the old contents first, so the file is never left without any.
+            let file = File {
+                object_id: Some(new_id(tag)),
+                ..file
+            };
+
+            let source = if crate::thumbnails::wanted(&file) {
+                Some(processed.buf.clone())
+            } else {
+                None
+            };
+
+            crate::storage::write(
+                &tag_id,
+                file.storage_id(),
+                &file.content_type,
+                processed.buf,
+            )
+            .await?;
+
+            // Only replaced if nothing else changed it since its ETag was checked.
+            let result = collection
+                .replace_one(
+                    doc! {
+                        "_id": &previous.id,
+                        "tag": &tag_id,
+                        "updated_at": previous.updated_at
+                    },
+                    &file,
+                    None,
+                )
+                .await;
+
+            let replaced = matches!(&result, Ok(result) if result.matched_count == 1);
+            if !replaced {
+                if let Err(err) = crate::storage::delete(&tag_id, file.storage_id()).await {
+                    tracing::warn!("Failed to delete unused {}. {:?}", file.storage_id(), err);
                 }
+
+                return Err(match result {
+                    Ok(_) => Error::PreconditionFailed,
+                    Err(_) => Error::DatabaseError,
+                });
             }
-            /* mp3 */ "audio/mpeg" |
-            /* wav */ "audio/wav" |
-            /* ogg */ "audio/x-vorbis+ogg" |
-            /* opus */ "audio/x-opus+ogg" => {
-                Metadata::Audio
+
+            if previous.deleted != Some(true) {
+                crate::quota::removed(previous).await;
             }
-            _ => {
-                if inspect(&buf).is_text() {
-                    Metadata::Text
-                } else {
-                    // Scan the file for malware
-                    if *USE_CLAMD {
-                        let scan_response =
-                            revolt_clamav_client::scan_buffer_tcp(&buf, CLAMD_HOST.to_string(), None).unwrap();
-
-                        let file_clean = revolt_clamav_client::clean(&scan_response).unwrap();
-                        if !file_clean {
-                            return Err(Error::Malware)
-                        }
-                    }
 
-                    Metadata::File
+            crate::cache::invalidate(&previous.id);
+            for variant in previous.variants.iter().flatten() {
+                if let Err(err) = crate::storage::delete(&tag_id, &variant.id).await {
+                    tracing::warn!("Failed to delete variant {}. {:?}", variant.id, err);
                 }
             }
-        };
 
-        if let Some(content_type) = &tag.restrict_content_type {
-            if !matches!(
-                (content_type, &metadata),
-                (ContentType::Image, Metadata::Image { .. })
-                    | (ContentType::Video, Metadata::Video { .. })
-                    | (ContentType::Audio, Metadata::Audio)
-            ) {
-                return Err(Error::FileTypeNotAllowed);
+            // Copies were refused above, so nothing else uses the old contents.
+            if let Err(err) = crate::storage::delete(&tag_id, previous.storage_id()).await {
+                tracing::warn!("Failed to delete replaced {}. {:?}", previous.id, err);
             }
+
+            (announce(file, source).await, false)
         }
+        None => {
+            match collection.insert_one(&file, None).await {
+                Ok(_) => {}
+                // Another upload got there first.
+                Err(err) if is_duplicate_key(&err) => return Err(Error::PreconditionFailed),
+                Err(_) => return Err(Error::DatabaseError),
+            }
 
-        let id = if tag.use_ulid {
-            ulid::Ulid::new().to_string()
-        } else {
-            nanoid!(42)
-        };
+            (finish(file, processed.buf).await?, true)
+        }
+    };
 
-        let file = crate::db::File {
-            id,
-            tag: tag_id.clone(),
-            filename,
-            metadata,
-            content_type,
-            size: buf.len() as isize,
-            deleted: None,
-            reported: None,
-        };
+    let mut response = if created {
+        HttpResponse::Created()
+    } else {
+        HttpResponse::Ok()
+    };
 
-        get_collection("attachments")
-            .insert_one(&file, None)
-            .await
-            .map_err(|_| Error::DatabaseError)?;
+    Ok(response
+        .insert_header(("ETag", etag::generate(&file.id, file.updated_at, "")))
+        .json(file))
+}
 
-        if *USE_S3 {
-            let bucket = get_s3_bucket(&tag_id)?;
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::routes::{serve, upload};
+    use crate::util::variables::get_local_path;
+
+    use crate::db::Metadata;
+    use crate::util::metadata::probe;
+
+    use actix_web::{test, web, App};
+    use image::DynamicImage;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    /// A 40x20 JPEG which is meant to be shown rotated a quarter turn, as 20x40.
+    fn rotated_jpeg() -> Vec<u8> {
+        use exif::experimental::Writer;
+        use exif::{Field, In, Value};
+
+        let orientation = Field {
+            tag: exif::Tag::Orientation,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![6]),
+        };
 
-            let (_, code) = bucket
-                .put_object(format!("/{}", file.id), &buf)
-                .await
-                .map_err(|_| Error::S3Error)?;
+        let mut writer = Writer::new();
+        writer.push_field(&orientation);
+        let mut tiff = Cursor::new(vec![]);
+        writer.write(&mut tiff, false).unwrap();
+
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend(tiff.into_inner());
+
+        let mut jpeg = vec![];
+        DynamicImage::ImageRgb8(image::RgbImage::new(40, 20))
+            .write_to(
+                &mut Cursor::new(&mut jpeg),
+                image::ImageOutputFormat::Jpeg(90),
+            )
+            .unwrap();
+
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend(((app1.len() + 2) as u16).to_be_bytes());
+        segment.extend(app1);
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    #[test]
+    fn refuses_ids_which_clash_with_storage_or_routes() {
+        assert!(upload::valid_id("abcd"));
+        assert!(upload::valid_id("my-file_1"));
+        assert!(!upload::valid_id("ab"));
+        assert!(!upload::valid_id("abc"));
+        assert!(!upload::valid_id("archive"));
+        assert!(!upload::valid_id("../x"));
+        assert!(!upload::valid_id(&"a".repeat(65)));
+    }
 
-            if code != 200 {
-                return Err(Error::S3Error);
+    #[test]
+    fn uploads_are_described_like_stored_files() {
+        let config = Config::init_for_tests();
+        let tag = config.tags.get("test").unwrap();
+
+        actix_web::rt::System::new().block_on(async {
+            let uploads: &[(&str, Vec<u8>)] = &[
+                ("photo.jpg", rotated_jpeg()),
+                ("notes.txt", b"hello".to_vec()),
+            ];
+
+            for (filename, buf) in uploads {
+                let processed = upload::process(tag, filename, buf.clone()).await.unwrap();
+                let probed = probe(&processed.buf, &processed.content_type).await;
+                assert_eq!(
+                    serde_json::to_value(&processed.metadata).unwrap(),
+                    serde_json::to_value(&probed).unwrap()
+                );
             }
-        } else {
-            let path = format!("{}/{}", *LOCAL_STORAGE_PATH, &file.id);
-            let mut f = web::block(|| std::fs::File::create(path))
-                .await
-                .map_err(|_| Error::BlockingError)?
-                .map_err(|_| Error::IOError)?;
 
-            web::block(move || f.write_all(&buf))
+            // Sized as it's shown, once the rotation has been applied.
+            let processed = upload::process(tag, "photo.jpg", rotated_jpeg())
                 .await
-                .map_err(|_| Error::BlockingError)?
-                .map_err(|_| Error::IOError)?;
-        }
+                .unwrap();
+            assert!(matches!(
+                processed.metadata,
+                Metadata::Image {
+                    width: 20,
+                    height: 40,
+                    ..
+                }
+            ));
+        });
+    }
 
-        Ok(HttpResponse::Ok().json(json!({ "id": file.id })))
-    } else {
-        Err(Error::MissingData)
+    fn multipart(contents: &str) -> (String, String) {
+        let body = format!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"file\"; filename=\"notes.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\n\
+             {}\r\n\
+             --boundary--\r\n",
+            contents
+        );
+
+        ("multipart/form-data; boundary=boundary".to_string(), body)
+    }
+
+    #[test]
+    #[ignore = "needs MongoDB at AUTUMN_MONGO_URI"]
+    fn put_replaces_contents_under_a_new_object() {
+        actix_web::rt::System::new().block_on(async {
+            let config = Config::init_for_tests();
+            crate::db::connect_for_tests().await;
+
+            let app = test::init_service(
+                App::new().service(
+                    web::resource("/{tag:[^/]*}/{filename:[^/]*}")
+                        .route(web::get().to(serve::get))
+                        .route(web::put().to(upload::put)),
+                ),
+            )
+            .await;
+
+            let id = format!("put-{}", std::process::id());
+            let uri = format!("/private/{}", id);
+            let put = |contents, condition: (&'static str, String)| {
+                let (content_type, body) = multipart(contents);
+                test::TestRequest::put()
+                    .uri(&uri)
+                    .insert_header(("Authorization", "Bearer test-token"))
+                    .insert_header(("Content-Type", content_type))
+                    .insert_header(condition)
+                    .set_payload(body)
+                    .to_request()
+            };
+
+            let response =
+                test::call_service(&app, put("hello", ("If-None-Match", "*".into()))).await;
+            assert_eq!(response.status(), 201);
+            let etag = response
+                .headers()
+                .get("ETag")
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string();
+
+            let response = test::call_service(&app, put("world", ("If-Match", etag.clone()))).await;
+            assert_eq!(response.status(), 200);
+
+            // The old contents are gone once the document points at the new ones.
+            let tag = config.tags.get("private").unwrap();
+            let file = crate::db::find_file(&id, ("private".to_string(), tag))
+                .await
+                .unwrap();
+            let object = file.object_id.clone().unwrap();
+            assert!(!Path::new(&get_local_path("private", &id)).exists());
+            assert!(Path::new(&get_local_path("private", &object)).exists());
+
+            // A stale ETag changes nothing.
+            let response = test::call_service(&app, put("stale", ("If-Match", etag))).await;
+            assert_eq!(response.status(), 412);
+
+            let response = test::call_service(
+                &app,
+                test::TestRequest::get()
+                    .uri(&uri)
+                    .insert_header(("Authorization", "Bearer test-token"))
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(test::read_body(response).await, &b"world"[..]);
+        });
     }
 }
@@ -0,0 +1,61 @@
+use crate::db::get_collection;
+use crate::util::result::Error;
+
+use dashmap::DashMap;
+use mongodb::bson::doc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+lazy_static! {
+    static ref DOWNLOADS: DashMap<String, AtomicU64> = DashMap::new();
+}
+
+/// Count a download, these are buffered until the next flush.
+pub fn record_download(id: &str) {
+    if let Some(count) = DOWNLOADS.get(id) {
+        count.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    DOWNLOADS
+        .entry(id.to_string())
+        .or_insert_with(|| AtomicU64::new(0))
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Downloads which have not been written to the database yet.
+pub fn pending_downloads(id: &str) -> u64 {
+    DOWNLOADS
+        .get(id)
+        .map(|count| count.load(Ordering::Relaxed))
+        .unwrap_or_default()
+}
+
+/// Write buffered download counts to the database.
+pub async fn flush() -> Result<(), Error> {
+    let ids: Vec<String> = DOWNLOADS.iter().map(|entry| entry.key().clone()).collect();
+
+    for id in ids {
+        let count = match DOWNLOADS.get(&id) {
+            Some(count) => count.swap(0, Ordering::Relaxed),
+            None => continue,
+        };
+
+        // Only drop the entry if nothing was counted since the swap.
+        DOWNLOADS.remove_if(&id, |_, count| count.load(Ordering::Relaxed) == 0);
+
+        if count == 0 {
+            continue;
+        }
+
+        get_collection("attachments")
+            .update_one(
+                doc! { "_id": &id },
+                doc! { "$inc": { "download_count": count as i64 } },
+                None,
+            )
+            .await
+            .map_err(|_| Error::DatabaseError)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,837 @@
+use crate::config::Config;
+use crate::encryption;
+use crate::util::metrics::s3_error;
+use crate::util::result::Error;
+use crate::util::variables::{
+    get_gcs_bucket, get_local_path, get_local_root, get_s3_buckets, get_sharded_path,
+    LOCAL_STORAGE_PATH, USE_GCS, USE_S3,
+};
+#[cfg(feature = "azure")]
+use crate::util::variables::{AZURE_CONTAINER, USE_AZURE};
+
+use actix_web::web::{self, Bytes};
+use dashmap::DashMap;
+use futures::stream::{self, BoxStream, StreamExt};
+use s3::command::{Command, Multipart};
+use s3::request::Reqwest;
+use s3::request_trait::Request;
+use s3::serde_types::{CompleteMultipartUploadData, Part};
+use s3::Bucket;
+use std::cmp;
+use std::future::Future;
+use std::io::{SeekFrom, Write};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::time::{sleep, timeout};
+use tracing::{error, info, warn};
+
+/// Size of chunks read from local storage when streaming.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Size of each part of a multipart upload, S3 requires at least 5 MiB.
+const MULTIPART_PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// How long a bucket gets to answer before it's considered down.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long signed URLs used internally for streaming stay valid.
+const STREAM_URL_EXPIRY: u32 = 60;
+
+/// Longest wait between retries of an S3 request.
+const MAX_S3_BACKOFF: Duration = Duration::from_secs(5);
+
+/// How long a bucket gets to answer a read before the next one is tried.
+const S3_FAILOVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
+    static ref GCS_CLIENT: cloud_storage::Client = cloud_storage::Client::default();
+    /// Multipart uploads in progress, by upload ID, so they can be aborted on shutdown.
+    static ref MULTIPART_UPLOADS: DashMap<String, (Bucket, String)> = DashMap::new();
+}
+
+/// Name of the backend in use, for tracing.
+pub fn backend() -> &'static str {
+    #[cfg(feature = "azure")]
+    if *USE_AZURE {
+        return "azure";
+    }
+
+    if *USE_S3 {
+        "s3"
+    } else if *USE_GCS {
+        "gcs"
+    } else {
+        "local"
+    }
+}
+
+/// Write to a temporary file and rename it into place,
+/// so readers never see a partially written file.
+pub fn write_atomic(path: &str, buf: &[u8]) -> std::io::Result<()> {
+    write_atomic_with(path, |f| f.write_all(buf))
+}
+
+/// As `write_atomic`, with the contents written out by `write`.
+fn write_atomic_with<F>(path: &str, write: F) -> std::io::Result<()>
+where
+    F: FnOnce(&mut std::fs::File) -> std::io::Result<()>,
+{
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let tmp = format!("{}.tmp", path);
+    let result = std::fs::File::create(&tmp)
+        .and_then(|mut f| write(&mut f).and_then(|_| f.sync_all()))
+        .and_then(|_| std::fs::rename(&tmp, path));
+
+    if result.is_err() {
+        std::fs::remove_file(&tmp).ok();
+    }
+
+    result
+}
+
+/// Move files from the flat layout used by older versions into sharded directories.
+pub fn migrate_local_storage() -> std::io::Result<usize> {
+    let mut count = 0;
+    for entry in std::fs::read_dir(&*LOCAL_STORAGE_PATH)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        let id = match entry.file_name().into_string() {
+            Ok(id) if !id.ends_with(".tmp") => id,
+            _ => continue,
+        };
+
+        let path = get_sharded_path(&LOCAL_STORAGE_PATH, &id);
+        if entry.path() == std::path::Path::new(&path) {
+            continue;
+        }
+
+        if let Some(parent) = std::path::Path::new(&path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        std::fs::rename(entry.path(), &path)?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Send an S3 request, retrying with exponential backoff while S3 is throttling
+/// or briefly unavailable. Other failures are returned straight away.
+async fn retry_s3<F, Fut, T, E>(request: F) -> Result<(T, u16), E>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<(T, u16), E>>,
+{
+    let config = Config::global();
+    let mut backoff = Duration::from_millis(config.s3_initial_backoff_ms);
+    let mut attempt = 0;
+
+    loop {
+        let response = request().await;
+        match &response {
+            Ok((_, code @ (429 | 500 | 503))) if attempt < config.max_s3_retries => {
+                warn!("S3 responded with {}, retrying in {:?}.", code, backoff);
+            }
+            _ => return response,
+        }
+
+        sleep(backoff).await;
+        backoff = cmp::min(backoff * 2, MAX_S3_BACKOFF);
+        attempt += 1;
+    }
+}
+
+/// Read a whole file from storage.
+pub async fn read(tag: &str, id: &str) -> Result<Vec<u8>, Error> {
+    #[cfg(feature = "azure")]
+    if *USE_AZURE {
+        return azure::read(tag, id).await;
+    }
+
+    if *USE_S3 {
+        let path = format!("/{}", id);
+        let buckets = get_s3_buckets(tag)?;
+        let last = buckets.len().saturating_sub(1);
+        for (index, bucket) in buckets.iter().enumerate() {
+            let request = retry_s3(|| bucket.get_object(&path));
+
+            // A bucket which has gone away shouldn't hold up the ones after it.
+            let response = if index < last {
+                match timeout(S3_FAILOVER_TIMEOUT, request).await {
+                    Ok(response) => response,
+                    Err(_) => {
+                        warn!("S3 bucket {} timed out, trying the next one.", bucket.name);
+                        continue;
+                    }
+                }
+            } else {
+                request.await
+            };
+
+            match response {
+                Ok((data, 200)) => return Ok(data),
+                Ok((_, code)) => s3_error(code),
+                Err(error) => s3_error(error),
+            };
+        }
+
+        Err(Error::S3Error)
+    } else if *USE_GCS {
+        GCS_CLIENT
+            .object()
+            .download(&get_gcs_bucket(tag), id)
+            .await
+            .map_err(|_| Error::GCSError)
+    } else {
+        let mut contents = vec![];
+        let mut f = File::open(get_local_path(tag, id))
+            .await
+            .map_err(|_| Error::IOError)?;
+
+        f.read_to_end(&mut contents)
+            .await
+            .map_err(|_| Error::IOError)?;
+
+        if !encryption::enabled(tag) || !encryption::is_encrypted(&contents) {
+            return Ok(contents);
+        }
+
+        let tag = tag.to_string();
+        web::block(move || encryption::open(&tag, contents))
+            .await
+            .map_err(|_| Error::BlockingError)?
+    }
+}
+
+/// Stream `length` bytes of a file of `size` bytes, starting at `offset`.
+pub async fn stream(
+    tag: &str,
+    id: &str,
+    offset: u64,
+    length: u64,
+    size: u64,
+) -> Result<BoxStream<'static, Result<Bytes, std::io::Error>>, Error> {
+    #[cfg(feature = "azure")]
+    if *USE_AZURE {
+        return azure::stream(tag, id, offset, length).await;
+    }
+
+    let urls = if *USE_S3 {
+        get_s3_buckets(tag)?
+            .iter()
+            .map(|bucket| bucket.presign_get(format!("/{}", id), STREAM_URL_EXPIRY))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(s3_error)?
+    } else if *USE_GCS {
+        vec![GCS_CLIENT
+            .object()
+            .read(&get_gcs_bucket(tag), id)
+            .await
+            .map_err(|_| Error::GCSError)?
+            .download_url(STREAM_URL_EXPIRY)
+            .map_err(|_| Error::GCSError)?]
+    } else {
+        // Encrypted files can only be checked and decrypted as a whole.
+        if encryption::enabled(tag) {
+            let contents = read(tag, id).await?;
+            let start = cmp::min(offset as usize, contents.len());
+            let end = cmp::min(start + length as usize, contents.len());
+            let chunk = Bytes::from(contents).slice(start..end);
+            return Ok(stream::once(async move { Ok(chunk) }).boxed());
+        }
+
+        let mut f = File::open(get_local_path(tag, id))
+            .await
+            .map_err(|_| Error::IOError)?;
+
+        f.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|_| Error::IOError)?;
+
+        return Ok(stream::try_unfold(f.take(length), |mut reader| async move {
+            let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+            let read = reader.read(&mut chunk).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+
+            chunk.truncate(read);
+            Ok(Some((Bytes::from(chunk), reader)))
+        })
+        .boxed());
+    };
+
+    let error = || {
+        if *USE_S3 {
+            s3_error(())
+        } else {
+            Error::GCSError
+        }
+    };
+
+    let last = urls.len().saturating_sub(1);
+    for (index, url) in urls.into_iter().enumerate() {
+        let mut request = HTTP_CLIENT.get(url);
+        if offset > 0 || length < size {
+            request = request.header("Range", format!("bytes={}-{}", offset, offset + length - 1));
+        }
+
+        if index < last {
+            request = request.timeout(S3_FAILOVER_TIMEOUT);
+        }
+
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                return Ok(response
+                    .bytes_stream()
+                    .map(|chunk| chunk.map_err(std::io::Error::other))
+                    .boxed())
+            }
+            _ => error(),
+        };
+    }
+
+    Err(error())
+}
+
+/// Whether a bucket answers at all, the object need not exist.
+pub async fn s3_bucket_healthy(bucket: &Bucket) -> bool {
+    matches!(
+        timeout(HEALTH_CHECK_TIMEOUT, bucket.head_object("/")).await,
+        Ok(Ok((_, 200 | 404)))
+    )
+}
+
+/// The bucket holding an object, which is the first of a tag's buckets to have it.
+/// Tags with a single bucket aren't checked, since there's nowhere else for it to be.
+pub async fn s3_bucket_holding(tag: &str, id: &str) -> Result<Option<Bucket>, Error> {
+    let buckets = get_s3_buckets(tag)?;
+    if buckets.len() == 1 {
+        return Ok(buckets.into_iter().next());
+    }
+
+    let path = format!("/{}", id);
+    for bucket in buckets {
+        if let Ok(Ok((_, 200))) = timeout(HEALTH_CHECK_TIMEOUT, bucket.head_object(&path)).await {
+            return Ok(Some(bucket));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The first bucket for a tag that is reachable.
+async fn healthy_s3_bucket(tag: &str) -> Result<Bucket, Error> {
+    for bucket in get_s3_buckets(tag)? {
+        if s3_bucket_healthy(&bucket).await {
+            return Ok(bucket);
+        }
+
+        warn!(
+            "S3 bucket {} is unreachable, trying the next one.",
+            bucket.name
+        );
+    }
+
+    Err(s3_error("no reachable bucket"))
+}
+
+/// Pull the upload ID out of an `InitiateMultipartUploadResult` body.
+fn parse_upload_id(body: &[u8]) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?;
+    let start = body.find("<UploadId>")? + "<UploadId>".len();
+    let end = body[start..].find("</UploadId>")? + start;
+    Some(body[start..end].to_string())
+}
+
+/// Upload the parts of a multipart upload and complete it.
+async fn put_parts(bucket: &Bucket, path: &str, upload_id: &str, buf: &[u8]) -> Result<(), Error> {
+    let mut parts = vec![];
+    for (index, chunk) in buf.chunks(MULTIPART_PART_SIZE).enumerate() {
+        let part_number = index as u32 + 1;
+        let command = Command::PutObject {
+            content: chunk,
+            content_type: "application/octet-stream",
+            multipart: Some(Multipart::new(part_number, upload_id)),
+        };
+
+        let (etag, code) = Reqwest::new(bucket, path, command)
+            .response_data(true)
+            .await
+            .map_err(s3_error)?;
+
+        if code != 200 {
+            return Err(s3_error(code));
+        }
+
+        parts.push(Part {
+            part_number,
+            etag: String::from_utf8(etag).map_err(s3_error)?,
+        });
+    }
+
+    let command = Command::CompleteMultipartUpload {
+        upload_id,
+        data: CompleteMultipartUploadData { parts },
+    };
+
+    let (_, code) = Reqwest::new(bucket, path, command)
+        .response_data(false)
+        .await
+        .map_err(s3_error)?;
+
+    if code != 200 {
+        return Err(s3_error(code));
+    }
+
+    Ok(())
+}
+
+/// A bucket which asks S3 to encrypt what's written with the tag's `s3_sse` settings.
+///
+/// S3 rejects these headers on reads and on the parts of multipart uploads,
+/// so only use it to create objects.
+fn encrypting(bucket: &Bucket, tag: &str) -> Bucket {
+    let mut bucket = bucket.clone();
+    if let Some(config) = Config::global().tags.get(tag) {
+        if let Some(sse) = config.s3_sse {
+            bucket.add_header("x-amz-server-side-encryption", sse.as_str());
+            if let Some(key_id) = &config.s3_sse_kms_key_id {
+                bucket.add_header("x-amz-server-side-encryption-aws-kms-key-id", key_id);
+            }
+        }
+    }
+
+    bucket
+}
+
+/// Upload a large file to S3 in parts, so a single failed
+/// request doesn't mean sending the whole file again.
+async fn put_multipart(bucket: &Bucket, tag: &str, path: &str, buf: &[u8]) -> Result<(), Error> {
+    let initiating = encrypting(bucket, tag);
+    let (body, code) = Reqwest::new(&initiating, path, Command::InitiateMultipartUpload)
+        .response_data(false)
+        .await
+        .map_err(s3_error)?;
+
+    if code != 200 {
+        return Err(s3_error(code));
+    }
+
+    let upload_id = parse_upload_id(&body).ok_or_else(|| s3_error("missing upload ID"))?;
+    MULTIPART_UPLOADS.insert(upload_id.clone(), (bucket.clone(), path.to_string()));
+
+    let result = put_parts(bucket, path, &upload_id, buf).await;
+    if result.is_err() {
+        // Otherwise the uploaded parts are kept, and billed, indefinitely.
+        if let Err(error) = bucket.abort_upload(path, &upload_id).await {
+            warn!("Failed to abort multipart upload {}: {}", upload_id, error);
+        }
+    }
+
+    MULTIPART_UPLOADS.remove(&upload_id);
+    result
+}
+
+/// Abort every multipart upload still in progress, for when the
+/// server stops before they finish and can't clean up after themselves.
+pub async fn abort_multipart_uploads() {
+    let uploads: Vec<(String, (Bucket, String))> = MULTIPART_UPLOADS
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect();
+
+    for (upload_id, (bucket, path)) in uploads {
+        match bucket.abort_upload(&path, &upload_id).await {
+            Ok(()) => info!("Aborted multipart upload {}.", upload_id),
+            Err(error) => warn!("Failed to abort multipart upload {}: {}", upload_id, error),
+        }
+
+        MULTIPART_UPLOADS.remove(&upload_id);
+    }
+}
+
+/// Write a file to storage.
+pub async fn write(tag: &str, id: &str, content_type: &str, buf: Vec<u8>) -> Result<(), Error> {
+    if crate::mirror::enabled(tag) {
+        let copy = buf.clone();
+        write_primary(tag, id, content_type, buf).await?;
+        crate::mirror::write(tag, id, content_type, copy);
+        return Ok(());
+    }
+
+    write_primary(tag, id, content_type, buf).await
+}
+
+async fn write_primary(tag: &str, id: &str, content_type: &str, buf: Vec<u8>) -> Result<(), Error> {
+    #[cfg(feature = "azure")]
+    if *USE_AZURE {
+        return azure::write(tag, id, content_type, buf).await;
+    }
+
+    if *USE_S3 {
+        let bucket = healthy_s3_bucket(tag).await?;
+        let path = format!("/{}", id);
+        if buf.len() > Config::global().multipart_threshold {
+            return put_multipart(&bucket, tag, &path, &buf).await;
+        }
+
+        let bucket = &encrypting(&bucket, tag);
+        let (_, code) = retry_s3(|| bucket.put_object(&path, &buf))
+            .await
+            .map_err(s3_error)?;
+
+        if code != 200 {
+            return Err(s3_error(code));
+        }
+    } else if *USE_GCS {
+        GCS_CLIENT
+            .object()
+            .create(&get_gcs_bucket(tag), buf, id, content_type)
+            .await
+            .map_err(|_| Error::GCSError)?;
+    } else {
+        let path = get_local_path(tag, id);
+        let tag = tag.to_string();
+        web::block(move || {
+            let buf = encryption::seal(&tag, buf)?;
+            write_atomic(&path, &buf).map_err(|_| Error::IOError)
+        })
+        .await
+        .map_err(|_| Error::BlockingError)??;
+    }
+
+    Ok(())
+}
+
+/// Delete a file from storage.
+pub async fn delete(tag: &str, id: &str) -> Result<(), Error> {
+    #[cfg(feature = "azure")]
+    if *USE_AZURE {
+        return azure::delete(tag, id).await;
+    }
+
+    if *USE_S3 {
+        // The file could be in any of the buckets, depending on which was up.
+        let mut deleted = false;
+        let path = format!("/{}", id);
+        for bucket in get_s3_buckets(tag)? {
+            let bucket = &bucket;
+            match retry_s3(|| bucket.delete_object(&path)).await {
+                Ok((_, 200 | 204)) => deleted = true,
+                Ok((_, code)) => {
+                    s3_error(code);
+                }
+                Err(error) => {
+                    s3_error(error);
+                }
+            }
+        }
+
+        if !deleted {
+            return Err(Error::S3Error);
+        }
+    } else if *USE_GCS {
+        GCS_CLIENT
+            .object()
+            .delete(&get_gcs_bucket(tag), id)
+            .await
+            .map_err(|_| Error::GCSError)?;
+    } else {
+        let path = get_local_path(tag, id);
+        web::block(|| std::fs::remove_file(path))
+            .await
+            .map_err(|_| Error::BlockingError)?
+            .map_err(|_| Error::IOError)?;
+    }
+
+    Ok(())
+}
+
+/// Check that S3 accepts encrypted writes for every tag with `s3_sse` set,
+/// which fails if the KMS key doesn't exist or can't be used.
+pub async fn check_encryption() {
+    if !*USE_S3 {
+        return;
+    }
+
+    let path = "/.autumn-encryption-check";
+    for (id, tag) in &Config::global().tags {
+        if tag.s3_sse.is_none() {
+            continue;
+        }
+
+        let bucket = match healthy_s3_bucket(id).await {
+            Ok(bucket) => bucket,
+            Err(_) => {
+                error!(
+                    "Couldn't check encryption for {}, no bucket is reachable.",
+                    id
+                );
+                continue;
+            }
+        };
+
+        match encrypting(&bucket, id).put_object(path, b"").await {
+            Ok((_, 200)) => {
+                bucket.delete_object(path).await.ok();
+            }
+            Ok((body, code)) => error!(
+                "S3 refused encrypted writes for {} with {}, check s3_sse_kms_key_id exists and can be used. {}",
+                id,
+                code,
+                String::from_utf8_lossy(&body)
+            ),
+            Err(err) => error!("Failed to check encryption for {}. {}", id, err),
+        }
+    }
+}
+
+/// Where a tag's files are kept, tags with the same location share storage.
+fn location(tag: &str) -> Result<String, Error> {
+    #[cfg(feature = "azure")]
+    if *USE_AZURE {
+        return Ok(AZURE_CONTAINER.clone().unwrap_or_else(|| tag.to_string()));
+    }
+
+    if *USE_S3 {
+        Ok(get_s3_buckets(tag)?
+            .iter()
+            .map(|bucket| format!("{}/{}", bucket.region.endpoint(), bucket.name))
+            .collect::<Vec<_>>()
+            .join(","))
+    } else if *USE_GCS {
+        Ok(get_gcs_bucket(tag))
+    } else {
+        Ok(get_local_root(tag).to_string())
+    }
+}
+
+/// Move a file between tags, which may keep their files in different places.
+/// Local files are renamed, anything else is copied then deleted.
+pub async fn transfer(from: &str, to: &str, id: &str, content_type: &str) -> Result<(), Error> {
+    let local = backend() == "local";
+    let shared = location(from)? == location(to)?;
+
+    // Only local files are encrypted, they stay in place when both tags use the same keys.
+    let same_keys = !local || encryption::same_keys(from, to);
+    if shared && same_keys {
+        return Ok(());
+    }
+
+    // Files are re-encrypted when tags use different keys.
+    if local && !encryption::enabled(from) && !encryption::enabled(to) {
+        let (source, target) = (get_local_path(from, id), get_local_path(to, id));
+        return web::block(move || {
+            if let Some(parent) = std::path::Path::new(&target).parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            // Renaming fails between file systems.
+            std::fs::rename(&source, &target).or_else(|_| {
+                std::fs::copy(&source, &target)?;
+                std::fs::remove_file(&source)
+            })
+        })
+        .await
+        .map_err(|_| Error::BlockingError)?
+        .map_err(|_| Error::IOError);
+    }
+
+    let contents = read(from, id).await?;
+    write(to, id, content_type, contents).await?;
+
+    // Rewritten in place, so there's nothing left to delete.
+    if shared {
+        return Ok(());
+    }
+
+    delete(from, id).await
+}
+
+#[cfg(feature = "azure")]
+mod azure {
+    use crate::util::result::Error;
+    use crate::util::variables::get_azure_blob;
+
+    use actix_web::web::Bytes;
+    use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+
+    pub async fn read(tag: &str, id: &str) -> Result<Vec<u8>, Error> {
+        get_azure_blob(tag, id)?
+            .get_content()
+            .await
+            .map_err(|_| Error::AzureError)
+    }
+
+    pub async fn stream(
+        tag: &str,
+        id: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<BoxStream<'static, Result<Bytes, std::io::Error>>, Error> {
+        if length == 0 {
+            return Ok(stream::empty().boxed());
+        }
+
+        Ok(get_azure_blob(tag, id)?
+            .get()
+            .range(offset..offset + length)
+            .into_stream()
+            .map_ok(|response| response.data)
+            .try_flatten()
+            .map(|chunk| chunk.map_err(std::io::Error::other))
+            .boxed())
+    }
+
+    pub async fn write(tag: &str, id: &str, content_type: &str, buf: Vec<u8>) -> Result<(), Error> {
+        get_azure_blob(tag, id)?
+            .put_block_blob(buf)
+            .content_type(content_type.to_string())
+            .await
+            .map_err(|_| Error::AzureError)?;
+
+        Ok(())
+    }
+
+    pub async fn delete(tag: &str, id: &str) -> Result<(), Error> {
+        get_azure_blob(tag, id)?
+            .delete()
+            .await
+            .map_err(|_| Error::AzureError)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("autumn-storage-{}", std::process::id()));
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn failed_writes_keep_the_old_file() {
+        let path = path("failed");
+        write_atomic(&path, b"old contents").unwrap();
+
+        // Runs out of space half way through.
+        let result = write_atomic_with(&path, |f| {
+            f.write_all(b"new")?;
+            Err(std::io::Error::other("disk full"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"old contents");
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+    }
+
+    #[test]
+    fn interrupted_writes_keep_the_old_file() {
+        let path = path("interrupted");
+        write_atomic(&path, b"old contents").unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            write_atomic_with(&path, |f| {
+                f.write_all(b"new")?;
+                panic!("interrupted");
+            })
+        });
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"old contents");
+
+        write_atomic(&path, b"new contents").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new contents");
+    }
+
+    #[test]
+    #[ignore = "needs MinIO at AUTUMN_TEST_MINIO_ENDPOINT"]
+    fn round_trips_through_minio() {
+        let config = Config::init_for_tests();
+        let endpoint = config.tags["minio"].s3_endpoint.clone().unwrap();
+
+        // The default credentials are read from the environment, as when running against MinIO.
+        let user = std::env::var("MINIO_ROOT_USER").unwrap_or_else(|_| "minioadmin".into());
+        let password = std::env::var("MINIO_ROOT_PASSWORD").unwrap_or_else(|_| "minioadmin".into());
+        std::env::set_var("AWS_ACCESS_KEY_ID", &user);
+        std::env::set_var("AWS_SECRET_ACCESS_KEY", &password);
+
+        let buckets = get_s3_buckets("minio").unwrap();
+        assert_eq!(buckets.len(), 1);
+        let bucket = &buckets[0];
+        assert!(bucket.url().starts_with(&endpoint));
+
+        actix_web::rt::System::new().block_on(async {
+            // Already existing is fine too.
+            Bucket::create_with_path_style(
+                &bucket.name,
+                bucket.region.clone(),
+                bucket.credentials.clone(),
+                s3::BucketConfiguration::default(),
+            )
+            .await
+            .ok();
+
+            assert!(s3_bucket_healthy(bucket).await);
+
+            let (_, code) = bucket.put_object("/round-trip", b"hello").await.unwrap();
+            assert_eq!(code, 200);
+
+            let (data, code) = bucket.get_object("/round-trip").await.unwrap();
+            assert_eq!((data.as_slice(), code), (&b"hello"[..], 200));
+
+            // Signed URLs have to point at the custom endpoint too.
+            let url = bucket.presign_get("/round-trip", 60).unwrap();
+            assert!(url.starts_with(&endpoint));
+            let body = HTTP_CLIENT.get(url).send().await.unwrap().bytes().await;
+            assert_eq!(body.unwrap().as_ref(), b"hello");
+
+            bucket.delete_object("/round-trip").await.unwrap();
+            let (_, code) = bucket.head_object("/round-trip").await.unwrap();
+            assert_eq!(code, 404);
+        });
+    }
+
+    #[test]
+    fn re_encrypts_files_moved_between_keys() {
+        Config::init_for_tests();
+        actix_web::rt::System::new().block_on(async {
+            write("test", "moved", "text/plain", b"hello".to_vec())
+                .await
+                .unwrap();
+            transfer("test", "encrypted", "moved", "text/plain")
+                .await
+                .unwrap();
+        });
+
+        assert!(!std::path::Path::new(&get_local_path("test", "moved")).exists());
+        let buf = std::fs::read(get_local_path("encrypted", "moved")).unwrap();
+        assert!(encryption::is_encrypted(&buf));
+        assert_eq!(encryption::open("encrypted", buf).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn never_creates_missing_files_partially() {
+        let path = path("missing");
+        let result = write_atomic_with(&path, |f| {
+            f.write_all(b"new")?;
+            Err(std::io::Error::other("disk full"))
+        });
+
+        assert!(result.is_err());
+        assert!(!std::path::Path::new(&path).exists());
+    }
+}
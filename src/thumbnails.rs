@@ -0,0 +1,134 @@
+use crate::config::Config;
+use crate::db::{get_collection, File, Metadata, Variant};
+use crate::routes::serve::{try_resize, Transform};
+use crate::util::result::Error;
+
+use futures::StreamExt;
+use mongodb::bson::{doc, to_bson};
+use std::cmp;
+use tracing::{error, info};
+
+/// Storage ID of a pre-generated variant, such as `<id>_128x128.webp`.
+pub fn variant_id(id: &str, width: u32, height: u32, content_type: &str) -> String {
+    let extension = content_type.rsplit('/').next().unwrap_or("bin");
+    format!("{}_{}x{}.{}", id, width, height, extension)
+}
+
+/// Whether any thumbnails should be generated for this file.
+pub fn wanted(file: &File) -> bool {
+    matches!(file.metadata, Metadata::Image { .. })
+        && Config::global()
+            .tags
+            .get(&file.tag)
+            .map(|tag| !tag.pregenerate_sizes.is_empty())
+            .unwrap_or(false)
+}
+
+/// Generate every size configured for the file's tag and record them on the file.
+pub async fn pregenerate(file: &File, contents: Vec<u8>) -> Result<usize, Error> {
+    let (width, height, animated) = match file.metadata {
+        Metadata::Image {
+            width,
+            height,
+            animated,
+            ..
+        } => (width, height, animated),
+        _ => return Ok(0),
+    };
+
+    let sizes = match Config::global().tags.get(&file.tag) {
+        Some(tag) => &tag.pregenerate_sizes,
+        None => return Ok(0),
+    };
+
+    let mut variants: Vec<Variant> = vec![];
+    for (w, h) in sizes {
+        // Sizes are bounded by the original, the same as when serving ?width=&height=.
+        let target_width = cmp::min(width, *w as isize) as u32;
+        let target_height = cmp::min(height, *h as isize) as u32;
+
+        if variants
+            .iter()
+            .any(|variant| variant.width == target_width && variant.height == target_height)
+        {
+            continue;
+        }
+
+        let transform = Transform {
+            animated,
+            svg: file.content_type == "image/svg+xml",
+            ..Default::default()
+        };
+
+        let cloned = contents.clone();
+        let (bytes, content_type) = crate::util::pool::run(move || {
+            try_resize(cloned, target_width, target_height, transform)
+        })
+        .await?
+        .map_err(|_| Error::IOError)?;
+
+        let id = variant_id(&file.id, target_width, target_height, content_type);
+        crate::storage::write(&file.tag, &id, content_type, bytes).await?;
+
+        variants.push(Variant {
+            id,
+            width: target_width,
+            height: target_height,
+            content_type: content_type.to_string(),
+        });
+    }
+
+    let count = variants.len();
+    get_collection("attachments")
+        .update_one(
+            doc! { "_id": &file.id },
+            doc! { "$set": { "variants": to_bson(&variants).map_err(|_| Error::DatabaseError)? } },
+            None,
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    Ok(count)
+}
+
+/// Generate thumbnails for every existing image in a tag.
+pub async fn backfill(tag: &str) -> Result<(), Error> {
+    if !Config::global().tags.contains_key(tag) {
+        return Err(Error::UnknownTag);
+    }
+
+    let mut cursor = get_collection("attachments")
+        .find(
+            doc! {
+                "tag": tag,
+                "metadata.type": "Image",
+                "deleted": { "$ne": true }
+            },
+            None,
+        )
+        .await
+        .map_err(|_| Error::DatabaseError)?;
+
+    let mut count = 0;
+    while let Some(file) = cursor.next().await {
+        let file = file.map_err(|_| Error::DatabaseError)?;
+        let contents = match crate::storage::read(&file.tag, file.storage_id()).await {
+            Ok(contents) => contents,
+            Err(err) => {
+                error!("Failed to read {}. {:?}", file.id, err);
+                continue;
+            }
+        };
+
+        match pregenerate(&file, contents).await {
+            Ok(generated) => {
+                info!("Generated {} thumbnails for {}.", generated, file.id);
+                count += 1;
+            }
+            Err(err) => error!("Failed to generate thumbnails for {}. {:?}", file.id, err),
+        }
+    }
+
+    info!("Finished generating thumbnails for {} files.", count);
+    Ok(())
+}
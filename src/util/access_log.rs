@@ -0,0 +1,221 @@
+use crate::config::{Config, LogFormat};
+use crate::util::ip::client_ip;
+use crate::util::request_id::RequestId;
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Bytes;
+use actix_web::HttpMessage;
+use chrono::{SecondsFormat, Utc};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use serde::Serialize;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tracing::info;
+
+/// Query parameters which change how a file is served.
+const RESIZE_PARAMS: &[&str] = &[
+    "size",
+    "width",
+    "height",
+    "max_side",
+    "crop",
+    "rotate",
+    "flip",
+    "quality",
+    "blur",
+    "grayscale",
+    "format",
+    "fit",
+    "bg",
+    "strip_metadata",
+    "dpr",
+];
+
+/// What's logged about each request.
+#[derive(Serialize)]
+struct Entry {
+    timestamp: String,
+    client_ip: Option<String>,
+    method: String,
+    path: String,
+    status: u16,
+    tag: Option<String>,
+    file_id: Option<String>,
+    resize_params: Option<String>,
+    response_size_bytes: u64,
+    backend: &'static str,
+    request_id: Option<String>,
+    duration_ms: f64,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Entry {
+    fn log(&self) {
+        match Config::global().log_format {
+            LogFormat::Json => match serde_json::to_string(self) {
+                Ok(line) => println!("{}", line),
+                Err(err) => info!("Failed to serialize an access log entry. {}", err),
+            },
+            LogFormat::Text => {
+                let or_dash = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".into());
+                info!(
+                    r#"{} "{} {}" {} {} "{}" "{}" {:.3}ms tag={} file_id={} resize={} backend={} request_id={}"#,
+                    or_dash(&self.client_ip),
+                    self.method,
+                    self.path,
+                    self.status,
+                    self.response_size_bytes,
+                    or_dash(&self.referer),
+                    or_dash(&self.user_agent),
+                    self.duration_ms,
+                    or_dash(&self.tag),
+                    or_dash(&self.file_id),
+                    or_dash(&self.resize_params),
+                    self.backend,
+                    or_dash(&self.request_id),
+                );
+            }
+        }
+    }
+}
+
+/// Resizing parameters of a query string, in the order they were given.
+fn resize_params(query: &str) -> Option<String> {
+    let params: Vec<&str> = query
+        .split('&')
+        .filter(|pair| {
+            let key = pair.split('=').next().unwrap_or_default();
+            RESIZE_PARAMS.contains(&key)
+        })
+        .collect();
+
+    if params.is_empty() {
+        None
+    } else {
+        Some(params.join("&"))
+    }
+}
+
+/// Log every request once its response has been sent, in the configured `log_format`.
+pub struct AccessLog;
+
+impl<S, B> Transform<S, ServiceRequest> for AccessLog
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<LoggedBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = AccessLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AccessLogMiddleware { service }))
+    }
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for AccessLogMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<LoggedBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        let entry = Entry {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            client_ip: client_ip(req.headers(), req.peer_addr()).map(|ip| ip.to_string()),
+            method: req.method().to_string(),
+            path: req.path().to_string(),
+            status: 0,
+            tag: None,
+            file_id: None,
+            resize_params: resize_params(req.query_string()),
+            response_size_bytes: 0,
+            backend: crate::storage::backend(),
+            request_id: req.extensions().get::<RequestId>().map(|id| id.0.clone()),
+            duration_ms: 0.0,
+            referer: header("Referer"),
+            user_agent: header("User-Agent"),
+        };
+
+        let future = self.service.call(req);
+        Box::pin(async move {
+            let response = future.await?;
+
+            // Only known once the request has been routed.
+            let params = response.request().match_info();
+            let entry = Entry {
+                status: response.status().as_u16(),
+                tag: params.get("tag").map(str::to_string),
+                file_id: params.get("filename").map(str::to_string),
+                ..entry
+            };
+
+            Ok(response.map_body(move |_, body| LoggedBody {
+                body: Box::pin(body),
+                entry: Some(entry),
+                start,
+            }))
+        })
+    }
+}
+
+/// Response body which counts the bytes sent, logging the request once it's dropped.
+pub struct LoggedBody<B> {
+    body: Pin<Box<B>>,
+    entry: Option<Entry>,
+    start: Instant,
+}
+
+impl<B: MessageBody> MessageBody for LoggedBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let chunk = this.body.as_mut().poll_next(cx);
+        if let (Poll::Ready(Some(Ok(chunk))), Some(entry)) = (&chunk, &mut this.entry) {
+            entry.response_size_bytes += chunk.len() as u64;
+        }
+
+        chunk
+    }
+}
+
+impl<B> Drop for LoggedBody<B> {
+    fn drop(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.duration_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+            entry.log();
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use crate::config::{get_tag, Config};
+use crate::util::result::Error;
+use crate::util::upload_token::{self, UploadClaims};
+
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+
+/// Compare two secrets without short-circuiting on the first difference.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Token given in an `Authorization: Bearer <token>` header.
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Extractor which requires a matching `Authorization: Bearer <token>`
+/// header on requests to tags that have an `auth_token` configured.
+pub struct Authorized;
+
+impl Authorized {
+    fn check(req: &HttpRequest) -> Result<Authorized, Error> {
+        // Unknown tags are reported by the handler itself.
+        let expected = match get_tag(req) {
+            Ok((_, tag)) => match &tag.auth_token {
+                Some(token) => token,
+                None => return Ok(Authorized),
+            },
+            Err(_) => return Ok(Authorized),
+        };
+
+        match bearer_token(req) {
+            Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+                Ok(Authorized)
+            }
+            _ => Err(Error::Unauthorized),
+        }
+    }
+}
+
+impl FromRequest for Authorized {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Authorized::check(req))
+    }
+}
+
+/// Extractor for uploads, which are allowed either like `Authorized` or with an
+/// `X-Upload-Token` for the tag. A token's claims still have to be enforced and redeemed.
+pub struct UploadAuthorized(pub Option<UploadClaims>);
+
+impl FromRequest for UploadAuthorized {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = match req
+            .headers()
+            .get("X-Upload-Token")
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(token) => token,
+            None => return ready(Authorized::check(req).map(|_| UploadAuthorized(None))),
+        };
+
+        // Tokens are signed with the master key.
+        let secret = match &Config::global().master_key {
+            Some(key) => key,
+            None => return ready(Err(Error::Forbidden)),
+        };
+
+        ready(upload_token::verify(token, secret).and_then(|claims| {
+            if claims.tag == req.match_info().query("tag") {
+                Ok(UploadAuthorized(Some(claims)))
+            } else {
+                Err(Error::InvalidSignature)
+            }
+        }))
+    }
+}
+
+/// Extractor which requires the configured `master_key` as a bearer token,
+/// admin routes are unavailable without one.
+pub struct MasterKey;
+
+impl FromRequest for MasterKey {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let expected = match &Config::global().master_key {
+            Some(key) => key,
+            None => return ready(Err(Error::Forbidden)),
+        };
+
+        ready(match bearer_token(req) {
+            Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(MasterKey),
+            _ => Err(Error::Unauthorized),
+        })
+    }
+}
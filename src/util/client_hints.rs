@@ -0,0 +1,90 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::{HttpMessage, HttpRequest};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+/// Hints browsers are asked to send.
+const ACCEPT_CH: &str = "DPR, Viewport-Width";
+
+/// Highest device pixel ratio honoured, beyond this the difference isn't visible.
+const MAX_DPR: f32 = 4.0;
+
+/// Client hints sent with the current request, available from the request's extensions.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientHints {
+    pub dpr: Option<f32>,
+    pub viewport_width: Option<u32>,
+}
+
+impl ClientHints {
+    pub fn from_request(req: &HttpRequest) -> ClientHints {
+        req.extensions()
+            .get::<ClientHints>()
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+fn header<T: std::str::FromStr>(req: &ServiceRequest, name: &str) -> Option<T> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Read the `DPR` and `Viewport-Width` hints and ask browsers to send them.
+pub struct AcceptClientHints;
+
+impl<S, B> Transform<S, ServiceRequest> for AcceptClientHints
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = ClientHintsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ClientHintsMiddleware { service }))
+    }
+}
+
+pub struct ClientHintsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ClientHintsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let hints = ClientHints {
+            dpr: header::<f32>(&req, "DPR")
+                .filter(|dpr| dpr.is_finite() && *dpr > 0.0)
+                .map(|dpr| dpr.min(MAX_DPR)),
+            viewport_width: header(&req, "Viewport-Width").filter(|width| *width > 0),
+        };
+
+        req.extensions_mut().insert(hints);
+
+        let future = self.service.call(req);
+        Box::pin(async move {
+            let mut response = future.await?;
+            response.headers_mut().insert(
+                HeaderName::from_static("accept-ch"),
+                HeaderValue::from_static(ACCEPT_CH),
+            );
+
+            Ok(response)
+        })
+    }
+}
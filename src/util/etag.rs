@@ -0,0 +1,21 @@
+use mongodb::bson::DateTime;
+
+/// Generate a strong ETag for a file.
+///
+/// The variant distinguishes different representations of the
+/// same file, such as resized images, and should be the query string.
+pub fn generate(id: &str, updated_at: Option<DateTime>, variant: &str) -> String {
+    let updated_at = updated_at
+        .map(|date| date.timestamp_millis())
+        .unwrap_or_default();
+
+    let digest = md5::compute(format!("{}:{}:{}", id, updated_at, variant));
+    format!("\"{:x}\"", digest)
+}
+
+/// Check whether an If-None-Match header matches the given ETag.
+pub fn matches(header: &str, etag: &str) -> bool {
+    header.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
@@ -0,0 +1,662 @@
+use flate2::read::ZlibDecoder;
+use image::codecs::jpeg::JpegDecoder;
+use image::{imageops, DynamicImage, ImageDecoder, RgbImage, Rgba, RgbaImage};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions, Xyzd};
+use std::io::{Cursor, Read};
+
+/// Scale an image to cover `width` x `height`, cropping whatever
+/// overflows equally from both sides, like CSS `object-fit: cover`.
+pub fn crop_to_fit(image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let (source_width, source_height) = (image.width() as u64, image.height() as u64);
+    let (width, height) = (width.max(1), height.max(1));
+
+    // Largest region of the source with the target's aspect ratio.
+    let (crop_width, crop_height) = if source_width * height as u64 > source_height * width as u64 {
+        (
+            (source_height * width as u64 / height as u64).max(1),
+            source_height,
+        )
+    } else {
+        (
+            source_width,
+            (source_width * height as u64 / width as u64).max(1),
+        )
+    };
+
+    let x = (source_width - crop_width) / 2;
+    let y = (source_height - crop_height) / 2;
+
+    image
+        .crop_imm(x as u32, y as u32, crop_width as u32, crop_height as u32)
+        .thumbnail_exact(width, height)
+}
+
+/// Scale an image to fit within `width` x `height`, centring it on
+/// a `background` coloured canvas, like CSS `object-fit: contain`.
+pub fn pad_to_fit(
+    image: DynamicImage,
+    width: u32,
+    height: u32,
+    background: Rgba<u8>,
+) -> DynamicImage {
+    let (width, height) = (width.max(1), height.max(1));
+    let has_alpha = image.color().has_alpha();
+    let scaled = image.thumbnail(width, height).to_rgba8();
+
+    let mut canvas = RgbaImage::from_pixel(width, height, background);
+    let x = (width - scaled.width().min(width)) / 2;
+    let y = (height - scaled.height().min(height)) / 2;
+    imageops::overlay(&mut canvas, &scaled, x as i64, y as i64);
+
+    let canvas = DynamicImage::ImageRgba8(canvas);
+    if has_alpha || background[3] != 255 {
+        canvas
+    } else {
+        // Nothing is transparent, so don't make the encoder carry an alpha channel.
+        DynamicImage::ImageRgb8(canvas.to_rgb8())
+    }
+}
+
+/// Rounds of k-means refinement when finding dominant colours.
+const KMEANS_ITERATIONS: usize = 10;
+
+fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+}
+
+fn nearest(centroids: &[[f32; 3]], pixel: &[f32; 3]) -> usize {
+    let mut best = 0;
+    for (index, centroid) in centroids.iter().enumerate() {
+        if distance(centroid, pixel) < distance(&centroids[best], pixel) {
+            best = index;
+        }
+    }
+
+    best
+}
+
+/// Find up to `count` dominant colours with k-means, along with the
+/// fraction of the image closest to each, most common first.
+pub fn dominant_colours(image: &DynamicImage, count: usize) -> Vec<([u8; 3], f32)> {
+    // A small copy is plenty to find the main colours, and mostly
+    // transparent pixels aren't part of what anyone sees.
+    let pixels: Vec<[f32; 3]> = image
+        .thumbnail(64, 64)
+        .to_rgba8()
+        .pixels()
+        .filter(|pixel| pixel[3] >= 128)
+        .map(|pixel| [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32])
+        .collect();
+
+    if pixels.is_empty() {
+        return vec![];
+    }
+
+    // Spread the starting centroids across the image so results are repeatable.
+    let count = count.min(pixels.len());
+    let mut centroids: Vec<[f32; 3]> = (0..count)
+        .map(|index| pixels[index * pixels.len() / count])
+        .collect();
+
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut sums = vec![([0.0; 3], 0); count];
+        for pixel in &pixels {
+            let (sum, total) = &mut sums[nearest(&centroids, pixel)];
+            for channel in 0..3 {
+                sum[channel] += pixel[channel];
+            }
+
+            *total += 1;
+        }
+
+        let mut changed = false;
+        for (centroid, (sum, total)) in centroids.iter_mut().zip(sums) {
+            if total == 0 {
+                continue;
+            }
+
+            let mean = sum.map(|channel| channel / total as f32);
+            changed |= mean != *centroid;
+            *centroid = mean;
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let mut totals = vec![0; count];
+    for pixel in &pixels {
+        totals[nearest(&centroids, pixel)] += 1;
+    }
+
+    let mut colours: Vec<([u8; 3], f32)> = centroids
+        .iter()
+        .zip(totals)
+        .filter(|(_, total)| *total > 0)
+        .map(|(centroid, total)| {
+            (
+                centroid.map(|channel| channel.round() as u8),
+                total as f32 / pixels.len() as f32,
+            )
+        })
+        .collect();
+
+    colours.sort_by(|a, b| b.1.total_cmp(&a.1));
+    colours
+}
+
+/// Count the frames of a GIF or PNG, static PNGs have one.
+///
+/// Frames are counted from the file's structure rather than by decoding them,
+/// so files with thousands of frames are as quick to count as any other.
+pub fn frame_count(buf: &[u8], content_type: &str) -> Option<u32> {
+    match content_type {
+        "image/gif" => gif_frame_count(buf),
+        "image/png" => png_frame_count(buf),
+        _ => None,
+    }
+}
+
+/// Bytes taken by the colour table a GIF's packed fields describe.
+fn gif_colour_table_size(flags: u8) -> usize {
+    if flags & 0x80 == 0 {
+        0
+    } else {
+        3 << ((flags & 0x07) + 1)
+    }
+}
+
+/// Skip a run of GIF data sub-blocks, which ends with an empty one.
+fn skip_gif_sub_blocks(buf: &[u8], mut position: usize) -> Option<usize> {
+    loop {
+        let size = *buf.get(position)? as usize;
+        position += 1 + size;
+        if size == 0 {
+            return Some(position);
+        }
+    }
+}
+
+/// Count the image descriptors of a GIF, stopping at a truncated frame as decoding would.
+fn gif_frame_count(buf: &[u8]) -> Option<u32> {
+    if buf.len() < 13 || !(buf.starts_with(b"GIF87a") || buf.starts_with(b"GIF89a")) {
+        return None;
+    }
+
+    // Header and logical screen descriptor, then the global colour table.
+    let mut position = 13 + gif_colour_table_size(buf[10]);
+    let mut count = 0;
+    loop {
+        let next = match buf.get(position) {
+            // Image descriptor, local colour table, LZW code size and then the image data.
+            Some(0x2C) => buf.get(position + 9).and_then(|flags| {
+                let data = position + 10 + gif_colour_table_size(*flags) + 1;
+                let end = skip_gif_sub_blocks(buf, data)?;
+                count += 1;
+                Some(end)
+            }),
+            // Extension, its label and then its data.
+            Some(0x21) => skip_gif_sub_blocks(buf, position + 2),
+            // The trailer, or anything else which isn't a GIF block.
+            _ => None,
+        };
+
+        match next {
+            Some(next) => position = next,
+            None => return Some(count),
+        }
+    }
+}
+
+/// Read the frame count from an APNG's `acTL` chunk, which has to come before any image data.
+fn png_frame_count(buf: &[u8]) -> Option<u32> {
+    if !buf.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return None;
+    }
+
+    let mut position = 8;
+    while position + 8 <= buf.len() {
+        let length = u32::from_be_bytes([
+            buf[position],
+            buf[position + 1],
+            buf[position + 2],
+            buf[position + 3],
+        ]) as usize;
+
+        match &buf[position + 4..position + 8] {
+            b"acTL" => {
+                let frames = buf.get(position + 8..position + 12)?;
+                let frames = u32::from_be_bytes([frames[0], frames[1], frames[2], frames[3]]);
+                return Some(frames).filter(|frames| *frames > 0);
+            }
+            b"IDAT" => return Some(1),
+            _ => {}
+        }
+
+        // Length, type, data and CRC.
+        position = position.saturating_add(12).saturating_add(length);
+    }
+
+    None
+}
+
+/// Drop anything after a PNG's IEND chunk, where cropping tools
+/// have left pieces of the original image (CVE-2023-21036).
+pub fn truncate_png(buf: &mut Vec<u8>) {
+    // Skip the signature, then walk the chunks rather than searching for
+    // IEND, as the leftover data can contain an IEND of its own.
+    let mut position = 8;
+    while position + 8 <= buf.len() {
+        let length = u32::from_be_bytes([
+            buf[position],
+            buf[position + 1],
+            buf[position + 2],
+            buf[position + 3],
+        ]) as usize;
+
+        // Length, type, data and CRC.
+        let end = position.saturating_add(12).saturating_add(length);
+        if &buf[position + 4..position + 8] == b"IEND" {
+            buf.truncate(end);
+            return;
+        }
+
+        position = end;
+    }
+}
+
+/// VP8X flags for the chunks `strip_webp_metadata` removes.
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+/// Remove the EXIF and XMP chunks of a WebP, where cameras record locations and
+/// serial numbers. WebPs aren't re-encoded at upload, so nothing else removes them.
+/// Anything which isn't a well formed WebP is left alone.
+pub fn strip_webp_metadata(buf: &mut Vec<u8>) {
+    if buf.len() < 12 || &buf[0..4] != b"RIFF" || &buf[8..12] != b"WEBP" {
+        return;
+    }
+
+    let mut stripped = buf[..12].to_vec();
+    let mut position = 12;
+    while position < buf.len() {
+        let length = match buf.get(position + 4..position + 8) {
+            Some(length) => u32::from_le_bytes([length[0], length[1], length[2], length[3]]),
+            None => return,
+        } as usize;
+
+        // Chunks are padded to an even length.
+        let end = (position + 8)
+            .saturating_add(length)
+            .saturating_add(length & 1);
+
+        let chunk = match buf.get(position..end) {
+            Some(chunk) => chunk,
+            None => return,
+        };
+
+        match &chunk[0..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" if chunk.len() > 8 => {
+                let flags = stripped.len() + 8;
+                stripped.extend_from_slice(chunk);
+                stripped[flags] &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+            }
+            _ => stripped.extend_from_slice(chunk),
+        }
+
+        position = end;
+    }
+
+    let size = (stripped.len() - 8) as u32;
+    stripped[4..8].copy_from_slice(&size.to_le_bytes());
+    *buf = stripped;
+}
+
+/// Largest ICC profile read from a PNG, real ones are a few kilobytes.
+const MAX_ICC_PROFILE_SIZE: u64 = 1024 * 1024;
+
+/// Inflate the profile in a PNG's iCCP chunk. The PNG decoder drops
+/// whatever its inflater hasn't flushed, which is all of a small profile.
+fn png_icc_profile(buf: &[u8]) -> Option<Vec<u8>> {
+    let mut position = 8;
+    while position + 8 <= buf.len() {
+        let length = u32::from_be_bytes([
+            buf[position],
+            buf[position + 1],
+            buf[position + 2],
+            buf[position + 3],
+        ]) as usize;
+
+        let data = position + 8;
+        let end = data.saturating_add(length);
+        match &buf[position + 4..data] {
+            b"iCCP" => {
+                let chunk = buf.get(data..end)?;
+
+                // Profile name, then the compression method, which is always zlib.
+                let name = chunk.iter().position(|byte| *byte == 0)?;
+                if chunk.get(name + 1) != Some(&0) {
+                    return None;
+                }
+
+                let mut profile = vec![];
+                ZlibDecoder::new(&chunk[name + 2..])
+                    .take(MAX_ICC_PROFILE_SIZE)
+                    .read_to_end(&mut profile)
+                    .ok()?;
+
+                return Some(profile);
+            }
+            // The profile has to come before the image data.
+            b"IDAT" => return None,
+            _ => {}
+        }
+
+        // Skip the CRC.
+        position = end.saturating_add(4);
+    }
+
+    None
+}
+
+/// Read the ICC profile embedded in a JPEG or PNG.
+pub fn icc_profile(buf: &[u8], content_type: &str) -> Option<Vec<u8>> {
+    match content_type {
+        "image/jpeg" => JpegDecoder::new(Cursor::new(buf)).ok()?.icc_profile(),
+        "image/png" => png_icc_profile(buf),
+        _ => None,
+    }
+}
+
+/// How far a profile's primaries may be from sRGB's before it's treated as another colour space,
+/// profiles written by different tools round them differently.
+const SRGB_COLORANT_TOLERANCE: f64 = 0.002;
+
+fn is_srgb(profile: &ColorProfile) -> bool {
+    let srgb = ColorProfile::new_srgb();
+    let close = |a: Xyzd, b: Xyzd| {
+        (a.x - b.x).abs() <= SRGB_COLORANT_TOLERANCE
+            && (a.y - b.y).abs() <= SRGB_COLORANT_TOLERANCE
+            && (a.z - b.z).abs() <= SRGB_COLORANT_TOLERANCE
+    };
+
+    close(profile.red_colorant, srgb.red_colorant)
+        && close(profile.green_colorant, srgb.green_colorant)
+        && close(profile.blue_colorant, srgb.blue_colorant)
+}
+
+/// Convert an image decoded from contents with an RGB ICC profile, such as Adobe RGB
+/// or Display P3, to sRGB. Gives `None` where there's nothing to convert: the profile
+/// is already sRGB, isn't RGB or can't be parsed.
+pub fn to_srgb(image: &DynamicImage, icc: &[u8]) -> Option<DynamicImage> {
+    let profile = ColorProfile::new_from_slice(icc).ok()?;
+    if profile.color_space != DataColorSpace::Rgb || is_srgb(&profile) {
+        return None;
+    }
+
+    let srgb = ColorProfile::new_srgb();
+    let options = TransformOptions::default();
+    if image.color().has_alpha() {
+        let source = image.to_rgba8();
+        let mut converted = RgbaImage::new(source.width(), source.height());
+        profile
+            .create_transform_8bit(Layout::Rgba, &srgb, Layout::Rgba, options)
+            .ok()?
+            .transform(source.as_raw(), &mut converted)
+            .ok()?;
+
+        Some(DynamicImage::ImageRgba8(converted))
+    } else {
+        let source = image.to_rgb8();
+        let mut converted = RgbImage::new(source.width(), source.height());
+        profile
+            .create_transform_8bit(Layout::Rgb, &srgb, Layout::Rgb, options)
+            .ok()?
+            .transform(source.as_raw(), &mut converted)
+            .ok()?;
+
+        Some(DynamicImage::ImageRgb8(converted))
+    }
+}
+
+/// Side of the greyscale thumbnail a perceptual hash is taken from.
+const PHASH_SIZE: usize = 32;
+
+/// Side of the block of lowest frequencies which make up the hash's bits.
+const PHASH_FREQUENCIES: usize = 8;
+
+/// 64 bit DCT perceptual hash. Images which look alike have hashes a small
+/// Hamming distance apart, however they are encoded or scaled.
+pub fn perceptual_hash(image: &DynamicImage) -> u64 {
+    let grey = image
+        .resize_exact(
+            PHASH_SIZE as u32,
+            PHASH_SIZE as u32,
+            imageops::FilterType::Triangle,
+        )
+        .into_luma8();
+
+    let pixels: Vec<f64> = grey.pixels().map(|pixel| pixel.0[0] as f64).collect();
+
+    // Only the lowest frequencies are needed, so the DCT is computed directly.
+    let mut cosines = [[0.0; PHASH_SIZE]; PHASH_FREQUENCIES];
+    for (k, row) in cosines.iter_mut().enumerate() {
+        for (n, value) in row.iter_mut().enumerate() {
+            *value = (std::f64::consts::PI * (2 * n + 1) as f64 * k as f64
+                / (2 * PHASH_SIZE) as f64)
+                .cos();
+        }
+    }
+
+    let mut rows = [[0.0; PHASH_FREQUENCIES]; PHASH_SIZE];
+    for (y, row) in rows.iter_mut().enumerate() {
+        let line = &pixels[y * PHASH_SIZE..(y + 1) * PHASH_SIZE];
+        for (u, value) in row.iter_mut().enumerate() {
+            *value = line.iter().zip(&cosines[u]).map(|(p, c)| p * c).sum();
+        }
+    }
+
+    let mut frequencies = Vec::with_capacity(PHASH_FREQUENCIES * PHASH_FREQUENCIES);
+    for cosine in &cosines {
+        for u in 0..PHASH_FREQUENCIES {
+            frequencies.push(
+                rows.iter()
+                    .zip(cosine)
+                    .map(|(row, c)| row[u] * c)
+                    .sum::<f64>(),
+            );
+        }
+    }
+
+    // The first coefficient is the average brightness, which would skew the median.
+    let mut sorted = frequencies[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let median = sorted[sorted.len() / 2];
+
+    frequencies
+        .iter()
+        .fold(0, |hash, value| (hash << 1) | (*value > median) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(fourcc: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = fourcc.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+
+        chunk
+    }
+
+    #[test]
+    fn strips_exif_and_xmp_from_webp() {
+        let pixels = [10, 20, 30].repeat(4);
+        let simple = webp::Encoder::new(&pixels, webp::PixelLayout::Rgb, 2, 2)
+            .encode_lossless()
+            .to_vec();
+
+        // An extended WebP with the simple file's image, then both kinds of metadata.
+        let mut body = b"WEBP".to_vec();
+        body.extend(chunk(
+            b"VP8X",
+            &[WEBP_EXIF_FLAG | WEBP_XMP_FLAG, 0, 0, 0, 1, 0, 0, 1, 0, 0],
+        ));
+        body.extend_from_slice(&simple[12..]);
+        body.extend(chunk(b"EXIF", b"MM\0*GPS"));
+        body.extend(chunk(b"XMP ", b"<x:xmpmeta/>"));
+
+        let mut buf = b"RIFF".to_vec();
+        buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        buf.extend(body);
+
+        strip_webp_metadata(&mut buf);
+
+        let contains = |needle: &[u8]| buf.windows(needle.len()).any(|window| window == needle);
+        assert!(!contains(b"EXIF") && !contains(b"XMP ") && !contains(b"GPS"));
+        assert_eq!(buf[20] & (WEBP_EXIF_FLAG | WEBP_XMP_FLAG), 0);
+        assert_eq!(
+            u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize,
+            buf.len() - 8
+        );
+
+        let decoded = webp::Decoder::new(&buf).decode().unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (2, 2));
+    }
+
+    #[test]
+    fn pads_opaque_images_with_the_background() {
+        let red = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 2, image::Rgb([255, 0, 0])));
+        let padded = pad_to_fit(red, 4, 4, Rgba([0, 0, 255, 255]));
+
+        // Nothing ends up transparent, so there's no alpha channel to encode.
+        let padded = match padded {
+            DynamicImage::ImageRgb8(padded) => padded,
+            other => panic!("expected an RGB image, got {:?}", other.color()),
+        };
+
+        assert_eq!(padded.dimensions(), (4, 4));
+        assert_eq!(padded.get_pixel(0, 0).0, [0, 0, 255]);
+        assert_eq!(padded.get_pixel(3, 1).0, [255, 0, 0]);
+        assert_eq!(padded.get_pixel(0, 2).0, [255, 0, 0]);
+        assert_eq!(padded.get_pixel(3, 3).0, [0, 0, 255]);
+    }
+
+    #[test]
+    fn pads_transparent_images_with_transparency() {
+        let green = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 4, Rgba([0, 255, 0, 128])));
+        let padded = pad_to_fit(green, 4, 4, Rgba([0, 0, 0, 0])).to_rgba8();
+
+        assert_eq!(padded.dimensions(), (4, 4));
+        assert_eq!(padded.get_pixel(0, 0).0[3], 0);
+        assert_eq!(padded.get_pixel(3, 3).0[3], 0);
+        assert_eq!(padded.get_pixel(1, 0).0, [0, 255, 0, 128]);
+        assert_eq!(padded.get_pixel(2, 3).0, [0, 255, 0, 128]);
+    }
+
+    #[test]
+    fn counts_gif_frames_without_decoding() {
+        use image::codecs::gif::{GifDecoder, GifEncoder};
+        use image::{AnimationDecoder, Frame};
+
+        let mut gif = vec![];
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            for shade in [0, 100, 200] {
+                let frame = RgbaImage::from_pixel(3, 2, Rgba([shade, 50, 50, 255]));
+                encoder.encode_frame(Frame::new(frame)).unwrap();
+            }
+        }
+
+        let decoded = GifDecoder::new(Cursor::new(&gif))
+            .unwrap()
+            .into_frames()
+            .count();
+        assert_eq!(frame_count(&gif, "image/gif"), Some(decoded as u32));
+        assert_eq!(decoded, 3);
+
+        // Frames cut off part way through aren't counted.
+        assert_eq!(frame_count(&gif[..gif.len() - 4], "image/gif"), Some(2));
+        assert_eq!(frame_count(b"not a gif", "image/gif"), None);
+    }
+
+    #[test]
+    fn counts_apng_frames_from_actl() {
+        let mut png = vec![];
+        DynamicImage::ImageRgb8(RgbImage::new(2, 2))
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+        assert_eq!(frame_count(&png, "image/png"), Some(1));
+
+        // An animation control chunk for 1000 frames, right after IHDR.
+        let mut actl = 8u32.to_be_bytes().to_vec();
+        actl.extend_from_slice(b"acTL");
+        actl.extend_from_slice(&1000u32.to_be_bytes());
+        actl.extend_from_slice(&0u32.to_be_bytes());
+        actl.extend_from_slice(&[0; 4]);
+
+        let ihdr_end = 8 + 12 + 13;
+        let mut apng = png[..ihdr_end].to_vec();
+        apng.extend(actl);
+        apng.extend_from_slice(&png[ihdr_end..]);
+        assert_eq!(frame_count(&apng, "image/png"), Some(1000));
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        let mut buf = b"RIFF\x04\0\0\0WAVE".to_vec();
+        strip_webp_metadata(&mut buf);
+        assert_eq!(buf, b"RIFF\x04\0\0\0WAVE");
+    }
+
+    /// A solid JPEG tagged with an Adobe RGB profile, as cameras set to Adobe RGB write them.
+    fn adobe_rgb_jpeg(colour: [u8; 3]) -> Vec<u8> {
+        let mut jpeg = vec![];
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, image::Rgb(colour)))
+            .write_to(
+                &mut Cursor::new(&mut jpeg),
+                image::ImageOutputFormat::Jpeg(100),
+            )
+            .unwrap();
+
+        let profile = ColorProfile::new_adobe_rgb().encode().unwrap();
+        let mut app2 = b"ICC_PROFILE\0\x01\x01".to_vec();
+        app2.extend(profile);
+
+        let mut segment = vec![0xFF, 0xE2];
+        segment.extend(((app2.len() + 2) as u16).to_be_bytes());
+        segment.extend(app2);
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    fn hex(image: &DynamicImage) -> Vec<String> {
+        image
+            .to_rgb8()
+            .pixels()
+            .map(|pixel| format!("#{:02x}{:02x}{:02x}", pixel[0], pixel[1], pixel[2]))
+            .collect()
+    }
+
+    #[test]
+    fn converts_adobe_rgb_jpegs_to_srgb() {
+        let jpeg = adobe_rgb_jpeg([200, 100, 50]);
+        let icc = icc_profile(&jpeg, "image/jpeg").unwrap();
+        let image = image::load_from_memory(&jpeg).unwrap();
+        assert!(hex(&image).iter().all(|pixel| pixel == "#c86432"));
+
+        // Adobe RGB's red is more saturated than sRGB can show, so it moves the most.
+        let converted = to_srgb(&image, &icc).unwrap();
+        assert!(hex(&converted).iter().all(|pixel| pixel == "#e3642a"));
+
+        // Nothing is done to images which are already sRGB.
+        let srgb = ColorProfile::new_srgb().encode().unwrap();
+        assert!(to_srgb(&image, &srgb).is_none());
+    }
+}
@@ -0,0 +1,142 @@
+use crate::config::Config;
+
+use actix_web::http::HeaderMap;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+/// Resolve the address of the client making a request.
+///
+/// `X-Forwarded-For` is only used if `trust_proxy_headers` is set and the peer
+/// is one of `trusted_proxy_cidrs`, otherwise any client could pick its own address.
+/// The header is walked from the right, skipping our own proxies, since anything
+/// to the left of the first untrusted hop may have been written by the client.
+/// Without any `trusted_proxy_cidrs`, private hops are taken to be proxies.
+pub fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<IpAddr> {
+    let config = Config::global();
+    resolve(
+        headers,
+        peer,
+        config.trust_proxy_headers,
+        &config.trusted_proxy_cidrs,
+    )
+}
+
+fn resolve(
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    trust_proxy_headers: bool,
+    trusted_proxy_cidrs: &[IpNet],
+) -> Option<IpAddr> {
+    let peer = peer.map(|addr| addr.ip());
+
+    let is_proxy = |ip: IpAddr| {
+        if trusted_proxy_cidrs.is_empty() {
+            !is_public(ip)
+        } else {
+            trusted_proxy_cidrs.iter().any(|net| net.contains(&ip))
+        }
+    };
+
+    let trusted = trust_proxy_headers
+        && (trusted_proxy_cidrs.is_empty() || peer.map(is_proxy).unwrap_or(false));
+
+    if trusted {
+        let forwarded = headers
+            .get("X-Forwarded-For")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                value
+                    .rsplit(',')
+                    .map(|ip| ip.trim().parse().ok())
+                    // An unparseable hop can't be trusted, nor can anything before it.
+                    .take_while(Option::is_some)
+                    .flatten()
+                    .find(|ip| !is_proxy(*ip))
+            });
+
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+
+    peer
+}
+
+/// Whether an address is reachable on the public internet, rather than
+/// being private, loopback, link-local or otherwise reserved.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Carrier-grade NAT.
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local.
+                || (first & 0xfe00) == 0xfc00
+                // Link-local.
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::HeaderValue;
+
+    fn forwarded(value: &'static str, peer: &str, cidrs: &[&str]) -> Option<IpAddr> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Forwarded-For".parse().unwrap(),
+            HeaderValue::from_static(value),
+        );
+        let cidrs: Vec<IpNet> = cidrs.iter().map(|net| net.parse().unwrap()).collect();
+        resolve(&headers, Some(peer.parse().unwrap()), true, &cidrs)
+    }
+
+    #[test]
+    fn takes_the_first_untrusted_hop_from_the_right() {
+        let ip = forwarded("6.6.6.6, 1.2.3.4, 10.0.0.2", "10.0.0.1:80", &["10.0.0.0/8"]);
+        assert_eq!(ip, Some("1.2.3.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn ignores_addresses_spoofed_by_the_client() {
+        // The client sent `X-Forwarded-For: 8.8.8.8`, and our proxy appended its address.
+        let ip = forwarded("8.8.8.8, 1.2.3.4", "10.0.0.1:80", &["10.0.0.0/8"]);
+        assert_eq!(ip, Some("1.2.3.4".parse().unwrap()));
+
+        let ip = forwarded("8.8.8.8, garbage, 10.0.0.2", "10.0.0.1:80", &["10.0.0.0/8"]);
+        assert_eq!(ip, Some("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ignores_the_header_from_untrusted_peers() {
+        let ip = forwarded("1.2.3.4", "5.6.7.8:80", &["10.0.0.0/8"]);
+        assert_eq!(ip, Some("5.6.7.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn skips_private_hops_without_trusted_cidrs() {
+        let ip = forwarded("6.6.6.6, 1.2.3.4, 192.168.0.2", "10.0.0.1:80", &[]);
+        assert_eq!(ip, Some("1.2.3.4".parse().unwrap()));
+    }
+}
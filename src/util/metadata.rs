@@ -0,0 +1,119 @@
+use crate::db::Metadata;
+use crate::util::image::frame_count;
+use crate::util::result::Error;
+
+use actix_web::web;
+use content_inspector::inspect;
+use ffprobe::ffprobe;
+use std::cmp;
+use std::convert::TryInto;
+use std::io::Write;
+use tempfile::NamedTempFile;
+
+pub fn determine_video_size(path: &std::path::Path) -> Result<(isize, isize), Error> {
+    let data = ffprobe(path).map_err(|_| Error::ProbeError)?;
+
+    // Take the first valid stream.
+    for stream in data.streams {
+        if let (Some(w), Some(h)) = (stream.width, stream.height) {
+            if let (Ok(w), Ok(h)) = (w.try_into(), h.try_into()) {
+                return Ok((w, h));
+            }
+        }
+    }
+
+    Err(Error::ProbeError)
+}
+
+/// Find the content type of a file from its contents, falling back on the
+/// filename for formats that share a container.
+pub fn detect_content_type(buf: &[u8], filename: &str) -> String {
+    let mut content_type = tree_magic::from_u8(buf);
+
+    // Intercept known file extensions with certain content types
+    if content_type == "application/zip" && filename.to_lowercase().ends_with(".apk") {
+        content_type = "application/vnd.android.package-archive".to_string();
+    }
+
+    if content_type == "application/x-riff" {
+        if filename.to_lowercase().ends_with(".webp") {
+            content_type = "image/webp".to_string();
+        } else if filename.to_lowercase().ends_with(".wav")
+            || filename.to_lowercase().ends_with(".wave")
+        {
+            content_type = "audio/wav".to_string();
+        }
+    }
+
+    // Only relabelled when it can be decoded, otherwise it's kept as an opaque file.
+    #[cfg(feature = "heif")]
+    if crate::routes::serve::is_heif(buf) {
+        content_type = "image/heic".to_string();
+    }
+
+    content_type
+}
+
+/// Describe a SVG by its intrinsic size, anything that doesn't parse is only text.
+pub fn svg_metadata(buf: &[u8]) -> Metadata {
+    match resvg::usvg::Tree::from_data(buf, &resvg::usvg::Options::default()) {
+        Ok(tree) => Metadata::Image {
+            width: cmp::max(tree.size().width().round() as isize, 1),
+            height: cmp::max(tree.size().height().round() as isize, 1),
+            animated: false,
+            frame_count: None,
+        },
+        Err(_) => Metadata::Text,
+    }
+}
+
+/// Describe contents which are already stored, without changing them.
+///
+/// Uploads are processed first, so this gives the same metadata an upload
+/// of the stored contents would have.
+pub async fn probe(buf: &[u8], content_type: &str) -> Metadata {
+    match content_type {
+        "image/jpeg" | "image/png" | "image/gif" | "image/webp" => {
+            match imagesize::blob_size(buf) {
+                Ok(imagesize::ImageSize { width, height }) => {
+                    let frame_count = frame_count(buf, content_type);
+                    Metadata::Image {
+                        width: width as isize,
+                        height: height as isize,
+                        animated: content_type == "image/gif" && frame_count.unwrap_or(0) > 1,
+                        frame_count,
+                    }
+                }
+                Err(_) => Metadata::File,
+            }
+        }
+        "image/svg+xml" => svg_metadata(buf),
+        "video/mp4" | "video/webm" | "video/quicktime" => {
+            let tmp = match NamedTempFile::new().and_then(|mut tmp| tmp.write_all(buf).map(|_| tmp))
+            {
+                Ok(tmp) => tmp,
+                Err(_) => return Metadata::File,
+            };
+
+            match web::block(move || determine_video_size(tmp.path())).await {
+                Ok(Ok((width, height))) => Metadata::Video { width, height },
+                _ => Metadata::File,
+            }
+        }
+        "audio/mpeg" | "audio/wav" | "audio/x-vorbis+ogg" | "audio/x-opus+ogg" => Metadata::Audio,
+        #[cfg(feature = "pdf")]
+        "application/pdf" => {
+            let copy = buf.to_vec();
+            match web::block(move || crate::pdf::probe(&copy)).await {
+                Ok(Some((width, height, page_count))) => Metadata::Document {
+                    width,
+                    height,
+                    page_count,
+                },
+                _ => Metadata::File,
+            }
+        }
+        _ if inspect(buf).is_text() => Metadata::Text,
+        _ => Metadata::File,
+    }
+}
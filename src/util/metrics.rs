@@ -0,0 +1,137 @@
+use crate::config::Config;
+use crate::util::result::Error;
+use crate::util::shutdown::{InFlight, InFlightBody};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts,
+    Registry, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+    pub static ref REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "autumn_requests_total",
+            "Requests handled, by tag and status."
+        ),
+        &["tag", "status"],
+    ));
+    pub static ref RESIZE_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "autumn_resize_duration_seconds",
+            "Time spent resizing images, by tag."
+        ),
+        &["tag"],
+    ));
+    pub static ref CACHE_HITS: IntCounter = register(IntCounter::new(
+        "autumn_cache_hits_total",
+        "Resized images served from a cache."
+    ));
+    pub static ref INTEGRITY_FAILURES: IntCounter = register(IntCounter::new(
+        "autumn_integrity_failures_total",
+        "Stored files that didn't match the hash recorded at upload."
+    ));
+    pub static ref S3_ERRORS: IntCounter = register(IntCounter::new(
+        "autumn_s3_errors_total",
+        "Failed requests to S3."
+    ));
+    pub static ref SECONDARY_WRITE_FAILURES: IntCounter = register(IntCounter::new(
+        "autumn_secondary_write_failures_total",
+        "Files which couldn't be copied to a tag's secondary storage."
+    ));
+    pub static ref FILE_SIZE: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("autumn_file_size_bytes", "Size of uploaded files, by tag.")
+            .buckets(exponential_buckets(1024.0, 4.0, 10).unwrap()),
+        &["tag"],
+    ));
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: prometheus::Result<T>) -> T {
+    let metric = metric.expect("Invalid metric.");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("Failed to register metric.");
+
+    metric
+}
+
+/// Label for a tag, unknown tags are grouped so clients can't grow the label set.
+pub fn tag_label(tag: &str) -> &str {
+    if Config::global().tags.contains_key(tag) {
+        tag
+    } else {
+        "unknown"
+    }
+}
+
+/// Count an S3 failure, for use with `map_err`.
+pub fn s3_error<E>(_: E) -> Error {
+    S3_ERRORS.inc();
+    Error::S3Error
+}
+
+/// Render every metric in the Prometheus text format.
+pub fn gather() -> String {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .expect("Failed to encode metrics.");
+
+    String::from_utf8(buffer).expect("Metrics are not valid UTF-8.")
+}
+
+/// Count responses by tag and status.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<InFlightBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware { service }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<InFlightBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // Counted until the response has been sent, so shutdown can wait for it.
+        let in_flight = InFlight::start();
+        let future = self.service.call(req);
+        Box::pin(async move {
+            let response = future.await?;
+            let tag = response.request().match_info().get("tag").unwrap_or("");
+
+            REQUESTS
+                .with_label_values(&[tag_label(tag), response.status().as_str()])
+                .inc();
+
+            Ok(response.map_body(move |_, body| InFlightBody::new(body, in_flight)))
+        })
+    }
+}
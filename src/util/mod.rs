@@ -1,2 +1,18 @@
+pub mod access_log;
+pub mod auth;
+pub mod client_hints;
+pub mod etag;
+pub mod image;
+pub mod ip;
+pub mod metadata;
+pub mod metrics;
+pub mod pool;
+pub mod ratelimit;
+pub mod request_id;
 pub mod result;
+pub mod shutdown;
+pub mod signing;
+pub mod telemetry;
+pub mod upload_token;
 pub mod variables;
+pub mod zip;
@@ -0,0 +1,42 @@
+use crate::config::Config;
+use crate::util::result::Error;
+
+use actix_web::web;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use tokio::sync::oneshot;
+
+lazy_static! {
+    static ref POOL: Option<ThreadPool> = Config::global().image_worker_threads.map(|threads| {
+        ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|index| format!("autumn-image-{}", index))
+            .build()
+            .expect("Failed to build the image worker pool.")
+    });
+}
+
+/// Run image decoding / encoding work off the async runtime.
+///
+/// Uses the dedicated pool when `image_worker_threads` is set,
+/// otherwise falls back to the shared blocking pool.
+pub async fn run<F, R>(f: F) -> Result<R, Error>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    // Keep work on the pool attached to the caller's span.
+    let span = tracing::Span::current();
+    let f = move || span.in_scope(f);
+
+    match &*POOL {
+        Some(pool) => {
+            let (tx, rx) = oneshot::channel();
+            pool.spawn(move || {
+                tx.send(f()).ok();
+            });
+
+            rx.await.map_err(|_| Error::BlockingError)
+        }
+        None => web::block(f).await.map_err(|_| Error::BlockingError),
+    }
+}
@@ -0,0 +1,105 @@
+use crate::config::Config;
+use crate::util::ip::client_ip;
+use crate::util::result::Error;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use dashmap::DashMap;
+use futures::future::{ready, Either, Ready};
+use std::net::IpAddr;
+use std::time::Instant;
+
+lazy_static! {
+    static ref BUCKETS: DashMap<IpAddr, TokenBucket> = DashMap::new();
+}
+
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, rate: f64, burst: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+/// Take a token for the given client, returning the
+/// number of seconds to wait if none are available.
+fn take(ip: IpAddr, rate: f64, burst: f64) -> Result<(), u64> {
+    let mut bucket = BUCKETS.entry(ip).or_insert_with(|| TokenBucket {
+        tokens: burst,
+        updated: Instant::now(),
+    });
+
+    bucket.refill(rate, burst);
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        Ok(())
+    } else {
+        Err(((1.0 - bucket.tokens) / rate).ceil() as u64)
+    }
+}
+
+/// Drop buckets which have refilled completely,
+/// since they are equivalent to having no bucket.
+pub fn prune() {
+    if let Some(rate_limit) = &Config::global().rate_limit {
+        BUCKETS.retain(|_, bucket| {
+            bucket.refill(rate_limit.requests_per_second, rate_limit.burst);
+            bucket.tokens < rate_limit.burst
+        });
+    }
+}
+
+/// Per-IP token bucket rate limiting, configured by `rate_limit`.
+pub struct RateLimit;
+
+impl<S> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware { service }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+}
+
+impl<S> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Some(rate_limit) = &Config::global().rate_limit {
+            if let Some(ip) = client_ip(req.headers(), req.peer_addr()) {
+                if let Err(retry_after) = take(ip, rate_limit.requests_per_second, rate_limit.burst)
+                {
+                    // Respond rather than erroring so outer middleware still apply.
+                    return Either::Right(ready(Ok(
+                        req.error_response(Error::TooManyRequests { retry_after })
+                    )));
+                }
+            }
+        }
+
+        Either::Left(self.service.call(req))
+    }
+}
@@ -0,0 +1,80 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::HttpMessage;
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+pub static HEADER: &str = "X-Request-ID";
+
+/// Longest client supplied ID which is passed through.
+const MAX_LENGTH: usize = 128;
+
+/// ID of the current request, available from the request's extensions.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+fn from_header(value: &HeaderValue) -> Option<String> {
+    let value = value.to_str().ok()?;
+    if value.is_empty() || value.len() > MAX_LENGTH || !value.bytes().all(|c| c.is_ascii_graphic())
+    {
+        return None;
+    }
+
+    Some(value.to_string())
+}
+
+/// Take the request ID from `X-Request-ID`, or generate one, and echo it in the response.
+pub struct RequestIds;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIds
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware { service }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id = req
+            .headers()
+            .get(HEADER)
+            .and_then(from_header)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+        req.extensions_mut().insert(RequestId(id.clone()));
+
+        let future = self.service.call(req);
+        Box::pin(async move {
+            let mut response = future.await?;
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static("x-request-id"), value);
+            }
+
+            Ok(response)
+        })
+    }
+}
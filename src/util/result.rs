@@ -1,30 +1,135 @@
 use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, ResponseError};
 use serde::Serialize;
-use serde_json;
+use serde_json::{json, Value};
 use std::fmt::Display;
 
 #[derive(Serialize, Debug)]
 #[serde(tag = "type")]
 pub enum Error {
-    FileTooLarge { max_size: usize },
+    FileTooLarge {
+        max_size: usize,
+    },
+    ImageTooLarge {
+        width: u32,
+        height: u32,
+        max_width: Option<u32>,
+        max_height: Option<u32>,
+    },
+    WrongAspectRatio {
+        width: u32,
+        height: u32,
+        required_aspect_ratio: (u32, u32),
+        tolerance: f32,
+    },
+    TooManyRequests {
+        retry_after: u64,
+    },
+    InsufficientStorage {
+        quota: u64,
+        used: u64,
+    },
     FileTypeNotAllowed,
+    UnsupportedMediaType {
+        allowed: Vec<String>,
+    },
     FailedToReceive,
     BlockingError,
     DatabaseError,
     MissingData,
+    #[serde(skip_serializing)]
+    BadRequest(String),
+    InvalidSignature,
     UnknownTag,
+    Unauthorized,
+    Forbidden,
     ProbeError,
     NotFound,
+    PreconditionFailed,
+    PreconditionRequired,
     Malware,
     IOError,
+    IntegrityError,
     S3Error,
+    GCSError,
+    AzureError,
     LabelMe,
 }
 
+impl Error {
+    /// Human readable description of the error.
+    pub fn message(&self) -> String {
+        match self {
+            Error::FileTooLarge { max_size } => {
+                format!("The file is larger than the {} byte limit", max_size)
+            }
+            Error::ImageTooLarge { width, height, .. } => format!(
+                "The image's dimensions of {}x{} are larger than allowed",
+                width, height
+            ),
+            Error::WrongAspectRatio {
+                width,
+                height,
+                required_aspect_ratio: (ratio_width, ratio_height),
+                ..
+            } => format!(
+                "The image's dimensions of {}x{} don't have the required aspect ratio of {}:{}",
+                width, height, ratio_width, ratio_height
+            ),
+            Error::TooManyRequests { retry_after } => {
+                format!("Too many requests, retry in {} seconds", retry_after)
+            }
+            Error::InsufficientStorage { quota, .. } => format!(
+                "The file would exceed the tag's storage quota of {} bytes",
+                quota
+            ),
+            Error::FileTypeNotAllowed => "This type of file is not allowed here".to_string(),
+            Error::UnsupportedMediaType { allowed } => format!(
+                "The file's type is not allowed, expected one of: {}",
+                allowed.join(", ")
+            ),
+            Error::FailedToReceive => "Failed to receive the uploaded file".to_string(),
+            Error::BlockingError => "A background task failed".to_string(),
+            Error::DatabaseError => "The database could not be reached".to_string(),
+            Error::MissingData => "The request is missing required data".to_string(),
+            Error::BadRequest(message) => message.clone(),
+            Error::InvalidSignature => "The signed URL is invalid or has expired".to_string(),
+            Error::UnknownTag => "The requested tag does not exist".to_string(),
+            Error::Unauthorized => "A valid bearer token is required".to_string(),
+            Error::Forbidden => "This action is not allowed on this tag".to_string(),
+            Error::ProbeError => "Failed to probe the file's metadata".to_string(),
+            Error::NotFound => "The requested file does not exist".to_string(),
+            Error::PreconditionFailed => {
+                "The file already exists or has changed since it was fetched".to_string()
+            }
+            Error::PreconditionRequired => {
+                "An If-Match or If-None-Match header is required".to_string()
+            }
+            Error::Malware => "The file was flagged as malware".to_string(),
+            Error::IOError => "Failed to read or write the file".to_string(),
+            Error::IntegrityError => "The stored file is corrupted".to_string(),
+            Error::S3Error => "Failed to reach S3".to_string(),
+            Error::GCSError => "Failed to reach Google Cloud Storage".to_string(),
+            Error::AzureError => "Failed to reach Azure Blob Storage".to_string(),
+            Error::LabelMe => "An unexpected error occurred".to_string(),
+        }
+    }
+
+    /// JSON body sent to clients, the variant's `type`, any fields it has and a `message`.
+    pub fn to_response_body(&self) -> Value {
+        let mut body = match self {
+            Error::BadRequest(_) => json!({ "type": "BadRequest" }),
+            error => serde_json::to_value(error).unwrap(),
+        };
+
+        body["message"] = Value::String(self.message());
+        body
+    }
+}
+
 impl Display for Error {
-    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        todo!()
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message())
     }
 }
 
@@ -32,26 +137,43 @@ impl ResponseError for Error {
     fn status_code(&self) -> StatusCode {
         match &self {
             Error::FileTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::ImageTooLarge { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::WrongAspectRatio { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Error::InsufficientStorage { .. } => StatusCode::INSUFFICIENT_STORAGE,
             Error::FileTypeNotAllowed => StatusCode::BAD_REQUEST,
+            Error::UnsupportedMediaType { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
             Error::FailedToReceive => StatusCode::BAD_REQUEST,
             Error::DatabaseError => StatusCode::INTERNAL_SERVER_ERROR,
             Error::MissingData => StatusCode::BAD_REQUEST,
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidSignature => StatusCode::FORBIDDEN,
             Error::UnknownTag => StatusCode::BAD_REQUEST,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::Forbidden => StatusCode::FORBIDDEN,
             Error::ProbeError => StatusCode::INTERNAL_SERVER_ERROR,
             Error::NotFound => StatusCode::NOT_FOUND,
+            Error::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            Error::PreconditionRequired => StatusCode::PRECONDITION_REQUIRED,
             Error::BlockingError => StatusCode::INTERNAL_SERVER_ERROR,
             Error::IOError => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::IntegrityError => StatusCode::INTERNAL_SERVER_ERROR,
             Error::S3Error => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::GCSError => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::AzureError => StatusCode::INTERNAL_SERVER_ERROR,
             Error::LabelMe => StatusCode::INTERNAL_SERVER_ERROR,
             Error::Malware => StatusCode::FORBIDDEN,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        let body = serde_json::to_string(&self).unwrap();
+        let body = self.to_response_body().to_string();
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let Error::TooManyRequests { retry_after } = self {
+            response.insert_header(("Retry-After", retry_after.to_string()));
+        }
 
-        HttpResponse::build(self.status_code())
-            .content_type("application/json")
-            .body(body)
+        response.content_type("application/json").body(body)
     }
 }
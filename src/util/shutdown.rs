@@ -0,0 +1,126 @@
+use crate::config::Config;
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::Server;
+use actix_web::web::Bytes;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+
+/// How often to check whether in-flight requests have finished.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the server has been asked to stop.
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Counts a request as in-flight until dropped.
+pub struct InFlight;
+
+impl InFlight {
+    pub fn start() -> InFlight {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Response body which keeps its request in-flight until it has been sent.
+pub struct InFlightBody<B> {
+    body: Pin<Box<B>>,
+    _in_flight: InFlight,
+}
+
+impl<B> InFlightBody<B> {
+    pub fn new(body: B, in_flight: InFlight) -> InFlightBody<B> {
+        InFlightBody {
+            body: Box::pin(body),
+            _in_flight: in_flight,
+        }
+    }
+}
+
+impl<B: MessageBody> MessageBody for InFlightBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        self.get_mut().body.as_mut().poll_next(cx)
+    }
+}
+
+/// Wait for SIGTERM or SIGINT, then stop accepting connections and give in-flight
+/// requests up to `shutdown_timeout_seconds` to finish before stopping the server.
+pub async fn drain_on_signal(server: Server) {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM.");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT.");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    server.pause().await;
+
+    let in_flight = IN_FLIGHT.load(Ordering::SeqCst);
+    info!(
+        "Shutting down, waiting for {} requests to finish.",
+        in_flight
+    );
+
+    let timeout = Duration::from_secs(Config::global().shutdown_timeout_seconds);
+    let deadline = Instant::now() + timeout;
+    while IN_FLIGHT.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+
+    // Requests on open connections may have started since, so this is approximate.
+    let remaining = IN_FLIGHT.load(Ordering::SeqCst);
+    if remaining == 0 {
+        info!("Drained {} requests.", in_flight);
+    } else {
+        warn!(
+            "Drained {} requests, {} timed out after {:?}.",
+            in_flight.saturating_sub(remaining),
+            remaining,
+            timeout
+        );
+    }
+
+    crate::storage::abort_multipart_uploads().await;
+    // Let workers finish any body which is still streaming.
+    server.stop(true).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_requests_until_the_body_is_dropped() {
+        let before = IN_FLIGHT.load(Ordering::SeqCst);
+        let body = InFlightBody::new(Bytes::from_static(b"hello"), InFlight::start());
+        assert_eq!(IN_FLIGHT.load(Ordering::SeqCst), before + 1);
+
+        drop(body);
+        assert_eq!(IN_FLIGHT.load(Ordering::SeqCst), before);
+    }
+}
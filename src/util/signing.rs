@@ -0,0 +1,115 @@
+use crate::config::Tag;
+use crate::util::result::Error;
+
+use actix_web::HttpRequest;
+use hmac::{Hmac, Mac, NewMac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Deserialize)]
+struct Signed {
+    token: Option<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn mac(id: &str, expires: u64, secret: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size.");
+    mac.update(format!("{}:{}", id, expires).as_bytes());
+    mac
+}
+
+/// Generate a token granting access to a file for `ttl` seconds.
+///
+/// Tokens take the form `<expiry_unix_ts>.<hex_signature>`.
+pub fn generate_token(id: &str, ttl: u64, secret: &str) -> String {
+    let expires = now() + ttl;
+    let signature = mac(id, expires, secret).finalize().into_bytes();
+
+    format!("{}.{}", expires, hex::encode(signature))
+}
+
+/// Check that a token is a valid, unexpired signature for a file.
+pub fn verify_token(id: &str, token: &str, secret: &str) -> Result<(), Error> {
+    let (expires, signature) = token.split_once('.').ok_or(Error::InvalidSignature)?;
+    let expires: u64 = expires.parse().map_err(|_| Error::InvalidSignature)?;
+    let signature = hex::decode(signature).map_err(|_| Error::InvalidSignature)?;
+
+    if expires < now() {
+        return Err(Error::InvalidSignature);
+    }
+
+    mac(id, expires, secret)
+        .verify(&signature)
+        .map_err(|_| Error::InvalidSignature)
+}
+
+/// The `?token=` a request was made with.
+pub fn request_token(request: &HttpRequest) -> Option<String> {
+    actix_web::web::Query::<Signed>::from_query(request.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().token)
+}
+
+/// Require a valid `?token=` on requests to tags with signed URLs enabled.
+pub fn check_request(request: &HttpRequest, tag: &Tag, id: &str) -> Result<(), Error> {
+    if let Some(signed_url) = &tag.signed_url {
+        let token = request_token(request).ok_or(Error::InvalidSignature)?;
+        verify_token(id, &token, &signed_url.secret)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "correct horse battery staple";
+
+    #[test]
+    fn accepts_a_valid_token() {
+        let token = generate_token("file", 60, SECRET);
+        assert!(verify_token("file", &token, SECRET).is_ok());
+    }
+
+    #[test]
+    fn refuses_an_expired_token() {
+        let expires = now() - 1;
+        let signature = mac("file", expires, SECRET).finalize().into_bytes();
+        let token = format!("{}.{}", expires, hex::encode(signature));
+
+        assert!(matches!(
+            verify_token("file", &token, SECRET),
+            Err(Error::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn refuses_a_tampered_token() {
+        let token = generate_token("file", 60, SECRET);
+        let (expires, signature) = token.split_once('.').unwrap();
+
+        // Pushing the expiry back invalidates the signature.
+        let extended = format!("{}.{}", expires.parse::<u64>().unwrap() + 3600, signature);
+        assert!(verify_token("file", &extended, SECRET).is_err());
+
+        // As does flipping a bit of the signature or using it for another file.
+        let mut bytes = hex::decode(signature).unwrap();
+        bytes[0] ^= 1;
+        let flipped = format!("{}.{}", expires, hex::encode(bytes));
+        assert!(verify_token("file", &flipped, SECRET).is_err());
+        assert!(verify_token("other", &token, SECRET).is_err());
+        assert!(verify_token("file", &token, "another secret").is_err());
+        assert!(verify_token("file", "not a token", SECRET).is_err());
+    }
+}
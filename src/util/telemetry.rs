@@ -0,0 +1,127 @@
+use crate::config::Config;
+use crate::util::request_id::RequestId;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::HeaderMap;
+use actix_web::HttpMessage;
+use futures::future::{ready, Ready};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing::instrument::Instrumented;
+use tracing::{info_span, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// Set up logging, and trace export if `telemetry` is configured.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer());
+
+    let config = match &Config::global().telemetry {
+        Some(config) => config,
+        None => return registry.init(),
+    };
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(&config.otlp_endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                config.service_name.clone(),
+            )])),
+        )
+        .install_batch(runtime::Tokio)
+        .expect("Failed to set up the OTLP exporter.");
+
+    registry
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .init();
+}
+
+/// Send any spans which have not been exported yet.
+pub fn shutdown() {
+    if Config::global().telemetry.is_some() {
+        global::shutdown_tracer_provider();
+    }
+}
+
+struct RequestHeaders<'a>(&'a HeaderMap);
+
+impl Extractor for RequestHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Start a span for every request, continuing any trace given in `traceparent`.
+pub struct RequestSpan;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestSpan
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestSpanMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestSpanMiddleware { service }))
+    }
+}
+
+pub struct RequestSpanMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestSpanMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Instrumented<S::Future>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = req
+            .extensions()
+            .get::<RequestId>()
+            .map(|id| id.0.clone())
+            .unwrap_or_default();
+
+        let span = info_span!(
+            "request",
+            request_id = %request_id,
+            http.method = %req.method(),
+            http.target = %req.path(),
+        );
+
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&RequestHeaders(req.headers()))
+        });
+        span.set_parent(parent);
+
+        self.service.call(req).instrument(span)
+    }
+}
@@ -0,0 +1,112 @@
+use crate::db::{get_used_upload_token_collection, is_duplicate_key, UsedUploadToken};
+use crate::util::result::Error;
+
+use hmac::{Hmac, Mac, NewMac};
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What an upload token allows, signed into the token itself.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadClaims {
+    pub tag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type_prefix: Option<String>,
+    /// Unix timestamp the token expires at.
+    pub expires: u64,
+    /// Identifies the token so it can only be used once.
+    pub nonce: String,
+}
+
+impl UploadClaims {
+    pub fn new(
+        tag: String,
+        max_size_bytes: Option<usize>,
+        content_type_prefix: Option<String>,
+        ttl: u64,
+    ) -> UploadClaims {
+        UploadClaims {
+            tag,
+            max_size_bytes,
+            content_type_prefix,
+            expires: now() + ttl,
+            nonce: uuid::Uuid::new_v4().to_string(),
+        }
+    }
+
+    pub fn allows_content_type(&self, content_type: &str) -> bool {
+        match &self.content_type_prefix {
+            Some(prefix) => content_type.starts_with(prefix.as_str()),
+            None => true,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn mac(payload: &str, secret: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size.");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// Sign claims into a token of the form `<base64url_claims>.<hex_signature>`.
+pub fn generate(claims: &UploadClaims, secret: &str) -> String {
+    let payload = base64::encode_config(
+        serde_json::to_vec(claims).expect("Claims are always serializable."),
+        base64::URL_SAFE_NO_PAD,
+    );
+
+    let signature = mac(&payload, secret).finalize().into_bytes();
+    format!("{}.{}", payload, hex::encode(signature))
+}
+
+/// Check a token's signature and expiry, returning what it allows.
+pub fn verify(token: &str, secret: &str) -> Result<UploadClaims, Error> {
+    let (payload, signature) = token.split_once('.').ok_or(Error::InvalidSignature)?;
+    let signature = hex::decode(signature).map_err(|_| Error::InvalidSignature)?;
+
+    mac(payload, secret)
+        .verify(&signature)
+        .map_err(|_| Error::InvalidSignature)?;
+
+    let claims: UploadClaims = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|claims| serde_json::from_slice(&claims).ok())
+        .ok_or(Error::InvalidSignature)?;
+
+    if claims.expires < now() {
+        return Err(Error::InvalidSignature);
+    }
+
+    Ok(claims)
+}
+
+/// Use up a token, which is refused if it was used before.
+pub async fn redeem(claims: &UploadClaims) -> Result<(), Error> {
+    let used = UsedUploadToken {
+        nonce: claims.nonce.clone(),
+        // Kept until the token would have expired anyway.
+        expires_at: DateTime::from_millis(claims.expires as i64 * 1000),
+    };
+
+    match get_used_upload_token_collection()
+        .insert_one(used, None)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(err) if is_duplicate_key(&err) => Err(Error::InvalidSignature),
+        Err(_) => Err(Error::DatabaseError),
+    }
+}
@@ -1,5 +1,10 @@
+use crate::config::{Config, S3BucketConfig};
 use crate::util::result::Error;
 
+#[cfg(feature = "azure")]
+use azure_storage::StorageCredentials;
+#[cfg(feature = "azure")]
+use azure_storage_blobs::prelude::{BlobClient, ClientBuilder};
 use s3::{creds::Credentials, Region};
 use std::env;
 
@@ -25,14 +30,123 @@ lazy_static! {
         region: env::var("AUTUMN_S3_REGION").unwrap_or_else(|_| "".to_string()),
         endpoint: env::var("AUTUMN_S3_ENDPOINT").unwrap_or_else(|_| "".to_string())
     };
+    pub static ref S3_PATH_STYLE: bool =
+        env::var("AUTUMN_S3_PATH_STYLE").map(|value| value != "false").unwrap_or(true);
     pub static ref S3_CREDENTIALS: Credentials = Credentials::default().unwrap();
+    pub static ref GCS_SERVICE_ACCOUNT: Option<String> =
+        env::var("AUTUMN_GCS_SERVICE_ACCOUNT").ok();
+    pub static ref GCS_BUCKET: Option<String> = env::var("AUTUMN_GCS_BUCKET").ok();
+    pub static ref AZURE_ACCOUNT: Option<String> = env::var("AUTUMN_AZURE_ACCOUNT").ok();
+    pub static ref AZURE_ACCESS_KEY: Option<String> = env::var("AUTUMN_AZURE_ACCESS_KEY").ok();
+    pub static ref AZURE_SAS_TOKEN: Option<String> = env::var("AUTUMN_AZURE_SAS_TOKEN").ok();
+    pub static ref AZURE_CONTAINER: Option<String> = env::var("AUTUMN_AZURE_CONTAINER").ok();
+    pub static ref REDIS_URL: Option<String> = env::var("REDIS_URL").ok();
 
     // Application Flags
     pub static ref USE_S3: bool = env::var("AUTUMN_S3_REGION").is_ok() && env::var("AUTUMN_S3_ENDPOINT").is_ok();
+    pub static ref USE_GCS: bool = GCS_SERVICE_ACCOUNT.is_some();
+    pub static ref USE_AZURE: bool = cfg!(feature = "azure") && AZURE_ACCOUNT.is_some();
     pub static ref USE_CLAMD: bool = env::var("CLAMD_HOST").is_ok();
 }
 
-pub fn get_s3_bucket(bucket: &str) -> Result<s3::Bucket, Error> {
-    s3::Bucket::new_with_path_style(bucket, S3_REGION.clone(), S3_CREDENTIALS.clone())
-        .map_err(|_| Error::S3Error)
+/// Directory a tag's files are stored in locally.
+pub fn get_local_root(tag: &str) -> &str {
+    Config::global()
+        .tags
+        .get(tag)
+        .and_then(|tag| tag.local_path_override.as_deref())
+        .unwrap_or(&*LOCAL_STORAGE_PATH)
+}
+
+/// Path of a file under `root`, sharded into two levels of
+/// directories by the start of its ID, such as `ab/cd/abcdef`.
+pub fn get_sharded_path(root: &str, id: &str) -> String {
+    match (id.get(0..2), id.get(2..4)) {
+        (Some(a), Some(b)) => format!("{}/{}/{}/{}", root, a, b, id),
+        _ => format!("{}/{}", root, id),
+    }
+}
+
+/// Path of a file in local storage.
+pub fn get_local_path(tag: &str, id: &str) -> String {
+    get_sharded_path(get_local_root(tag), id)
+}
+
+/// Every tag uses its own bucket unless `AUTUMN_GCS_BUCKET` is set.
+pub fn get_gcs_bucket(tag: &str) -> String {
+    GCS_BUCKET.clone().unwrap_or_else(|| tag.to_string())
+}
+
+/// Every tag uses its own container unless `AUTUMN_AZURE_CONTAINER` is set.
+#[cfg(feature = "azure")]
+pub fn get_azure_blob(tag: &str, id: &str) -> Result<BlobClient, Error> {
+    let account = AZURE_ACCOUNT.clone().ok_or(Error::AzureError)?;
+    let credentials = match (&*AZURE_ACCESS_KEY, &*AZURE_SAS_TOKEN) {
+        (Some(key), _) => StorageCredentials::access_key(account.clone(), key.clone()),
+        (None, Some(token)) => {
+            StorageCredentials::sas_token(token).map_err(|_| Error::AzureError)?
+        }
+        (None, None) => return Err(Error::AzureError),
+    };
+
+    let container = AZURE_CONTAINER.clone().unwrap_or_else(|| tag.to_string());
+    Ok(ClientBuilder::new(account, credentials).blob_client(container, id))
+}
+
+fn new_s3_bucket(
+    name: &str,
+    region: Region,
+    credentials: Credentials,
+    path_style: bool,
+) -> Result<s3::Bucket, Error> {
+    if path_style {
+        s3::Bucket::new_with_path_style(name, region, credentials)
+    } else {
+        s3::Bucket::new(name, region, credentials)
+    }
+    .map_err(|_| Error::S3Error)
+}
+
+/// Buckets for a tag in order of preference, or the bucket named after the tag.
+pub fn get_s3_buckets(tag: &str) -> Result<Vec<s3::Bucket>, Error> {
+    let buckets = match Config::global().tags.get(tag) {
+        Some(config) if !config.s3_buckets.is_empty() => &config.s3_buckets,
+        config => {
+            let region = match config.and_then(|config| config.s3_endpoint.clone()) {
+                Some(endpoint) => Region::Custom {
+                    region: S3_REGION.to_string(),
+                    endpoint,
+                },
+                None => S3_REGION.clone(),
+            };
+
+            return new_s3_bucket(tag, region, S3_CREDENTIALS.clone(), *S3_PATH_STYLE)
+                .map(|bucket| vec![bucket]);
+        }
+    };
+
+    buckets.iter().map(configured_s3_bucket).collect()
+}
+
+/// A bucket described in the config, using the default credentials unless it has its own.
+pub fn configured_s3_bucket(bucket: &S3BucketConfig) -> Result<s3::Bucket, Error> {
+    let region = Region::Custom {
+        region: bucket.region.clone(),
+        endpoint: bucket.endpoint.clone(),
+    };
+
+    let credentials = match (&bucket.access_key, &bucket.secret_key) {
+        (Some(access_key), Some(secret_key)) => {
+            Credentials::new(Some(access_key), Some(secret_key), None, None, None)
+                .map_err(|_| Error::S3Error)?
+        }
+        _ => S3_CREDENTIALS.clone(),
+    };
+
+    new_s3_bucket(
+        &bucket.name,
+        region,
+        credentials,
+        bucket.path_style.unwrap_or(*S3_PATH_STYLE),
+    )
 }
@@ -0,0 +1,424 @@
+use actix_web::web::Bytes;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const END: u32 = 0x0605_4b50;
+
+const VERSION: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+
+/// Sizes follow the contents, names are UTF-8.
+const FLAGS: u16 = 0x0008 | 0x0800;
+
+/// 1980-01-01 00:00, the earliest time a ZIP can hold.
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+const U16_LIMIT: u64 = 0xFFFF;
+const U32_LIMIT: u64 = 0xFFFF_FFFF;
+
+struct Entry {
+    name: String,
+    zip64: bool,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+#[derive(Default)]
+struct Buffer(Vec<u8>);
+
+impl Buffer {
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+}
+
+/// Values too large for 32 bits are replaced by a marker and stored in an extra field.
+fn clamp(value: u64) -> u32 {
+    if value >= U32_LIMIT {
+        U32_LIMIT as u32
+    } else {
+        value as u32
+    }
+}
+
+/// Streams a ZIP archive of stored entries, producing the bytes around each entry
+/// while the caller sends its contents in between. Checksums follow the contents
+/// in a data descriptor, and ZIP64 records are only added once the archive needs them.
+#[derive(Default)]
+pub struct ZipWriter {
+    offset: u64,
+    entries: Vec<Entry>,
+    current: Option<Entry>,
+}
+
+impl ZipWriter {
+    pub fn new() -> ZipWriter {
+        ZipWriter::default()
+    }
+
+    /// Header of a new entry which is expected to be `size` bytes long.
+    pub fn start_entry(&mut self, name: &str, size: u64) -> Bytes {
+        let zip64 = size >= U32_LIMIT;
+        let mut header = Buffer::default();
+        header
+            .u32(LOCAL_HEADER)
+            .u16(if zip64 { VERSION_ZIP64 } else { VERSION })
+            .u16(FLAGS)
+            .u16(0)
+            .u16(0)
+            .u16(DOS_DATE)
+            .u32(0);
+
+        if zip64 {
+            header
+                .u32(U32_LIMIT as u32)
+                .u32(U32_LIMIT as u32)
+                .u16(name.len() as u16)
+                .u16(20)
+                .bytes(name.as_bytes())
+                .u16(0x0001)
+                .u16(16)
+                .u64(0)
+                .u64(0);
+        } else {
+            header
+                .u32(0)
+                .u32(0)
+                .u16(name.len() as u16)
+                .u16(0)
+                .bytes(name.as_bytes());
+        }
+
+        self.current = Some(Entry {
+            name: name.to_string(),
+            zip64,
+            crc: 0,
+            size: 0,
+            offset: self.offset,
+        });
+
+        self.offset += header.0.len() as u64;
+        Bytes::from(header.0)
+    }
+
+    /// Data descriptor of the current entry, once `size` bytes with checksum `crc` were sent.
+    pub fn finish_entry(&mut self, crc: u32, size: u64) -> Bytes {
+        let mut entry = self.current.take().expect("no entry was started");
+        entry.crc = crc;
+        entry.size = size;
+
+        let mut descriptor = Buffer::default();
+        descriptor.u32(DATA_DESCRIPTOR).u32(crc);
+        if entry.zip64 {
+            descriptor.u64(size).u64(size);
+        } else {
+            descriptor.u32(size as u32).u32(size as u32);
+        }
+
+        self.offset += size + descriptor.0.len() as u64;
+        self.entries.push(entry);
+        Bytes::from(descriptor.0)
+    }
+
+    /// Central directory and end records, closing the archive.
+    pub fn finish(self) -> Bytes {
+        let mut directory = Buffer::default();
+        for entry in &self.entries {
+            let mut extra = Buffer::default();
+            if entry.size >= U32_LIMIT {
+                extra.u64(entry.size).u64(entry.size);
+            }
+
+            if entry.offset >= U32_LIMIT {
+                extra.u64(entry.offset);
+            }
+
+            let zip64 = entry.zip64 || !extra.0.is_empty();
+            let extra_length = if extra.0.is_empty() {
+                0
+            } else {
+                extra.0.len() + 4
+            };
+
+            directory
+                .u32(CENTRAL_HEADER)
+                .u16(VERSION_ZIP64)
+                .u16(if zip64 { VERSION_ZIP64 } else { VERSION })
+                .u16(FLAGS)
+                .u16(0)
+                .u16(0)
+                .u16(DOS_DATE)
+                .u32(entry.crc)
+                .u32(clamp(entry.size))
+                .u32(clamp(entry.size))
+                .u16(entry.name.len() as u16)
+                .u16(extra_length as u16)
+                .u16(0)
+                .u16(0)
+                .u16(0)
+                .u32(0)
+                .u32(clamp(entry.offset))
+                .bytes(entry.name.as_bytes());
+
+            if !extra.0.is_empty() {
+                directory
+                    .u16(0x0001)
+                    .u16(extra.0.len() as u16)
+                    .bytes(&extra.0);
+            }
+        }
+
+        let count = self.entries.len() as u64;
+        let size = directory.0.len() as u64;
+        let start = self.offset;
+
+        if count >= U16_LIMIT || size >= U32_LIMIT || start >= U32_LIMIT {
+            let end = start + size;
+            directory
+                .u32(ZIP64_END)
+                .u64(44)
+                .u16(VERSION_ZIP64)
+                .u16(VERSION_ZIP64)
+                .u32(0)
+                .u32(0)
+                .u64(count)
+                .u64(count)
+                .u64(size)
+                .u64(start)
+                .u32(ZIP64_LOCATOR)
+                .u32(0)
+                .u64(end)
+                .u32(1);
+        }
+
+        let count = count.min(U16_LIMIT) as u16;
+        directory
+            .u32(END)
+            .u16(0)
+            .u16(0)
+            .u16(count)
+            .u16(count)
+            .u32(clamp(size))
+            .u32(clamp(start))
+            .u16(0);
+
+        Bytes::from(directory.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    const GIB: u64 = 1 << 30;
+
+    fn crc(data: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([data[at], data[at + 1]])
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Record {
+        name: String,
+        crc: u32,
+        size: u64,
+        offset: u64,
+    }
+
+    /// Read the central directory of an archive, given its closing bytes and their offset.
+    fn read_directory(tail: &[u8], tail_offset: u64) -> Vec<Record> {
+        let end = tail.len() - 22;
+        assert_eq!(u32_at(tail, end), END);
+
+        let mut count = u16_at(tail, end + 10) as u64;
+        let mut start = u32_at(tail, end + 16) as u64;
+        if end >= 20 && u32_at(tail, end - 20) == ZIP64_LOCATOR {
+            let zip64_end = (u64_at(tail, end - 12) - tail_offset) as usize;
+            assert_eq!(u32_at(tail, zip64_end), ZIP64_END);
+            count = u64_at(tail, zip64_end + 32);
+            start = u64_at(tail, zip64_end + 48);
+        }
+
+        let mut at = (start - tail_offset) as usize;
+        let mut records = vec![];
+        for _ in 0..count {
+            assert_eq!(u32_at(tail, at), CENTRAL_HEADER);
+            let name_length = u16_at(tail, at + 28) as usize;
+            let extra_length = u16_at(tail, at + 30) as usize;
+            let name = &tail[at + 46..at + 46 + name_length];
+            let mut extra = &tail[at + 46 + name_length..at + 46 + name_length + extra_length];
+
+            let mut size = u32_at(tail, at + 24) as u64;
+            let mut offset = u32_at(tail, at + 42) as u64;
+            if !extra.is_empty() {
+                assert_eq!(u16_at(extra, 0), 0x0001);
+                extra = &extra[4..];
+            }
+
+            // ZIP64 values are only present for the fields which overflowed, in this order.
+            if size == U32_LIMIT {
+                assert_eq!(u64_at(extra, 0), u64_at(extra, 8));
+                size = u64_at(extra, 0);
+                extra = &extra[16..];
+            }
+
+            if offset == U32_LIMIT {
+                offset = u64_at(extra, 0);
+            }
+
+            records.push(Record {
+                name: String::from_utf8(name.to_vec()).unwrap(),
+                crc: u32_at(tail, at + 16),
+                size,
+                offset,
+            });
+
+            at += 46 + name_length + extra_length;
+        }
+
+        records
+    }
+
+    #[test]
+    fn round_trips_small_entries() {
+        let files: &[(&str, &[u8])] = &[("a.txt", b"hello"), ("b/ünïcode.txt", b"world!")];
+
+        let mut archive = vec![];
+        let mut writer = ZipWriter::new();
+        for (name, contents) in files {
+            archive.extend_from_slice(&writer.start_entry(name, contents.len() as u64));
+            archive.extend_from_slice(contents);
+            let crc = crc(contents);
+            archive.extend_from_slice(&writer.finish_entry(crc, contents.len() as u64));
+        }
+
+        let tail_offset = archive.len();
+        archive.extend_from_slice(&writer.finish());
+        let records = read_directory(&archive[tail_offset..], tail_offset as u64);
+        assert_eq!(records.len(), files.len());
+
+        for ((name, contents), record) in files.iter().zip(records) {
+            assert_eq!(record.name, *name);
+            assert_eq!(record.crc, crc(contents));
+            assert_eq!(record.size, contents.len() as u64);
+
+            // The local header leaves the checksum and sizes to the data descriptor.
+            let at = record.offset as usize;
+            assert_eq!(u32_at(&archive, at), LOCAL_HEADER);
+            assert_eq!(u16_at(&archive, at + 6) & 0x0008, 0x0008);
+            assert_eq!(u32_at(&archive, at + 14), 0);
+
+            let name_length = u16_at(&archive, at + 26) as usize;
+            assert_eq!(u16_at(&archive, at + 28), 0);
+            assert_eq!(&archive[at + 30..at + 30 + name_length], name.as_bytes());
+
+            let data = at + 30 + name_length;
+            assert_eq!(&archive[data..data + contents.len()], *contents);
+
+            let descriptor = data + contents.len();
+            assert_eq!(u32_at(&archive, descriptor), DATA_DESCRIPTOR);
+            assert_eq!(u32_at(&archive, descriptor + 4), record.crc);
+            assert_eq!(u32_at(&archive, descriptor + 8) as u64, record.size);
+            assert_eq!(u32_at(&archive, descriptor + 12) as u64, record.size);
+        }
+    }
+
+    #[test]
+    fn uses_zip64_past_4_gib() {
+        let mut writer = ZipWriter::new();
+
+        // Contents aren't needed to check the records around them.
+        let header = writer.start_entry("big.bin", 5 * GIB);
+        assert_eq!(u16_at(&header, 4), VERSION_ZIP64);
+        assert_eq!(u32_at(&header, 18), U32_LIMIT as u32);
+        assert_eq!(u32_at(&header, 22), U32_LIMIT as u32);
+        assert_eq!(u16_at(&header, 28), 20);
+        assert_eq!(u16_at(&header, 30 + 7), 0x0001);
+
+        let descriptor = writer.finish_entry(0x1234_5678, 5 * GIB);
+        assert_eq!(descriptor.len(), 24);
+        assert_eq!(u64_at(&descriptor, 8), 5 * GIB);
+        assert_eq!(u64_at(&descriptor, 16), 5 * GIB);
+        let big_end = header.len() as u64 + 5 * GIB + descriptor.len() as u64;
+
+        // Small, but beyond where a 32 bit offset can point.
+        let header = writer.start_entry("small.txt", 5);
+        assert_eq!(u16_at(&header, 4), VERSION);
+        let descriptor = writer.finish_entry(crc(b"hello"), 5);
+        assert_eq!(descriptor.len(), 16);
+        let tail_offset = big_end + header.len() as u64 + 5 + descriptor.len() as u64;
+
+        let tail = writer.finish();
+        let end = tail.len() - 22;
+        assert_eq!(u32_at(&tail, end + 16), U32_LIMIT as u32);
+
+        assert_eq!(
+            read_directory(&tail, tail_offset),
+            vec![
+                Record {
+                    name: "big.bin".to_string(),
+                    crc: 0x1234_5678,
+                    size: 5 * GIB,
+                    offset: 0,
+                },
+                Record {
+                    name: "small.txt".to_string(),
+                    crc: crc(b"hello"),
+                    size: 5,
+                    offset: big_end,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_zip64_records_below_4_gib() {
+        let mut writer = ZipWriter::new();
+        let header = writer.start_entry("almost.bin", 4 * GIB - 1024);
+        assert_eq!(u16_at(&header, 4), VERSION);
+        let descriptor = writer.finish_entry(0, 4 * GIB - 1024);
+        assert_eq!(descriptor.len(), 16);
+
+        let tail = writer.finish();
+        let end = tail.len() - 22;
+        assert_ne!(u32_at(&tail, end - 20), ZIP64_LOCATOR);
+
+        let records = read_directory(&tail, header.len() as u64 + 4 * GIB - 1024 + 16);
+        assert_eq!(records[0].size, 4 * GIB - 1024);
+        assert_eq!(records[0].offset, 0);
+    }
+}
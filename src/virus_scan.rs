@@ -1,7 +1,8 @@
 use std::time::Duration;
 
-use log::{error, info};
+use tracing::{error, info};
 
+use crate::util::result::Error;
 use crate::util::variables::{CLAMD_HOST, USE_CLAMD};
 
 pub fn init() {
@@ -20,7 +21,7 @@ pub fn init() {
             } else {
                 error!(
                     "Could not ping clamd host at {}, retrying in 10 seconds...",
-                    CLAMD_HOST.to_string()
+                    *CLAMD_HOST
                 );
 
                 std::thread::sleep(Duration::from_secs(10));
@@ -28,3 +29,18 @@ pub fn init() {
         }
     }
 }
+
+/// Reject the file if clamd finds anything in it.
+pub fn scan(buf: &[u8]) -> Result<(), Error> {
+    if *USE_CLAMD {
+        let scan_response =
+            revolt_clamav_client::scan_buffer_tcp(buf, CLAMD_HOST.to_string(), None).unwrap();
+
+        let file_clean = revolt_clamav_client::clean(&scan_response).unwrap();
+        if !file_clean {
+            return Err(Error::Malware);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use crate::config::Config;
+use crate::db::File;
+
+use hmac::{Hmac, Mac, NewMac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::time::Duration;
+use tracing::warn;
+
+/// Attempts after the first before a webhook is given up on.
+const MAX_RETRIES: u32 = 3;
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build the webhook client.");
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Upload,
+    Delete,
+}
+
+#[derive(Serialize, Debug)]
+struct Payload<'a> {
+    event: Event,
+    id: &'a str,
+    tag: &'a str,
+    content_type: &'a str,
+    file_size: isize,
+}
+
+/// Hex encoded HMAC-SHA256 of the body.
+fn sign(body: &[u8], secret: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size.");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+async fn deliver(url: &str, secret: Option<&str>, body: Vec<u8>) -> bool {
+    for attempt in 0..=MAX_RETRIES {
+        if attempt > 0 {
+            tokio::time::sleep(Duration::from_secs(1 << (attempt - 1))).await;
+        }
+
+        let mut request = CLIENT
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(body.clone());
+
+        if let Some(secret) = secret {
+            request = request.header("X-Autumn-Signature", sign(&body, secret));
+        }
+
+        if let Ok(response) = request.send().await {
+            if response.status().is_success() {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+/// Notify the tag's `webhook_url`, if any, in the background.
+pub fn notify(event: Event, file: &File) {
+    let tag = match Config::global().tags.get(&file.tag) {
+        Some(tag) => tag,
+        None => return,
+    };
+
+    let url = match &tag.webhook_url {
+        Some(url) => url,
+        None => return,
+    };
+
+    let body = serde_json::to_vec(&Payload {
+        event,
+        id: &file.id,
+        tag: &file.tag,
+        content_type: &file.content_type,
+        file_size: file.size,
+    })
+    .expect("Webhook payloads are always serializable.");
+
+    let id = file.id.clone();
+    let secret = tag.webhook_secret.as_deref();
+    tokio::spawn(async move {
+        if !deliver(url, secret, body).await {
+            warn!(
+                "Failed to deliver {:?} webhook for {} to {}.",
+                event, id, url
+            );
+        }
+    });
+}